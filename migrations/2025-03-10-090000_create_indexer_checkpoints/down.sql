@@ -0,0 +1 @@
+DROP TABLE indexer_checkpoints;
\ No newline at end of file
@@ -0,0 +1,7 @@
+CREATE TABLE indexer_checkpoints (
+    chain_id BIGINT NOT NULL,
+    token_address TEXT NOT NULL,
+    last_block BIGINT NOT NULL,
+    updated_at TIMESTAMP NOT NULL DEFAULT NOW(),
+    PRIMARY KEY (chain_id, token_address)
+);
@@ -0,0 +1,9 @@
+-- Keep only the first Transfer event of each transaction so the old uniqueness key can be restored.
+DELETE FROM transfers t
+USING transfers other
+WHERE t.tx_hash = other.tx_hash AND t.log_index > other.log_index;
+
+ALTER TABLE transfers DROP CONSTRAINT transfers_tx_hash_log_index_key;
+ALTER TABLE transfers DROP COLUMN transaction_index;
+ALTER TABLE transfers DROP COLUMN log_index;
+ALTER TABLE transfers ADD CONSTRAINT transfers_tx_hash_key UNIQUE (tx_hash);
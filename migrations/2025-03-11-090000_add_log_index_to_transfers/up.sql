@@ -0,0 +1,16 @@
+-- Rows indexed under the old tx_hash uniqueness key are missing every Transfer event after the
+-- first one in a transaction and carry no log index, so they cannot be repaired in place. Rather
+-- than silently deleting them, refuse to upgrade a database holding transfers: the operator must
+-- clear them explicitly, as described under "Upgrading an Existing Database" in the README, and the
+-- indexer then rebuilds the history from scratch.
+DO $$
+BEGIN
+    IF EXISTS (SELECT 1 FROM transfers) THEN
+        RAISE EXCEPTION 'Adding log_index requires reindexing the stored transfers. Run DELETE FROM transfers; DELETE FROM indexer_checkpoints; then run the migrations again (see "Upgrading an Existing Database" in the README).';
+    END IF;
+END $$;
+
+ALTER TABLE transfers DROP CONSTRAINT transfers_tx_hash_key;
+ALTER TABLE transfers ADD COLUMN log_index INTEGER NOT NULL;
+ALTER TABLE transfers ADD COLUMN transaction_index INTEGER NOT NULL;
+ALTER TABLE transfers ADD CONSTRAINT transfers_tx_hash_log_index_key UNIQUE (tx_hash, log_index);
@@ -0,0 +1,2 @@
+DROP TABLE reorgs;
+DROP TABLE indexed_blocks;
\ No newline at end of file
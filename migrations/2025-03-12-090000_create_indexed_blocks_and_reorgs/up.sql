@@ -0,0 +1,16 @@
+CREATE TABLE indexed_blocks (
+    chain_id BIGINT NOT NULL,
+    block_number BIGINT NOT NULL,
+    block_hash TEXT NOT NULL,
+    parent_hash TEXT NOT NULL,
+    PRIMARY KEY (chain_id, block_number)
+);
+
+CREATE TABLE reorgs (
+    id SERIAL PRIMARY KEY,
+    chain_id BIGINT NOT NULL,
+    fork_block BIGINT NOT NULL,
+    depth BIGINT NOT NULL,
+    removed_transfers BIGINT NOT NULL,
+    detected_at TIMESTAMP NOT NULL DEFAULT NOW()
+);
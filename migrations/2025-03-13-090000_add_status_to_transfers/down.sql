@@ -0,0 +1,3 @@
+DELETE FROM transfers WHERE status = 'unconfirmed';
+DROP INDEX transfers_status_block_number_idx;
+ALTER TABLE transfers DROP COLUMN status;
@@ -0,0 +1,2 @@
+ALTER TABLE transfers ADD COLUMN status TEXT NOT NULL DEFAULT 'confirmed';
+CREATE INDEX transfers_status_block_number_idx ON transfers (status, block_number);
@@ -0,0 +1,3 @@
+DROP INDEX transfers_token_address_block_number_idx;
+ALTER TABLE transfers DROP COLUMN token_address;
+DROP TABLE tokens;
@@ -0,0 +1,12 @@
+CREATE TABLE tokens (
+    address TEXT PRIMARY KEY,
+    created_at TIMESTAMP NOT NULL DEFAULT NOW()
+);
+
+-- Until now a single token was indexed per database: the one tracked by the checkpoints.
+INSERT INTO tokens (address) SELECT DISTINCT token_address FROM indexer_checkpoints;
+
+ALTER TABLE transfers ADD COLUMN token_address TEXT;
+UPDATE transfers SET token_address = (SELECT token_address FROM indexer_checkpoints LIMIT 1);
+ALTER TABLE transfers ALTER COLUMN token_address SET NOT NULL;
+CREATE INDEX transfers_token_address_block_number_idx ON transfers (token_address, block_number);
@@ -0,0 +1,5 @@
+ALTER TABLE tokens DROP COLUMN metadata_updated_at;
+ALTER TABLE tokens DROP COLUMN total_supply;
+ALTER TABLE tokens DROP COLUMN decimals;
+ALTER TABLE tokens DROP COLUMN symbol;
+ALTER TABLE tokens DROP COLUMN name;
@@ -0,0 +1,5 @@
+ALTER TABLE tokens ADD COLUMN name TEXT;
+ALTER TABLE tokens ADD COLUMN symbol TEXT;
+ALTER TABLE tokens ADD COLUMN decimals SMALLINT;
+ALTER TABLE tokens ADD COLUMN total_supply TEXT;
+ALTER TABLE tokens ADD COLUMN metadata_updated_at TIMESTAMP;
@@ -0,0 +1 @@
+ALTER TABLE transfers ALTER COLUMN amount TYPE TEXT USING amount::TEXT;
@@ -0,0 +1 @@
+ALTER TABLE transfers ALTER COLUMN amount TYPE NUMERIC(78,0) USING amount::NUMERIC(78,0);
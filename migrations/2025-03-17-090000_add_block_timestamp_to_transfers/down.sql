@@ -0,0 +1,2 @@
+DROP INDEX transfers_block_timestamp_idx;
+ALTER TABLE transfers DROP COLUMN block_timestamp;
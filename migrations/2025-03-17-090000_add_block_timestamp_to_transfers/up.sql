@@ -0,0 +1,11 @@
+-- Block timestamps can only be read from the node, so existing rows cannot be filled in place.
+-- Like the log_index migration, stop on a populated table and leave the reindex to the operator.
+DO $$
+BEGIN
+    IF EXISTS (SELECT 1 FROM transfers) THEN
+        RAISE EXCEPTION 'Adding block_timestamp requires reindexing the stored transfers. Run DELETE FROM transfers; DELETE FROM indexer_checkpoints; then run the migrations again (see "Upgrading an Existing Database" in the README).';
+    END IF;
+END $$;
+
+ALTER TABLE transfers ADD COLUMN block_timestamp TIMESTAMP NOT NULL;
+CREATE INDEX transfers_block_timestamp_idx ON transfers (block_timestamp);
@@ -0,0 +1,22 @@
+CREATE TABLE balances (
+    token_address TEXT NOT NULL,
+    address TEXT NOT NULL,
+    balance NUMERIC(78,0) NOT NULL,
+    updated_at TIMESTAMP NOT NULL DEFAULT NOW(),
+    PRIMARY KEY (token_address, address)
+);
+
+-- Compute the balances of the transfers indexed so far. The zero address is skipped, as it is the
+-- sender of mints and the recipient of burns.
+INSERT INTO balances (token_address, address, balance)
+SELECT token_address, address, SUM(delta)
+FROM (
+    SELECT token_address, recipient AS address, amount AS delta
+    FROM transfers
+    WHERE status = 'confirmed' AND recipient <> '0x0000000000000000000000000000000000000000'
+    UNION ALL
+    SELECT token_address, sender AS address, -amount AS delta
+    FROM transfers
+    WHERE status = 'confirmed' AND sender <> '0x0000000000000000000000000000000000000000'
+) AS deltas
+GROUP BY token_address, address;
@@ -0,0 +1,4 @@
+DROP INDEX transfers_recipient_token_address_block_number_idx;
+DROP INDEX transfers_sender_token_address_block_number_idx;
+DROP TABLE snapshot_checkpoints;
+DROP TABLE balance_snapshots;
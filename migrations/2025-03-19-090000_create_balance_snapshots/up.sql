@@ -0,0 +1,20 @@
+-- Balance of an address at the end of a block, recorded periodically for the addresses whose
+-- balance changed since the previous snapshot.
+CREATE TABLE balance_snapshots (
+    token_address TEXT NOT NULL,
+    address TEXT NOT NULL,
+    block_number BIGINT NOT NULL,
+    balance NUMERIC(78,0) NOT NULL,
+    PRIMARY KEY (token_address, address, block_number)
+);
+
+-- Last block up to which balance snapshots were taken for a token.
+CREATE TABLE snapshot_checkpoints (
+    token_address TEXT PRIMARY KEY,
+    last_block BIGINT NOT NULL,
+    updated_at TIMESTAMP NOT NULL DEFAULT NOW()
+);
+
+-- Let historical balance queries find the transfers of an address without scanning the table.
+CREATE INDEX transfers_sender_token_address_block_number_idx ON transfers (sender, token_address, block_number);
+CREATE INDEX transfers_recipient_token_address_block_number_idx ON transfers (recipient, token_address, block_number);
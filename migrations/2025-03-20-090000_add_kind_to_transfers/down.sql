@@ -0,0 +1,2 @@
+DROP INDEX transfers_token_address_kind_block_number_idx;
+ALTER TABLE transfers DROP COLUMN kind;
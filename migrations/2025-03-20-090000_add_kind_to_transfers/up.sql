@@ -0,0 +1,8 @@
+ALTER TABLE transfers ADD COLUMN kind TEXT;
+UPDATE transfers SET kind = CASE
+    WHEN sender = '0x0000000000000000000000000000000000000000' THEN 'mint'
+    WHEN recipient = '0x0000000000000000000000000000000000000000' THEN 'burn'
+    ELSE 'transfer'
+END;
+ALTER TABLE transfers ALTER COLUMN kind SET NOT NULL;
+CREATE INDEX transfers_token_address_kind_block_number_idx ON transfers (token_address, kind, block_number);
@@ -0,0 +1 @@
+ALTER TABLE tokens DROP COLUMN total_supply_block;
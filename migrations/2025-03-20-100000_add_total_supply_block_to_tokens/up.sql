@@ -0,0 +1,4 @@
+-- Block at which the cached total supply was read from the contract, to cross-check it with the
+-- indexed mints and burns at that block. Databases migrated before this column had its own migration
+-- already have it.
+ALTER TABLE tokens ADD COLUMN IF NOT EXISTS total_supply_block BIGINT;
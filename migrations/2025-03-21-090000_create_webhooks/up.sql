@@ -0,0 +1,29 @@
+CREATE TABLE webhooks (
+    id SERIAL PRIMARY KEY,
+    url TEXT NOT NULL,
+    -- Key of the HMAC-SHA256 signature of each delivered payload.
+    secret TEXT NOT NULL,
+    sender TEXT,
+    recipient TEXT,
+    min_amount NUMERIC(78,0),
+    created_at TIMESTAMP NOT NULL DEFAULT NOW()
+);
+
+-- One row per transfer to deliver to a webhook, recording its delivery attempts. Deliveries are
+-- 'pending' until delivered, or 'dead' once every attempt failed.
+CREATE TABLE webhook_deliveries (
+    id SERIAL PRIMARY KEY,
+    webhook_id INTEGER NOT NULL REFERENCES webhooks (id) ON DELETE CASCADE,
+    transfer_id INTEGER NOT NULL,
+    payload TEXT NOT NULL,
+    status TEXT NOT NULL DEFAULT 'pending',
+    attempts INTEGER NOT NULL DEFAULT 0,
+    next_attempt_at TIMESTAMP NOT NULL DEFAULT NOW(),
+    last_response_status INTEGER,
+    last_error TEXT,
+    created_at TIMESTAMP NOT NULL DEFAULT NOW(),
+    delivered_at TIMESTAMP
+);
+
+CREATE INDEX webhook_deliveries_status_next_attempt_at_idx ON webhook_deliveries (status, next_attempt_at);
+CREATE INDEX webhook_deliveries_webhook_id_id_idx ON webhook_deliveries (webhook_id, id);
@@ -0,0 +1,14 @@
+CREATE TABLE failed_ranges (
+    id SERIAL PRIMARY KEY,
+    chain_id BIGINT NOT NULL,
+    token_address TEXT NOT NULL,
+    from_block BIGINT NOT NULL,
+    to_block BIGINT NOT NULL,
+    attempts INT NOT NULL,
+    last_error TEXT NOT NULL,
+    next_retry_at TIMESTAMP NOT NULL DEFAULT NOW(),
+    created_at TIMESTAMP NOT NULL DEFAULT NOW(),
+    resolved_at TIMESTAMP
+);
+
+CREATE INDEX failed_ranges_pending_idx ON failed_ranges (chain_id, next_retry_at) WHERE resolved_at IS NULL;
@@ -0,0 +1,2 @@
+-- Block at which the token contract was deployed, found once by bisecting eth_getCode.
+ALTER TABLE tokens ADD COLUMN deployment_block BIGINT;
@@ -0,0 +1,68 @@
+use actix_web::{get, web, HttpResponse, Responder};
+use serde::{Deserialize, Serialize};
+use diesel::r2d2::ConnectionManager;
+use diesel::pg::PgConnection;
+use crate::api::transfers::parse_time;
+use crate::repositories::balance_repo::{BalanceAt, BalanceRepo};
+use crate::models::balance::{Balance, HistoricalBalance};
+use log::error;
+
+/// Query parameters for filtering balances by token and requesting them at a past block or time.
+#[derive(Deserialize)]
+pub struct BalanceQuery {
+    token: Option<String>,
+    block: Option<i64>,
+    /// RFC 3339 date or unix seconds.
+    time: Option<String>,
+}
+
+/// Response format for the balances API endpoint.
+#[derive(Serialize)]
+pub struct BalanceResponse {
+    address: String,
+    balances: Vec<Balance>,
+}
+
+/// Response format for the balances API endpoint when a block or time is given.
+#[derive(Serialize)]
+pub struct HistoricalBalanceResponse {
+    address: String,
+    balances: Vec<HistoricalBalance>,
+}
+
+/// API endpoint to retrieve the current token balances of an address, or its balances at a past
+/// block or time.
+#[get("/balances/{address}")]
+async fn get_balances(
+    path: web::Path<String>,
+    query: web::Query<BalanceQuery>,
+    pool: web::Data<diesel::r2d2::Pool<ConnectionManager<PgConnection>>>,
+) -> impl Responder {
+    let balance_repo = BalanceRepo::new(pool.as_ref().clone());
+    let address = path.into_inner().to_lowercase();
+
+    let at = match (query.block, parse_time(&query.time)) {
+        (_, Err(e)) => return HttpResponse::BadRequest().json(e),
+        (Some(_), Ok(Some(_))) => return HttpResponse::BadRequest().json("Only one of block and time can be given"),
+        (Some(block), Ok(None)) => Some(BalanceAt::Block(block)),
+        (None, Ok(Some(time))) => Some(BalanceAt::Time(time)),
+        (None, Ok(None)) => None,
+    };
+    if let Some(at) = at {
+        return match balance_repo.get_balances_at(address.clone(), query.token.clone(), at).await {
+            Ok(balances) => HttpResponse::Ok().json(HistoricalBalanceResponse { address, balances }),
+            Err(e) => {
+                error!("Failed to fetch historical balances of {}: {}", address, e);
+                HttpResponse::InternalServerError().json(format!("Error fetching balances: {}", e))
+            }
+        };
+    }
+
+    match balance_repo.get_balances(address.clone(), query.token.clone()).await {
+        Ok(balances) => HttpResponse::Ok().json(BalanceResponse { address, balances }),
+        Err(e) => {
+            error!("Failed to fetch balances of {}: {}", address, e);
+            HttpResponse::InternalServerError().json(format!("Error fetching balances: {}", e))
+        }
+    }
+}
@@ -0,0 +1,47 @@
+use actix_web::{get, web, HttpResponse, Responder};
+use serde::{Deserialize, Serialize};
+use diesel::r2d2::ConnectionManager;
+use diesel::pg::PgConnection;
+use crate::repositories::failed_range_repo::FailedRangeRepo;
+use crate::models::failed_range::FailedRange;
+use log::error;
+
+/// Number of most recent failed ranges returned by the API.
+const FAILED_RANGES_LIMIT: i64 = 100;
+
+/// Query parameters for filtering failed ranges.
+#[derive(Deserialize)]
+pub struct FailedRangeQuery {
+    /// `pending` or `resolved`.
+    status: Option<String>,
+}
+
+/// Response format for the failed ranges API endpoint.
+#[derive(Serialize)]
+pub struct FailedRangeResponse {
+    failed_ranges: Vec<FailedRange>,
+}
+
+/// API endpoint to retrieve the block ranges whose backfill failed, newest first, including when
+/// their next retry is due and whether they were resolved since.
+#[get("/failed-ranges")]
+async fn get_failed_ranges(
+    query: web::Query<FailedRangeQuery>,
+    pool: web::Data<diesel::r2d2::Pool<ConnectionManager<PgConnection>>>,
+) -> impl Responder {
+    let resolved = match query.status.as_deref() {
+        None => None,
+        Some("pending") => Some(false),
+        Some("resolved") => Some(true),
+        Some(status) => return HttpResponse::BadRequest().json(format!("Invalid status: {}", status)),
+    };
+    let failed_range_repo = FailedRangeRepo::new(pool.as_ref().clone());
+
+    match failed_range_repo.get_failed_ranges(resolved, FAILED_RANGES_LIMIT).await {
+        Ok(failed_ranges) => HttpResponse::Ok().json(FailedRangeResponse { failed_ranges }),
+        Err(e) => {
+            error!("Failed to fetch failed ranges: {}", e);
+            HttpResponse::InternalServerError().json(format!("Error fetching failed ranges: {}", e))
+        }
+    }
+}
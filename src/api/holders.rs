@@ -0,0 +1,136 @@
+use actix_web::{get, web, HttpResponse, Responder};
+use serde::{Deserialize, Serialize};
+use diesel::r2d2::ConnectionManager;
+use diesel::pg::PgConnection;
+use crate::repositories::balance_repo::BalanceRepo;
+use crate::repositories::token_repo::TokenRepo;
+use crate::models::transfer::serialize_amount;
+use bigdecimal::{BigDecimal, ToPrimitive, Zero};
+use log::error;
+use std::str::FromStr;
+
+/// Number of holders returned per page when no limit is given.
+const DEFAULT_PAGE_SIZE: i64 = 100;
+/// Maximum number of holders returned per page.
+const MAX_PAGE_SIZE: i64 = 1000;
+
+/// Query parameters for selecting the token, the block and the page of holders.
+#[derive(Deserialize)]
+pub struct HolderQuery {
+    token: Option<String>,
+    block: Option<i64>,
+    limit: Option<i64>,
+    cursor: Option<String>,
+}
+
+/// An address holding the token, with its rank and share of the supply.
+#[derive(Serialize)]
+pub struct HolderEntry {
+    rank: i64,
+    address: String,
+    #[serde(serialize_with = "serialize_amount")]
+    balance: BigDecimal,
+    /// Fraction of the total supply held, between 0 and 1.
+    share: f64,
+}
+
+/// Response format for the holders API endpoint.
+#[derive(Serialize)]
+pub struct HolderResponse {
+    token: String,
+    /// Block at the end of which balances are given, absent for current balances.
+    block_number: Option<i64>,
+    /// Number of addresses with a non-zero balance.
+    holder_count: i64,
+    /// Sum of the balances of all holders.
+    #[serde(serialize_with = "serialize_amount")]
+    total_supply: BigDecimal,
+    holders: Vec<HolderEntry>,
+    /// Cursor to pass to fetch the next page, absent on the last page.
+    next_cursor: Option<String>,
+}
+
+/// Encodes the position of a holder into an opaque pagination cursor.
+fn encode_cursor(balance: &BigDecimal, address: &str, rank: i64) -> String {
+    hex::encode(format!("{}:{}:{}", balance.to_plain_string(), address, rank))
+}
+
+/// Decodes a pagination cursor into the `(balance, address, rank)` position it points to.
+fn decode_cursor(cursor: &str) -> Option<(BigDecimal, String, i64)> {
+    let decoded = String::from_utf8(hex::decode(cursor).ok()?).ok()?;
+    let mut parts = decoded.splitn(3, ':');
+    let balance = BigDecimal::from_str(parts.next()?).ok()?;
+    let address = parts.next()?.to_string();
+    let rank = parts.next()?.parse().ok()?;
+    Some((balance, address, rank))
+}
+
+/// API endpoint to retrieve the holders of a token ranked by balance, currently or at a past block.
+#[get("/holders")]
+async fn get_holders(
+    query: web::Query<HolderQuery>,
+    pool: web::Data<diesel::r2d2::Pool<ConnectionManager<PgConnection>>>,
+) -> impl Responder {
+    let balance_repo = BalanceRepo::new(pool.as_ref().clone());
+    let token_repo = TokenRepo::new(pool.as_ref().clone());
+
+    let (after, rank_offset) = match query.cursor.as_deref().map(decode_cursor) {
+        Some(Some((balance, address, rank))) => (Some((balance, address)), rank),
+        Some(None) => return HttpResponse::BadRequest().json("Invalid cursor"),
+        None => (None, 0),
+    };
+    let limit = query.limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE);
+
+    // Default to the only registered token when none is given.
+    let token = match &query.token {
+        Some(address) => address.to_lowercase(),
+        None => match token_repo.get_token_addresses().await {
+            Ok(mut addresses) if addresses.len() == 1 => addresses.remove(0),
+            Ok(_) => return HttpResponse::BadRequest().json("A token must be given when several tokens are indexed"),
+            Err(e) => {
+                error!("Failed to fetch tokens: {}", e);
+                return HttpResponse::InternalServerError().json(format!("Error fetching tokens: {}", e));
+            }
+        },
+    };
+
+    // Fetch one extra holder to know whether another page follows.
+    match balance_repo.get_holders(token.clone(), query.block, after, limit + 1).await {
+        Ok((mut holders, stats)) => {
+            let next_cursor = if holders.len() as i64 > limit {
+                holders.truncate(limit as usize);
+                holders.last().map(|holder| encode_cursor(&holder.balance, &holder.address, rank_offset + limit))
+            } else {
+                None
+            };
+            let holders = holders.into_iter()
+                .enumerate()
+                .map(|(position, holder)| {
+                    let share = if stats.total_supply.is_zero() {
+                        0.0
+                    } else {
+                        (&holder.balance / &stats.total_supply).to_f64().unwrap_or_default()
+                    };
+                    HolderEntry {
+                        rank: rank_offset + position as i64 + 1,
+                        address: holder.address,
+                        balance: holder.balance,
+                        share,
+                    }
+                })
+                .collect();
+            HttpResponse::Ok().json(HolderResponse {
+                token,
+                block_number: query.block,
+                holder_count: stats.holder_count,
+                total_supply: stats.total_supply,
+                holders,
+                next_cursor,
+            })
+        }
+        Err(e) => {
+            error!("Failed to fetch holders of {}: {}", token, e);
+            HttpResponse::InternalServerError().json(format!("Error fetching holders: {}", e))
+        }
+    }
+}
@@ -1,8 +1,36 @@
 use actix_web::Scope;
 
 pub mod transfers;
+pub mod transfer_stream;
+pub mod reorgs;
+pub mod failed_ranges;
+pub mod rpc_metrics;
+pub mod tokens;
+pub mod balances;
+pub mod holders;
+pub mod supply;
+pub mod ws;
+pub mod webhooks;
 
 pub fn eth_scope() -> Scope {
     Scope::new("/eth")
         .service(transfers::get_transfers)
+        .service(transfer_stream::stream_transfers)
+        .service(reorgs::get_reorgs)
+        .service(failed_ranges::get_failed_ranges)
+        .service(rpc_metrics::get_rpc_metrics)
+        .service(tokens::get_tokens)
+        .service(balances::get_balances)
+        .service(holders::get_holders)
+        .service(supply::get_supply)
+        .service(ws::transfers_ws)
+}
+
+pub fn admin_scope() -> Scope {
+    Scope::new("/admin")
+        .service(webhooks::create_webhook)
+        .service(webhooks::get_webhooks)
+        .service(webhooks::delete_webhook)
+        .service(webhooks::get_deliveries)
+        .service(webhooks::retry_delivery)
 }
\ No newline at end of file
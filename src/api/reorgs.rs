@@ -0,0 +1,32 @@
+use actix_web::{get, web, HttpResponse, Responder};
+use serde::Serialize;
+use diesel::r2d2::ConnectionManager;
+use diesel::pg::PgConnection;
+use crate::repositories::block_repo::BlockRepo;
+use crate::models::block::Reorg;
+use log::error;
+
+/// Number of most recent reorganizations returned by the API.
+const REORGS_LIMIT: i64 = 100;
+
+/// Response format for the reorgs API endpoint.
+#[derive(Serialize)]
+pub struct ReorgResponse {
+    reorgs: Vec<Reorg>,
+}
+
+/// API endpoint to retrieve the chain reorganizations handled by the indexer, newest first.
+#[get("/reorgs")]
+async fn get_reorgs(
+    pool: web::Data<diesel::r2d2::Pool<ConnectionManager<PgConnection>>>,
+) -> impl Responder {
+    let block_repo = BlockRepo::new(pool.as_ref().clone());
+
+    match block_repo.get_reorgs(REORGS_LIMIT).await {
+        Ok(reorgs) => HttpResponse::Ok().json(ReorgResponse { reorgs }),
+        Err(e) => {
+            error!("Failed to fetch reorgs: {}", e);
+            HttpResponse::InternalServerError().json(format!("Error fetching reorgs: {}", e))
+        }
+    }
+}
@@ -0,0 +1,16 @@
+use actix_web::{get, web, HttpResponse, Responder};
+use serde::Serialize;
+use crate::utils::transport::{EndpointMetrics, FailoverTransport};
+
+/// Response format for the RPC metrics API endpoint.
+#[derive(Serialize)]
+pub struct RpcMetricsResponse {
+    endpoints: Vec<EndpointMetrics>,
+}
+
+/// API endpoint to retrieve the health of each node endpoint and the number of calls throttled by
+/// the request budget or rejected by the node over its rate limit.
+#[get("/rpc-metrics")]
+async fn get_rpc_metrics(transport: web::Data<FailoverTransport>) -> impl Responder {
+    HttpResponse::Ok().json(RpcMetricsResponse { endpoints: transport.metrics() })
+}
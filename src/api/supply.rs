@@ -0,0 +1,126 @@
+use actix_web::{get, web, HttpResponse, Responder};
+use serde::{Deserialize, Serialize};
+use diesel::r2d2::ConnectionManager;
+use diesel::pg::PgConnection;
+use crate::api::transfers::parse_time;
+use crate::repositories::transfer_repo::TransferRepo;
+use crate::repositories::token_repo::TokenRepo;
+use crate::models::token::{SupplyPoint, Token};
+use crate::models::transfer::serialize_amount;
+use bigdecimal::BigDecimal;
+use log::error;
+use std::str::FromStr;
+
+/// Query parameters for selecting the token and the periods of the supply history.
+#[derive(Deserialize)]
+pub struct SupplyQuery {
+    token: Option<String>,
+    /// `hour`, `day` (default), `week` or `month`.
+    interval: Option<String>,
+    /// RFC 3339 date or unix seconds.
+    from_time: Option<String>,
+    to_time: Option<String>,
+}
+
+/// Comparison of the on-chain `totalSupply()` with the supply computed from indexed mints and burns.
+#[derive(Serialize)]
+pub struct SupplyCheck {
+    /// Block at which `totalSupply()` was read.
+    block_number: i64,
+    #[serde(serialize_with = "serialize_amount")]
+    onchain_supply: BigDecimal,
+    /// Supply computed from the mints and burns indexed up to the same block.
+    #[serde(serialize_with = "serialize_amount")]
+    indexed_supply: BigDecimal,
+    /// `onchain_supply - indexed_supply`.
+    #[serde(serialize_with = "serialize_amount")]
+    difference: BigDecimal,
+    matches: bool,
+}
+
+/// Response format for the supply API endpoint.
+#[derive(Serialize)]
+pub struct SupplyResponse {
+    token: Token,
+    /// Supply computed from all indexed mints minus burns.
+    #[serde(serialize_with = "serialize_amount")]
+    supply: BigDecimal,
+    /// Absent until `totalSupply()` could be read from the contract.
+    check: Option<SupplyCheck>,
+    history: Vec<SupplyPoint>,
+}
+
+/// API endpoint to retrieve the supply of a token over time, derived from its mints and burns and
+/// cross-checked with the contract's `totalSupply()`.
+#[get("/supply")]
+async fn get_supply(
+    query: web::Query<SupplyQuery>,
+    pool: web::Data<diesel::r2d2::Pool<ConnectionManager<PgConnection>>>,
+) -> impl Responder {
+    let transfer_repo = TransferRepo::new(pool.as_ref().clone());
+    let token_repo = TokenRepo::new(pool.as_ref().clone());
+
+    let period = match query.interval.as_deref() {
+        Some("hour") => "hour",
+        Some("day") | None => "day",
+        Some("week") => "week",
+        Some("month") => "month",
+        Some(_) => return HttpResponse::BadRequest().json("Invalid interval, expected hour, day, week or month"),
+    };
+    let from_time = match parse_time(&query.from_time) {
+        Ok(time) => time,
+        Err(e) => return HttpResponse::BadRequest().json(e),
+    };
+    let to_time = match parse_time(&query.to_time) {
+        Ok(time) => time,
+        Err(e) => return HttpResponse::BadRequest().json(e),
+    };
+
+    // Default to the only registered token when none is given.
+    let token = match &query.token {
+        Some(address) => token_repo.get_token(address.to_lowercase()).await,
+        None => token_repo.get_tokens().await
+            .map(|mut tokens| if tokens.len() == 1 { tokens.pop() } else { None }),
+    };
+    let token = match token {
+        Ok(Some(token)) => token,
+        Ok(None) if query.token.is_some() => return HttpResponse::NotFound().json("Token not found"),
+        Ok(None) => return HttpResponse::BadRequest().json("A token must be given when several tokens are indexed"),
+        Err(e) => {
+            error!("Failed to fetch token metadata: {}", e);
+            return HttpResponse::InternalServerError().json(format!("Error fetching token metadata: {}", e));
+        }
+    };
+
+    let supply = transfer_repo.get_supply(token.address.clone(), None).await;
+    let history = transfer_repo.get_supply_history(token.address.clone(), period, from_time, to_time).await;
+    let onchain = token.total_supply.as_deref()
+        .and_then(|supply| BigDecimal::from_str(supply).ok())
+        .zip(token.total_supply_block);
+    let check = match onchain {
+        Some((onchain_supply, block_number)) => {
+            match transfer_repo.get_supply(token.address.clone(), Some(block_number)).await {
+                Ok(indexed_supply) => {
+                    let difference = &onchain_supply - &indexed_supply;
+                    Ok(Some(SupplyCheck {
+                        block_number,
+                        matches: onchain_supply == indexed_supply,
+                        onchain_supply,
+                        indexed_supply,
+                        difference,
+                    }))
+                }
+                Err(e) => Err(e),
+            }
+        }
+        None => Ok(None),
+    };
+
+    match (supply, check, history) {
+        (Ok(supply), Ok(check), Ok(history)) => HttpResponse::Ok().json(SupplyResponse { token, supply, check, history }),
+        (Err(e), _, _) | (_, Err(e), _) | (_, _, Err(e)) => {
+            error!("Failed to compute the supply of {}: {}", token.address, e);
+            HttpResponse::InternalServerError().json(format!("Error computing supply: {}", e))
+        }
+    }
+}
@@ -0,0 +1,29 @@
+use actix_web::{get, web, HttpResponse, Responder};
+use serde::Serialize;
+use diesel::r2d2::ConnectionManager;
+use diesel::pg::PgConnection;
+use crate::repositories::token_repo::TokenRepo;
+use crate::models::token::Token;
+use log::error;
+
+/// Response format for the tokens API endpoint.
+#[derive(Serialize)]
+pub struct TokenResponse {
+    tokens: Vec<Token>,
+}
+
+/// API endpoint to retrieve the registered tokens and their metadata.
+#[get("/tokens")]
+async fn get_tokens(
+    pool: web::Data<diesel::r2d2::Pool<ConnectionManager<PgConnection>>>,
+) -> impl Responder {
+    let token_repo = TokenRepo::new(pool.as_ref().clone());
+
+    match token_repo.get_tokens().await {
+        Ok(tokens) => HttpResponse::Ok().json(TokenResponse { tokens }),
+        Err(e) => {
+            error!("Failed to fetch tokens: {}", e);
+            HttpResponse::InternalServerError().json(format!("Error fetching tokens: {}", e))
+        }
+    }
+}
@@ -0,0 +1,147 @@
+use actix_web::{get, http::header, web, HttpRequest, HttpResponse, Responder};
+use actix_web::web::Bytes;
+use serde::Deserialize;
+use diesel::r2d2::ConnectionManager;
+use diesel::pg::PgConnection;
+use crate::api::transfers::{decode_cursor, encode_cursor};
+use crate::repositories::transfer_repo::{SortOrder, TransferFilter, TransferRepo};
+use crate::models::transfer::{Transfer, TransferEvent, STATUS_CONFIRMED};
+use futures::stream;
+use log::{error, warn};
+use std::collections::VecDeque;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::time::{timeout, Duration};
+
+/// Number of stored transfers fetched at once when catching up.
+const CATCH_UP_PAGE_SIZE: i64 = 500;
+/// Interval after which an idle stream sends a comment, so proxies keep the connection open.
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(15);
+
+/// Query parameters for filtering streamed transfers.
+#[derive(Deserialize)]
+pub struct TransferStreamQuery {
+    sender: Option<String>,
+    recipient: Option<String>,
+}
+
+/// State of a client's stream of transfers.
+struct TransferStream {
+    repo: TransferRepo,
+    receiver: broadcast::Receiver<TransferEvent>,
+    sender: Option<String>,
+    recipient: Option<String>,
+    /// Position of the last transfer sent, from which stored transfers are replayed when catching up.
+    last_sent: Option<(i64, i32)>,
+    /// Whether stored transfers after `last_sent` remain to be replayed before the published ones.
+    catching_up: bool,
+    /// Position up to which stored transfers were replayed; published transfers up to it were already sent.
+    replayed_up_to: Option<(i64, i32)>,
+    pending: VecDeque<Transfer>,
+}
+
+impl TransferStream {
+    fn matches(&self, transfer: &Transfer) -> bool {
+        self.sender.as_ref().is_none_or(|sender| *sender == transfer.sender)
+            && self.recipient.as_ref().is_none_or(|recipient| *recipient == transfer.recipient)
+    }
+
+    /// Waits for the next chunk to send: a transfer event, a keep-alive comment, or `None` when the
+    /// indexer stopped.
+    async fn next_chunk(&mut self) -> Option<Bytes> {
+        loop {
+            if let Some(transfer) = self.pending.pop_front() {
+                self.last_sent = Some((transfer.block_number, transfer.log_index));
+                return Some(transfer_event(&transfer));
+            }
+            if self.catching_up {
+                let filter = TransferFilter {
+                    sender: self.sender.clone(),
+                    recipient: self.recipient.clone(),
+                    status: Some(STATUS_CONFIRMED.to_string()),
+                    ..Default::default()
+                };
+                match self.repo.get_transfers(filter, SortOrder::Asc, self.last_sent, CATCH_UP_PAGE_SIZE).await {
+                    Ok(transfers) if transfers.is_empty() => {
+                        self.catching_up = false;
+                        self.replayed_up_to = self.last_sent;
+                    }
+                    Ok(transfers) => self.pending.extend(transfers),
+                    Err(e) => {
+                        error!("Failed to replay stored transfers: {}", e);
+                        return None;
+                    }
+                }
+                continue;
+            }
+            match timeout(KEEP_ALIVE_INTERVAL, self.receiver.recv()).await {
+                // Removed transfers are not notified, the SSE stream only carries new transfers.
+                Ok(Ok(TransferEvent::Removed(_))) => {}
+                Ok(Ok(TransferEvent::Indexed(transfer))) => {
+                    let position = (transfer.block_number, transfer.log_index);
+                    if self.replayed_up_to.is_some_and(|replayed| position <= replayed) {
+                        continue;
+                    }
+                    // Transfers re-indexed after a reorganization may come before the replayed ones.
+                    self.replayed_up_to = None;
+                    if self.matches(&transfer) {
+                        self.pending.push_back(transfer);
+                    }
+                }
+                Ok(Err(RecvError::Lagged(skipped))) => {
+                    warn!("Transfer stream lagged behind by {} transfers, catching up from the database", skipped);
+                    // Without a transfer sent yet, there is no position to catch up from.
+                    self.catching_up = self.last_sent.is_some();
+                }
+                Ok(Err(RecvError::Closed)) => return None,
+                Err(_) => return Some(Bytes::from_static(b": keep-alive\n\n")),
+            }
+        }
+    }
+}
+
+/// Formats a transfer as a server-sent event, identified by its position.
+fn transfer_event(transfer: &Transfer) -> Bytes {
+    let data = serde_json::to_string(transfer).unwrap_or_default();
+    Bytes::from(format!(
+        "id: {}\nevent: transfer\ndata: {}\n\n",
+        encode_cursor(transfer.block_number, transfer.log_index),
+        data,
+    ))
+}
+
+/// API endpoint streaming newly indexed confirmed transfers as server-sent events. A client
+/// reconnecting with `Last-Event-ID` first receives the stored transfers it missed.
+#[get("/transfers/stream")]
+async fn stream_transfers(
+    request: HttpRequest,
+    query: web::Query<TransferStreamQuery>,
+    pool: web::Data<diesel::r2d2::Pool<ConnectionManager<PgConnection>>>,
+    transfer_tx: web::Data<broadcast::Sender<TransferEvent>>,
+) -> impl Responder {
+    let last_event_id = request.headers().get("Last-Event-ID").map(|value| value.to_str().ok().and_then(decode_cursor));
+    let last_sent = match last_event_id {
+        Some(Some(position)) => Some(position),
+        Some(None) => return HttpResponse::BadRequest().json("Invalid Last-Event-ID"),
+        None => None,
+    };
+
+    // Subscribe before replaying, so no transfer is published in between unseen.
+    let state = TransferStream {
+        repo: TransferRepo::new(pool.as_ref().clone()),
+        receiver: transfer_tx.subscribe(),
+        sender: query.sender.as_ref().map(|sender| sender.to_lowercase()),
+        recipient: query.recipient.as_ref().map(|recipient| recipient.to_lowercase()),
+        last_sent,
+        catching_up: last_sent.is_some(),
+        replayed_up_to: None,
+        pending: VecDeque::new(),
+    };
+    let events = stream::unfold(state, |mut state| async move {
+        state.next_chunk().await.map(|chunk| (Ok::<_, actix_web::Error>(chunk), state))
+    });
+
+    HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header((header::CACHE_CONTROL, "no-cache"))
+        .streaming(events)
+}
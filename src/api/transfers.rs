@@ -2,47 +2,176 @@ use actix_web::{get, web, HttpResponse, Responder};
 use serde::{Deserialize, Serialize};
 use diesel::r2d2::ConnectionManager;
 use diesel::pg::PgConnection;
-use crate::repositories::transfer_repo::TransferRepo;
-use crate::models::transfer::Transfer;
+use crate::repositories::transfer_repo::{SortOrder, TransferFilter, TransferRepo};
+use crate::repositories::token_repo::TokenRepo;
+use crate::models::transfer::{Transfer, KIND_BURN, KIND_MINT, KIND_TRANSFER, STATUS_CONFIRMED, STATUS_UNCONFIRMED};
+use crate::models::token::Token;
 use log::error;
+use std::str::FromStr;
+use bigdecimal::BigDecimal;
+use chrono::{DateTime, NaiveDateTime};
+use web3::types::U256;
 
-/// Query parameters for filtering transfers by sender or recipient.
+/// Number of transfers returned per page when no limit is given.
+const DEFAULT_PAGE_SIZE: i64 = 100;
+/// Maximum number of transfers returned per page.
+const MAX_PAGE_SIZE: i64 = 1000;
+
+/// Query parameters for filtering and paginating transfers.
 #[derive(Deserialize)]
 pub struct TransferQuery {
     sender: Option<String>,
     recipient: Option<String>,
+    status: Option<String>,
+    token: Option<String>,
+    /// Matches transfers sent or received by this address.
+    address: Option<String>,
+    /// `mint`, `burn` or `transfer`.
+    kind: Option<String>,
+    from_block: Option<i64>,
+    to_block: Option<i64>,
+    /// RFC 3339 date or unix seconds.
+    from_time: Option<String>,
+    to_time: Option<String>,
+    min_amount: Option<String>,
+    max_amount: Option<String>,
+    /// `asc` or `desc` (default).
+    order: Option<String>,
+    limit: Option<i64>,
+    cursor: Option<String>,
 }
 
 /// Response format for the transfers API endpoint.
 #[derive(Serialize)]
 pub struct TransferResponse {
-    token: TokenInfo,
+    /// Metadata of the requested token, or of the only registered token when no token filter is given.
+    token: Option<Token>,
     transfers: Vec<Transfer>,
+    /// Cursor to pass to fetch the next page, absent on the last page.
+    next_cursor: Option<String>,
 }
 
-/// Token metadata included in the API response.
-#[derive(Serialize)]
-pub struct TokenInfo {
-    decimals: u8,
-    symbol: String,
+/// Encodes the position of a transfer into an opaque pagination cursor.
+pub(crate) fn encode_cursor(block_number: i64, log_index: i32) -> String {
+    hex::encode(format!("{}:{}", block_number, log_index))
+}
+
+/// Decodes a pagination cursor into the `(block_number, log_index)` position it points to.
+pub(crate) fn decode_cursor(cursor: &str) -> Option<(i64, i32)> {
+    let decoded = String::from_utf8(hex::decode(cursor).ok()?).ok()?;
+    let (block_number, log_index) = decoded.split_once(':')?;
+    Some((block_number.parse().ok()?, log_index.parse().ok()?))
+}
+
+/// Parses an optional amount filter, which must be a decimal integer that fits in a uint256.
+pub(crate) fn parse_amount(amount: &Option<String>) -> Result<Option<BigDecimal>, String> {
+    let Some(amount) = amount else { return Ok(None) };
+    if amount.is_empty() || !amount.bytes().all(|b| b.is_ascii_digit()) || U256::from_dec_str(amount).is_err() {
+        return Err(format!("Invalid amount: {}", amount));
+    }
+    BigDecimal::from_str(amount).map(Some).map_err(|e| format!("Invalid amount: {}", e))
+}
+
+/// Parses an optional time filter given as an RFC 3339 date or as unix seconds, into UTC.
+pub(crate) fn parse_time(time: &Option<String>) -> Result<Option<NaiveDateTime>, String> {
+    let Some(time) = time else { return Ok(None) };
+    let parsed = match time.parse::<i64>() {
+        Ok(seconds) => DateTime::from_timestamp(seconds, 0),
+        Err(_) => DateTime::parse_from_rfc3339(time).ok().map(|date| date.to_utc()),
+    };
+    parsed
+        .map(|date| Some(date.naive_utc()))
+        .ok_or_else(|| format!("Invalid time, expected RFC 3339 or unix seconds: {}", time))
 }
 
-/// API endpoint to retrieve transfer events with optional filters.
+/// API endpoint to retrieve a page of transfer events with optional filters.
 #[get("/transfers")]
 async fn get_transfers(
     query: web::Query<TransferQuery>,
     pool: web::Data<diesel::r2d2::Pool<ConnectionManager<PgConnection>>>,
 ) -> impl Responder {
     let transfer_repo = TransferRepo::new(pool.as_ref().clone());
-    
-    match transfer_repo.get_transfers(query.sender.clone(), query.recipient.clone()).await {
-        Ok(transfers) => {
+    let token_repo = TokenRepo::new(pool.as_ref().clone());
+
+    let after = match query.cursor.as_deref().map(decode_cursor) {
+        Some(Some(position)) => Some(position),
+        Some(None) => return HttpResponse::BadRequest().json("Invalid cursor"),
+        None => None,
+    };
+    let order = match query.order.as_deref() {
+        Some("asc") => SortOrder::Asc,
+        Some("desc") | None => SortOrder::Desc,
+        Some(_) => return HttpResponse::BadRequest().json("Invalid order, expected asc or desc"),
+    };
+    if let Some(status) = query.status.as_deref() {
+        if ![STATUS_CONFIRMED, STATUS_UNCONFIRMED].contains(&status) {
+            return HttpResponse::BadRequest().json("Invalid status, expected confirmed or unconfirmed");
+        }
+    }
+    if let Some(kind) = query.kind.as_deref() {
+        if ![KIND_MINT, KIND_BURN, KIND_TRANSFER].contains(&kind) {
+            return HttpResponse::BadRequest().json("Invalid kind, expected mint, burn or transfer");
+        }
+    }
+    let min_amount = match parse_amount(&query.min_amount) {
+        Ok(amount) => amount,
+        Err(e) => return HttpResponse::BadRequest().json(e),
+    };
+    let max_amount = match parse_amount(&query.max_amount) {
+        Ok(amount) => amount,
+        Err(e) => return HttpResponse::BadRequest().json(e),
+    };
+    let from_time = match parse_time(&query.from_time) {
+        Ok(time) => time,
+        Err(e) => return HttpResponse::BadRequest().json(e),
+    };
+    let to_time = match parse_time(&query.to_time) {
+        Ok(time) => time,
+        Err(e) => return HttpResponse::BadRequest().json(e),
+    };
+    let limit = query.limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE);
+
+    let token = match &query.token {
+        Some(address) => token_repo.get_token(address.to_lowercase()).await,
+        None => token_repo.get_tokens().await
+            .map(|mut tokens| if tokens.len() == 1 { tokens.pop() } else { None }),
+    };
+    let token = match token {
+        Ok(token) => token,
+        Err(e) => {
+            error!("Failed to fetch token metadata: {}", e);
+            return HttpResponse::InternalServerError().json(format!("Error fetching token metadata: {}", e));
+        }
+    };
+
+    let filter = TransferFilter {
+        sender: query.sender.clone(),
+        recipient: query.recipient.clone(),
+        address: query.address.clone(),
+        status: query.status.clone(),
+        token_address: query.token.clone(),
+        kind: query.kind.clone(),
+        from_block: query.from_block,
+        to_block: query.to_block,
+        from_time,
+        to_time,
+        min_amount,
+        max_amount,
+    };
+
+    // Fetch one extra transfer to know whether another page follows.
+    match transfer_repo.get_transfers(filter, order, after, limit + 1).await {
+        Ok(mut transfers) => {
+            let next_cursor = if transfers.len() as i64 > limit {
+                transfers.truncate(limit as usize);
+                transfers.last().map(|transfer| encode_cursor(transfer.block_number, transfer.log_index))
+            } else {
+                None
+            };
             let response = TransferResponse {
-                token: TokenInfo {
-                    decimals: 18,
-                    symbol: "LOB".to_string(),
-                },
+                token,
                 transfers,
+                next_cursor,
             };
             HttpResponse::Ok().json(response)
         }
@@ -51,4 +180,97 @@ async fn get_transfers(
             HttpResponse::InternalServerError().json(format!("Error fetching transfers: {}", e))
         }
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::StatusCode;
+    use actix_web::test::{call_service, init_service, TestRequest};
+    use actix_web::App;
+
+    #[test]
+    fn cursor_round_trips() {
+        for (block_number, log_index) in [(0, 0), (1, 2), (19_000_000, 513), (i64::MAX, i32::MAX)] {
+            let cursor = encode_cursor(block_number, log_index);
+            assert!(cursor.bytes().all(|b| b.is_ascii_hexdigit()));
+            assert_eq!(decode_cursor(&cursor), Some((block_number, log_index)));
+        }
+    }
+
+    #[test]
+    fn malformed_cursors_are_not_decoded() {
+        assert_eq!(decode_cursor(""), None);
+        assert_eq!(decode_cursor("zz"), None);
+        assert_eq!(decode_cursor("abc"), None);
+        assert_eq!(decode_cursor(&hex::encode("12")), None);
+        assert_eq!(decode_cursor(&hex::encode("12:x")), None);
+        assert_eq!(decode_cursor(&hex::encode("12:99999999999")), None);
+        assert_eq!(decode_cursor(&hex::encode([0xff, 0xfe])), None);
+    }
+
+    fn amount(value: &str) -> Result<Option<BigDecimal>, String> {
+        parse_amount(&Some(value.to_string()))
+    }
+
+    fn time(value: &str) -> Result<Option<NaiveDateTime>, String> {
+        parse_time(&Some(value.to_string()))
+    }
+
+    #[test]
+    fn amounts_must_be_uint256_decimal_integers() {
+        assert_eq!(parse_amount(&None), Ok(None));
+        assert_eq!(amount("0"), Ok(Some(BigDecimal::from(0))));
+        assert_eq!(amount("1000000000000000000"), Ok(Some(BigDecimal::from(1_000_000_000_000_000_000u64))));
+        let max = U256::MAX.to_string();
+        assert_eq!(amount(&max), Ok(Some(BigDecimal::from_str(&max).unwrap())));
+
+        // 2^256 overflows a uint256.
+        let overflow = "115792089237316195423570985008687907853269984665640564039457584007913129639936";
+        for invalid in ["", "-1", "+1", "1.5", "1e18", " 1", "0x10", "ten", overflow] {
+            assert!(amount(invalid).is_err(), "{:?}", invalid);
+        }
+    }
+
+    #[test]
+    fn times_are_rfc3339_dates_or_unix_seconds() {
+        let date = |value: &str| NaiveDateTime::parse_from_str(value, "%Y-%m-%d %H:%M:%S").unwrap();
+        assert_eq!(parse_time(&None), Ok(None));
+        assert_eq!(time("1700000000"), Ok(Some(date("2023-11-14 22:13:20"))));
+        assert_eq!(time("0"), Ok(Some(date("1970-01-01 00:00:00"))));
+        assert_eq!(time("-86400"), Ok(Some(date("1969-12-31 00:00:00"))));
+        assert_eq!(time("2023-11-14T22:13:20Z"), Ok(Some(date("2023-11-14 22:13:20"))));
+        // Offsets are converted to UTC.
+        assert_eq!(time("2023-11-15T00:13:20+02:00"), Ok(Some(date("2023-11-14 22:13:20"))));
+
+        for invalid in ["", "yesterday", "2023-11-14", "2023-11-14 22:13:20", "2023-02-30T00:00:00Z", "2023-11-14T25:00:00Z", "1.5", "99999999999999999"] {
+            assert!(time(invalid).is_err(), "{:?}", invalid);
+        }
+    }
+
+    /// Pool that never connects, for requests rejected before the database is queried.
+    fn unconnected_pool() -> diesel::r2d2::Pool<ConnectionManager<PgConnection>> {
+        diesel::r2d2::Pool::builder()
+            .min_idle(Some(0))
+            .build_unchecked(ConnectionManager::<PgConnection>::new("postgres://localhost/unused"))
+    }
+
+    #[actix_web::test]
+    async fn malformed_cursor_is_rejected_with_bad_request() {
+        let app = init_service(App::new().app_data(web::Data::new(unconnected_pool())).service(get_transfers)).await;
+        for cursor in ["zz", "3132", "31323a78"] {
+            let request = TestRequest::get().uri(&format!("/transfers?cursor={}", cursor)).to_request();
+            let response = call_service(&app, request).await;
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST, "cursor {}", cursor);
+        }
+    }
+
+    #[actix_web::test]
+    async fn unknown_status_is_rejected_with_bad_request() {
+        let app = init_service(App::new().app_data(web::Data::new(unconnected_pool())).service(get_transfers)).await;
+        for status in ["pending", "Confirmed", ""] {
+            let request = TestRequest::get().uri(&format!("/transfers?status={}", status)).to_request();
+            let response = call_service(&app, request).await;
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST, "status {:?}", status);
+        }
+    }
+}
@@ -0,0 +1,229 @@
+use actix_web::{delete, get, post, web, HttpRequest, HttpResponse, Responder};
+use actix_web::http::header;
+use serde::{Deserialize, Serialize};
+use diesel::r2d2::ConnectionManager;
+use diesel::pg::PgConnection;
+use crate::api::transfers::parse_amount;
+use crate::repositories::webhook_repo::WebhookRepo;
+use crate::models::webhook::{
+    NewWebhook, Webhook, WebhookDelivery, DELIVERY_CANCELLED, DELIVERY_DEAD, DELIVERY_DELIVERED, DELIVERY_PENDING,
+};
+use log::error;
+use web3::types::H160;
+
+/// Number of deliveries returned per page when no limit is given.
+const DEFAULT_PAGE_SIZE: i64 = 100;
+/// Maximum number of deliveries returned per page.
+const MAX_PAGE_SIZE: i64 = 1000;
+
+/// Token expected as `Authorization: Bearer <token>` by the admin endpoints, which are disabled when unset.
+#[derive(Debug, Clone)]
+pub struct AdminToken(pub Option<String>);
+
+/// Request body for registering a webhook.
+#[derive(Deserialize)]
+pub struct CreateWebhookRequest {
+    url: String,
+    /// Key of the HMAC-SHA256 signature of each delivery.
+    secret: String,
+    sender: Option<String>,
+    recipient: Option<String>,
+    min_amount: Option<String>,
+}
+
+/// Response format for the webhooks API endpoint.
+#[derive(Serialize)]
+pub struct WebhooksResponse {
+    webhooks: Vec<Webhook>,
+}
+
+/// Query parameters for filtering and paginating the deliveries of a webhook.
+#[derive(Deserialize)]
+pub struct DeliveryQuery {
+    /// `pending`, `delivered` or `dead`.
+    status: Option<String>,
+    limit: Option<i64>,
+    cursor: Option<i32>,
+}
+
+/// Response format for the webhook deliveries API endpoint.
+#[derive(Serialize)]
+pub struct DeliveryResponse {
+    deliveries: Vec<WebhookDelivery>,
+    /// Cursor to pass to fetch the next page, absent on the last page.
+    next_cursor: Option<i32>,
+}
+
+/// Checks the bearer token of an admin request, returning the error response to send if it is refused.
+fn refuse_unauthorized(request: &HttpRequest, admin_token: &AdminToken) -> Option<HttpResponse> {
+    let Some(expected) = &admin_token.0 else {
+        return Some(HttpResponse::Forbidden().json("Admin API is disabled, set ADMIN_API_TOKEN to enable it"));
+    };
+    let given = request.headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    // Compare every byte, so the time taken does not reveal how much of the token matched.
+    let matches = given.is_some_and(|given| {
+        given.len() == expected.len()
+            && given.bytes().zip(expected.bytes()).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+    });
+    (!matches).then(|| HttpResponse::Unauthorized().json("Invalid or missing admin token"))
+}
+
+/// Parses an optional address filter into the lowercase hex form addresses are stored in.
+fn parse_address(address: &Option<String>) -> Result<Option<String>, String> {
+    let Some(address) = address else { return Ok(None) };
+    address.parse::<H160>()
+        .map(|parsed| Some(format!("0x{}", hex::encode(parsed.as_bytes()))))
+        .map_err(|_| format!("Invalid address: {}", address))
+}
+
+/// Validates a webhook registration into the webhook to store.
+fn parse_webhook(body: &CreateWebhookRequest) -> Result<NewWebhook, String> {
+    let url = reqwest::Url::parse(&body.url).map_err(|e| format!("Invalid url: {}", e))?;
+    if url.scheme() != "http" && url.scheme() != "https" {
+        return Err("Webhook url must use http or https".to_string());
+    }
+    if body.secret.is_empty() {
+        return Err("Webhook secret must not be empty".to_string());
+    }
+    Ok(NewWebhook {
+        url: url.to_string(),
+        secret: body.secret.clone(),
+        sender: parse_address(&body.sender)?,
+        recipient: parse_address(&body.recipient)?,
+        min_amount: parse_amount(&body.min_amount)?,
+    })
+}
+
+/// API endpoint to register a webhook receiving the confirmed transfers matching its filters.
+#[post("/webhooks")]
+async fn create_webhook(
+    request: HttpRequest,
+    body: web::Json<CreateWebhookRequest>,
+    admin_token: web::Data<AdminToken>,
+    pool: web::Data<diesel::r2d2::Pool<ConnectionManager<PgConnection>>>,
+) -> impl Responder {
+    if let Some(response) = refuse_unauthorized(&request, &admin_token) {
+        return response;
+    }
+    let new_webhook = match parse_webhook(&body) {
+        Ok(new_webhook) => new_webhook,
+        Err(e) => return HttpResponse::BadRequest().json(e),
+    };
+    let webhook_repo = WebhookRepo::new(pool.as_ref().clone());
+
+    match webhook_repo.create_webhook(new_webhook).await {
+        Ok(webhook) => HttpResponse::Created().json(webhook),
+        Err(e) => {
+            error!("Failed to create webhook: {}", e);
+            HttpResponse::InternalServerError().json(format!("Error creating webhook: {}", e))
+        }
+    }
+}
+
+/// API endpoint to list the registered webhooks.
+#[get("/webhooks")]
+async fn get_webhooks(
+    request: HttpRequest,
+    admin_token: web::Data<AdminToken>,
+    pool: web::Data<diesel::r2d2::Pool<ConnectionManager<PgConnection>>>,
+) -> impl Responder {
+    if let Some(response) = refuse_unauthorized(&request, &admin_token) {
+        return response;
+    }
+    let webhook_repo = WebhookRepo::new(pool.as_ref().clone());
+
+    match webhook_repo.get_webhooks().await {
+        Ok(webhooks) => HttpResponse::Ok().json(WebhooksResponse { webhooks }),
+        Err(e) => {
+            error!("Failed to fetch webhooks: {}", e);
+            HttpResponse::InternalServerError().json(format!("Error fetching webhooks: {}", e))
+        }
+    }
+}
+
+/// API endpoint to delete a webhook along with its delivery log.
+#[delete("/webhooks/{id}")]
+async fn delete_webhook(
+    request: HttpRequest,
+    path: web::Path<i32>,
+    admin_token: web::Data<AdminToken>,
+    pool: web::Data<diesel::r2d2::Pool<ConnectionManager<PgConnection>>>,
+) -> impl Responder {
+    if let Some(response) = refuse_unauthorized(&request, &admin_token) {
+        return response;
+    }
+    let webhook_repo = WebhookRepo::new(pool.as_ref().clone());
+
+    match webhook_repo.delete_webhook(path.into_inner()).await {
+        Ok(true) => HttpResponse::NoContent().finish(),
+        Ok(false) => HttpResponse::NotFound().json("Webhook not found"),
+        Err(e) => {
+            error!("Failed to delete webhook: {}", e);
+            HttpResponse::InternalServerError().json(format!("Error deleting webhook: {}", e))
+        }
+    }
+}
+
+/// API endpoint to retrieve the delivery log of a webhook, newest first.
+#[get("/webhooks/{id}/deliveries")]
+async fn get_deliveries(
+    request: HttpRequest,
+    path: web::Path<i32>,
+    query: web::Query<DeliveryQuery>,
+    admin_token: web::Data<AdminToken>,
+    pool: web::Data<diesel::r2d2::Pool<ConnectionManager<PgConnection>>>,
+) -> impl Responder {
+    if let Some(response) = refuse_unauthorized(&request, &admin_token) {
+        return response;
+    }
+    if let Some(status) = &query.status {
+        if ![DELIVERY_PENDING, DELIVERY_DELIVERED, DELIVERY_DEAD, DELIVERY_CANCELLED].contains(&status.as_str()) {
+            return HttpResponse::BadRequest().json(format!("Invalid status: {}", status));
+        }
+    }
+    let limit = query.limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE);
+    let webhook_repo = WebhookRepo::new(pool.as_ref().clone());
+
+    // Fetch one extra delivery to know whether another page follows.
+    match webhook_repo.get_deliveries(path.into_inner(), query.status.clone(), query.cursor, limit + 1).await {
+        Ok(mut deliveries) => {
+            let next_cursor = if deliveries.len() as i64 > limit {
+                deliveries.truncate(limit as usize);
+                deliveries.last().map(|delivery| delivery.id)
+            } else {
+                None
+            };
+            HttpResponse::Ok().json(DeliveryResponse { deliveries, next_cursor })
+        }
+        Err(e) => {
+            error!("Failed to fetch webhook deliveries: {}", e);
+            HttpResponse::InternalServerError().json(format!("Error fetching webhook deliveries: {}", e))
+        }
+    }
+}
+
+/// API endpoint to retry a dead delivery, starting a new series of attempts.
+#[post("/webhooks/deliveries/{id}/retry")]
+async fn retry_delivery(
+    request: HttpRequest,
+    path: web::Path<i32>,
+    admin_token: web::Data<AdminToken>,
+    pool: web::Data<diesel::r2d2::Pool<ConnectionManager<PgConnection>>>,
+) -> impl Responder {
+    if let Some(response) = refuse_unauthorized(&request, &admin_token) {
+        return response;
+    }
+    let webhook_repo = WebhookRepo::new(pool.as_ref().clone());
+
+    match webhook_repo.retry_delivery(path.into_inner()).await {
+        Ok(Some(delivery)) => HttpResponse::Ok().json(delivery),
+        Ok(None) => HttpResponse::NotFound().json("No dead delivery with this id"),
+        Err(e) => {
+            error!("Failed to retry webhook delivery: {}", e);
+            HttpResponse::InternalServerError().json(format!("Error retrying webhook delivery: {}", e))
+        }
+    }
+}
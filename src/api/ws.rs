@@ -0,0 +1,378 @@
+use actix_web::{get, web, HttpRequest, Responder};
+use actix_ws::{CloseCode, CloseReason, Message, MessageStream, Session};
+use serde::{Deserialize, Serialize};
+use crate::api::transfers::parse_amount;
+use crate::models::transfer::{Transfer, TransferEvent};
+use crate::services::indexer::REORG_WINDOW;
+use bigdecimal::BigDecimal;
+use log::{error, warn};
+use std::collections::{BTreeMap, HashSet};
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::time::{interval, timeout, Duration, Instant};
+use web3::types::H160;
+
+/// Maximum number of subscriptions a single connection can hold.
+const MAX_SUBSCRIPTIONS: usize = 32;
+/// Interval between two pings sent to the client.
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(30);
+/// Time without any frame from the client after which the connection is closed.
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(90);
+/// Time a frame may wait for the client to read the previous ones before the connection is closed.
+const SEND_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Message sent by a client.
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ClientMessage {
+    /// Subscribes to the transfers matching every given criterion; omitted criteria match all transfers.
+    Subscribe {
+        /// Matches transfers sent or received by any of these addresses.
+        #[serde(default)]
+        addresses: Vec<String>,
+        /// Matches transfers of any of these token contracts.
+        #[serde(default)]
+        tokens: Vec<String>,
+        min_amount: Option<String>,
+        max_amount: Option<String>,
+    },
+    Unsubscribe {
+        subscription: u64,
+    },
+}
+
+/// Message sent to a client.
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ServerMessage {
+    Subscribed { subscription: u64 },
+    Unsubscribed { subscription: u64 },
+    /// A confirmed transfer matching the listed subscriptions was indexed.
+    Transfer { subscriptions: Vec<u64>, transfer: Transfer },
+    /// A transfer previously sent to the listed subscriptions was removed by a chain reorganization.
+    Removed { subscriptions: Vec<u64>, transfer: Transfer },
+    /// The client read too slowly and this many indexed or removed transfers were skipped.
+    Lagged { skipped: u64 },
+    Error { message: String },
+}
+
+/// Criteria of a client's subscription.
+struct Subscription {
+    addresses: HashSet<String>,
+    tokens: HashSet<String>,
+    min_amount: Option<BigDecimal>,
+    max_amount: Option<BigDecimal>,
+}
+
+impl Subscription {
+    fn matches(&self, transfer: &Transfer) -> bool {
+        (self.addresses.is_empty()
+            || self.addresses.contains(&transfer.sender)
+            || self.addresses.contains(&transfer.recipient))
+            && (self.tokens.is_empty() || self.tokens.contains(&transfer.token_address))
+            && self.min_amount.as_ref().is_none_or(|min| transfer.amount >= *min)
+            && self.max_amount.as_ref().is_none_or(|max| transfer.amount <= *max)
+    }
+}
+
+/// Parses a list of addresses into the lowercase hex form they are stored in.
+fn parse_addresses(addresses: Vec<String>) -> Result<HashSet<String>, String> {
+    addresses.into_iter()
+        .map(|address| match address.parse::<H160>() {
+            Ok(parsed) => Ok(format!("0x{}", hex::encode(parsed.as_bytes()))),
+            Err(_) => Err(format!("Invalid address: {}", address)),
+        })
+        .collect()
+}
+
+/// Identifies a transfer by block number first, so the oldest sent transfers are pruned first.
+type TransferKey = (i64, String, i32);
+
+fn transfer_key(transfer: &Transfer) -> TransferKey {
+    (transfer.block_number, transfer.tx_hash.clone(), transfer.log_index)
+}
+
+/// Subscriptions of a connection, identified by the ids returned to the client.
+#[derive(Default)]
+struct Subscriptions {
+    subscriptions: BTreeMap<u64, Subscription>,
+    next_id: u64,
+    /// Subscriptions each transfer of the last `REORG_WINDOW` blocks was sent to, so a removal is
+    /// only notified to the subscriptions that received the transfer.
+    sent: BTreeMap<TransferKey, Vec<u64>>,
+}
+
+impl Subscriptions {
+    /// Applies a message from the client and returns the reply.
+    fn handle(&mut self, text: &str) -> ServerMessage {
+        let message = match serde_json::from_str::<ClientMessage>(text) {
+            Ok(message) => message,
+            Err(e) => return ServerMessage::Error { message: format!("Invalid message: {}", e) },
+        };
+        match message {
+            ClientMessage::Subscribe { addresses, tokens, min_amount, max_amount } => {
+                if self.subscriptions.len() >= MAX_SUBSCRIPTIONS {
+                    return ServerMessage::Error { message: format!("At most {} subscriptions per connection", MAX_SUBSCRIPTIONS) };
+                }
+                let subscription = parse_addresses(addresses).and_then(|addresses| Ok(Subscription {
+                    addresses,
+                    tokens: parse_addresses(tokens)?,
+                    min_amount: parse_amount(&min_amount)?,
+                    max_amount: parse_amount(&max_amount)?,
+                }));
+                match subscription {
+                    Ok(subscription) => {
+                        self.next_id += 1;
+                        self.subscriptions.insert(self.next_id, subscription);
+                        ServerMessage::Subscribed { subscription: self.next_id }
+                    }
+                    Err(message) => ServerMessage::Error { message },
+                }
+            }
+            ClientMessage::Unsubscribe { subscription } => match self.subscriptions.remove(&subscription) {
+                Some(_) => ServerMessage::Unsubscribed { subscription },
+                None => ServerMessage::Error { message: format!("Unknown subscription: {}", subscription) },
+            },
+        }
+    }
+
+    /// Builds the notification of an event for the subscriptions it matches, if any. A removal is
+    /// notified to the remaining subscriptions the transfer was sent to.
+    fn notification(&mut self, event: TransferEvent) -> Option<ServerMessage> {
+        match event {
+            TransferEvent::Indexed(transfer) => {
+                let subscriptions: Vec<u64> = self.subscriptions.iter()
+                    .filter(|(_, subscription)| subscription.matches(&transfer))
+                    .map(|(id, _)| *id)
+                    .collect();
+                if subscriptions.is_empty() {
+                    return None;
+                }
+                // Transfers of blocks below the reorganization window can no longer be removed.
+                let oldest_block = transfer.block_number.saturating_sub(REORG_WINDOW as i64);
+                self.sent = self.sent.split_off(&(oldest_block, String::new(), i32::MIN));
+                self.sent.insert(transfer_key(&transfer), subscriptions.clone());
+                Some(ServerMessage::Transfer { subscriptions, transfer })
+            }
+            TransferEvent::Removed(transfer) => {
+                let subscriptions: Vec<u64> = self.sent.remove(&transfer_key(&transfer))?
+                    .into_iter()
+                    .filter(|id| self.subscriptions.contains_key(id))
+                    .collect();
+                if subscriptions.is_empty() {
+                    return None;
+                }
+                Some(ServerMessage::Removed { subscriptions, transfer })
+            }
+        }
+    }
+}
+
+/// Serves a client connection until it is closed, forwarding the events matching its subscriptions.
+async fn serve_connection(
+    mut session: Session,
+    mut messages: MessageStream,
+    mut events: broadcast::Receiver<TransferEvent>,
+) {
+    let mut subscriptions = Subscriptions::default();
+    let mut heartbeat = interval(HEARTBEAT_INTERVAL);
+    let mut last_seen = Instant::now();
+    let close_reason = loop {
+        let reply = tokio::select! {
+            message = messages.recv() => {
+                last_seen = Instant::now();
+                match message {
+                    Some(Ok(Message::Text(text))) => subscriptions.handle(&text),
+                    Some(Ok(Message::Ping(bytes))) => {
+                        if session.pong(&bytes).await.is_err() {
+                            return;
+                        }
+                        continue;
+                    }
+                    Some(Ok(Message::Close(reason))) => break reason,
+                    Some(Ok(Message::Binary(_))) => ServerMessage::Error { message: "Messages must be JSON text".to_string() },
+                    Some(Ok(_)) => continue,
+                    Some(Err(e)) => {
+                        warn!("WebSocket protocol error: {}", e);
+                        break Some(CloseCode::Protocol.into());
+                    }
+                    None => break None,
+                }
+            }
+            event = events.recv() => match event {
+                Ok(event) => match subscriptions.notification(event) {
+                    Some(notification) => notification,
+                    None => continue,
+                },
+                Err(RecvError::Lagged(skipped)) => ServerMessage::Lagged { skipped },
+                Err(RecvError::Closed) => break Some(CloseCode::Away.into()),
+            },
+            _ = heartbeat.tick() => {
+                if last_seen.elapsed() > CLIENT_TIMEOUT {
+                    break Some(CloseReason { code: CloseCode::Normal, description: Some("Client timed out".to_string()) });
+                }
+                if session.ping(b"").await.is_err() {
+                    return;
+                }
+                continue;
+            }
+        };
+        let text = match serde_json::to_string(&reply) {
+            Ok(text) => text,
+            Err(e) => {
+                error!("Failed to serialize WebSocket message: {}", e);
+                continue;
+            }
+        };
+        // Disconnect a client that stopped reading rather than buffering frames for it.
+        match timeout(SEND_TIMEOUT, session.text(text)).await {
+            Ok(Ok(())) => {}
+            Ok(Err(_)) => return,
+            Err(_) => {
+                warn!("Closing WebSocket connection of a client not reading its frames");
+                break Some(CloseReason { code: CloseCode::Policy, description: Some("Client too slow".to_string()) });
+            }
+        }
+    };
+    let _ = session.close(close_reason).await;
+}
+
+/// API endpoint upgrading to a WebSocket on which clients subscribe to newly indexed confirmed
+/// transfers, and to their removal by chain reorganizations.
+#[get("/ws")]
+async fn transfers_ws(
+    request: HttpRequest,
+    body: web::Payload,
+    transfer_tx: web::Data<broadcast::Sender<TransferEvent>>,
+) -> impl Responder {
+    let (response, session, messages) = match actix_ws::handle(&request, body) {
+        Ok(handshake) => handshake,
+        Err(e) => return e.error_response(),
+    };
+    actix_web::rt::spawn(serve_connection(session, messages, transfer_tx.subscribe()));
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::NaiveDateTime;
+    use serde_json::{json, Value};
+    use std::str::FromStr;
+
+    const ALICE: &str = "0x1111111111111111111111111111111111111111";
+    const BOB: &str = "0x2222222222222222222222222222222222222222";
+    const TOKEN: &str = "0xaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa";
+
+    fn transfer(block_number: i64, sender: &str, amount: &str) -> Transfer {
+        Transfer {
+            id: 1,
+            sender: sender.to_string(),
+            recipient: BOB.to_string(),
+            amount: BigDecimal::from_str(amount).unwrap(),
+            block_number,
+            tx_hash: format!("0x{:064x}", block_number),
+            log_index: 0,
+            transaction_index: 0,
+            status: "confirmed".to_string(),
+            token_address: TOKEN.to_string(),
+            block_timestamp: NaiveDateTime::default(),
+            kind: "transfer".to_string(),
+        }
+    }
+
+    fn reply(subscriptions: &mut Subscriptions, message: Value) -> Value {
+        serde_json::to_value(subscriptions.handle(&message.to_string())).unwrap()
+    }
+
+    /// Returns the type of the notification of an event and the subscriptions it lists, if any.
+    fn notified(subscriptions: &mut Subscriptions, event: TransferEvent) -> Option<(String, Value)> {
+        subscriptions.notification(event).map(|message| {
+            let message = serde_json::to_value(message).unwrap();
+            (message["type"].as_str().unwrap().to_string(), message["subscriptions"].clone())
+        })
+    }
+
+    #[test]
+    fn subscribe_and_unsubscribe_messages_are_parsed() {
+        let mut subscriptions = Subscriptions::default();
+        let subscribe = json!({"type": "subscribe", "addresses": [ALICE], "tokens": [TOKEN.to_uppercase().replace("0X", "0x")], "min_amount": "10"});
+        assert_eq!(reply(&mut subscriptions, subscribe), json!({"type": "subscribed", "subscription": 1}));
+        assert_eq!(reply(&mut subscriptions, json!({"type": "subscribe"})), json!({"type": "subscribed", "subscription": 2}));
+        // Addresses are stored lowercase, as transfers are.
+        assert!(subscriptions.subscriptions[&1].tokens.contains(TOKEN));
+
+        for invalid in [
+            json!({"type": "subscribe", "addresses": ["0x1234"]}),
+            json!({"type": "subscribe", "tokens": ["not an address"]}),
+            json!({"type": "subscribe", "min_amount": "-1"}),
+            json!({"type": "subscribe", "max_amount": "1.5"}),
+            json!({"type": "unsubscribe"}),
+            json!({"type": "publish"}),
+        ] {
+            assert_eq!(reply(&mut subscriptions, invalid.clone())["type"], "error", "{}", invalid);
+        }
+        assert_eq!(serde_json::to_value(subscriptions.handle("not json")).unwrap()["type"], "error");
+
+        assert_eq!(reply(&mut subscriptions, json!({"type": "unsubscribe", "subscription": 1})), json!({"type": "unsubscribed", "subscription": 1}));
+        assert_eq!(reply(&mut subscriptions, json!({"type": "unsubscribe", "subscription": 1}))["type"], "error");
+        assert_eq!(subscriptions.subscriptions.keys().collect::<Vec<_>>(), [&2]);
+    }
+
+    #[test]
+    fn subscriptions_per_connection_are_limited() {
+        let mut subscriptions = Subscriptions::default();
+        for id in 1..=MAX_SUBSCRIPTIONS as u64 {
+            assert_eq!(reply(&mut subscriptions, json!({"type": "subscribe"})), json!({"type": "subscribed", "subscription": id}));
+        }
+        assert_eq!(reply(&mut subscriptions, json!({"type": "subscribe"}))["type"], "error");
+        reply(&mut subscriptions, json!({"type": "unsubscribe", "subscription": 3}));
+        // Ids are never reused.
+        let id = MAX_SUBSCRIPTIONS as u64 + 1;
+        assert_eq!(reply(&mut subscriptions, json!({"type": "subscribe"})), json!({"type": "subscribed", "subscription": id}));
+    }
+
+    #[test]
+    fn transfers_are_notified_to_matching_subscriptions() {
+        let mut subscriptions = Subscriptions::default();
+        reply(&mut subscriptions, json!({"type": "subscribe", "addresses": [ALICE]}));
+        reply(&mut subscriptions, json!({"type": "subscribe", "tokens": [TOKEN], "min_amount": "10", "max_amount": "100"}));
+        reply(&mut subscriptions, json!({"type": "subscribe", "addresses": [BOB], "tokens": [ALICE]}));
+
+        let indexed = |sender, amount| TransferEvent::Indexed(transfer(1, sender, amount));
+        assert_eq!(notified(&mut subscriptions, indexed(ALICE, "5")), Some(("transfer".to_string(), json!([1]))));
+        assert_eq!(notified(&mut subscriptions, indexed(ALICE, "10")), Some(("transfer".to_string(), json!([1, 2]))));
+        assert_eq!(notified(&mut subscriptions, indexed(BOB, "100")), Some(("transfer".to_string(), json!([2]))));
+        assert_eq!(notified(&mut subscriptions, indexed(BOB, "101")), None);
+    }
+
+    #[test]
+    fn removals_are_only_notified_for_received_transfers() {
+        let mut subscriptions = Subscriptions::default();
+        reply(&mut subscriptions, json!({"type": "subscribe", "addresses": [ALICE]}));
+        reply(&mut subscriptions, json!({"type": "subscribe", "min_amount": "10"}));
+
+        // Never sent to the connection, e.g. indexed before it subscribed.
+        assert_eq!(notified(&mut subscriptions, TransferEvent::Removed(transfer(100, ALICE, "50"))), None);
+
+        notified(&mut subscriptions, TransferEvent::Indexed(transfer(100, ALICE, "50")));
+        notified(&mut subscriptions, TransferEvent::Indexed(transfer(101, BOB, "50")));
+        reply(&mut subscriptions, json!({"type": "unsubscribe", "subscription": 2}));
+        assert_eq!(notified(&mut subscriptions, TransferEvent::Removed(transfer(100, ALICE, "50"))), Some(("removed".to_string(), json!([1]))));
+        // Only received by the subscription that was removed.
+        assert_eq!(notified(&mut subscriptions, TransferEvent::Removed(transfer(101, BOB, "50"))), None);
+        // Notified once.
+        assert_eq!(notified(&mut subscriptions, TransferEvent::Removed(transfer(100, ALICE, "50"))), None);
+    }
+
+    #[test]
+    fn sent_transfers_are_forgotten_below_the_reorganization_window() {
+        let mut subscriptions = Subscriptions::default();
+        reply(&mut subscriptions, json!({"type": "subscribe"}));
+        let head = 1000;
+        for block_number in [head - REORG_WINDOW as i64 - 1, head - REORG_WINDOW as i64, head] {
+            notified(&mut subscriptions, TransferEvent::Indexed(transfer(block_number, ALICE, "1")));
+        }
+        let blocks: Vec<i64> = subscriptions.sent.keys().map(|(block_number, _, _)| *block_number).collect();
+        assert_eq!(blocks, [head - REORG_WINDOW as i64, head]);
+    }
+}
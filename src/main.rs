@@ -1,16 +1,21 @@
 use diesel::pg::PgConnection;
 use diesel::r2d2::ConnectionManager;
 use dotenv::dotenv;
+use std::collections::HashMap;
 use std::env;
-use crate::services::indexer::start_indexing;
-use crate::api::eth_scope;
+use std::time::Duration;
+use crate::services::indexer::{start_indexing, Finality, IndexerConfig};
+use crate::services::webhooks::{run_webhook_deliveries, WebhookConfig};
+use crate::api::{admin_scope, eth_scope};
+use crate::api::webhooks::AdminToken;
+use crate::repositories::balance_repo::BalanceRepo;
 use crate::utils::eth::connect_to_node;
 use actix_web::{App, HttpServer, web};
 use actix_cors::Cors;
 use actix_files::Files;
-use env_logger;
 use log::{error, info};
 use tokio::sync::broadcast;
+use web3::types::H160;
 
 mod schema;
 mod models;
@@ -21,6 +26,9 @@ mod api;
 
 type DbPool = diesel::r2d2::Pool<ConnectionManager<PgConnection>>;
 
+/// Number of newly indexed transfers buffered for the live streams; slower clients catch up from the database.
+const TRANSFER_CHANNEL_CAPACITY: usize = 4096;
+
 /// Creates a database connection pool for PostgreSQL.
 fn create_db_pool(database_url: &str) -> Result<DbPool, diesel::r2d2::Error> {
     let manager = ConnectionManager::<PgConnection>::new(database_url);
@@ -41,13 +49,126 @@ async fn main() -> std::io::Result<()> {
     // Retrieve required environment variables, fail if not set.
     let database_url = env::var("DATABASE_URL")
         .expect("DATABASE_URL must be set in .env file");
+
+    // Create database pool, exit on failure.
+    let pool = create_db_pool(&database_url).unwrap_or_else(|e| {
+        eprintln!("Database pool failed: {}", e);
+        std::process::exit(1);
+    });
+    info!("Database pool initialized successfully");
+
+    // `rebuild-balances` recomputes the balances from the indexed transfers, then exits.
+    if env::args().nth(1).as_deref() == Some("rebuild-balances") {
+        match BalanceRepo::new(pool.clone()).rebuild_balances().await {
+            Ok(count) => info!("Rebuilt {} balances from the indexed transfers", count),
+            Err(e) => {
+                error!("Failed to rebuild balances: {}", e);
+                std::process::exit(1);
+            }
+        }
+        return Ok(());
+    }
     
-    let ethereum_node_url = env::var("ETHEREUM_NODE_URL")
-        .expect("ETHEREUM_NODE_URL must be set in .env file");
+    let ethereum_node_urls: Vec<String> = env::var("ETHEREUM_NODE_URLS")
+        .or_else(|_| env::var("ETHEREUM_NODE_URL"))
+        .expect("ETHEREUM_NODE_URLS or ETHEREUM_NODE_URL must be set in .env file")
+        .split(',')
+        .map(|url| url.trim().to_string())
+        .filter(|url| !url.is_empty())
+        .collect();
+
+    let max_block_lag = env::var("NODE_MAX_BLOCK_LAG")
+        .ok()
+        .filter(|lag| !lag.is_empty())
+        .map(|lag| lag.parse::<u64>().expect("NODE_MAX_BLOCK_LAG must be a number of blocks"));
+    
+    let requests_per_second = env::var("RPC_REQUESTS_PER_SECOND")
+        .ok()
+        .filter(|rate| !rate.is_empty())
+        .map(|rate| rate.parse::<f64>()
+            .ok()
+            .filter(|rate| *rate > 0.0)
+            .expect("RPC_REQUESTS_PER_SECOND must be a positive number of requests"));
+
+    let max_batch_size = env::var("RPC_MAX_BATCH_SIZE")
+        .unwrap_or_else(|_| "50".to_string())
+        .parse::<usize>()
+        .ok()
+        .filter(|size| *size > 0)
+        .expect("RPC_MAX_BATCH_SIZE must be a positive number of calls");
+
+    let request_timeout = env::var("RPC_REQUEST_TIMEOUT_SECS")
+        .unwrap_or_else(|_| "30".to_string())
+        .parse::<u64>()
+        .ok()
+        .filter(|secs| *secs > 0)
+        .map(Duration::from_secs)
+        .expect("RPC_REQUEST_TIMEOUT_SECS must be a positive number of seconds");
+
+    let connect_timeout = env::var("RPC_CONNECT_TIMEOUT_SECS")
+        .unwrap_or_else(|_| "10".to_string())
+        .parse::<u64>()
+        .ok()
+        .filter(|secs| *secs > 0)
+        .map(Duration::from_secs)
+        .expect("RPC_CONNECT_TIMEOUT_SECS must be a positive number of seconds");
+
+    let ethereum_node_ws_url = env::var("ETHEREUM_NODE_WS_URL")
+        .ok()
+        .filter(|url| !url.is_empty());
+
+    let ethereum_token_addresses: Vec<String> = env::var("ETHEREUM_TOKEN_ADDRESSES")
+        .or_else(|_| env::var("ETHEREUM_TOKEN_ADDRESS"))
+        .expect("ETHEREUM_TOKEN_ADDRESSES or ETHEREUM_TOKEN_ADDRESS must be set in .env file")
+        .split(',')
+        .map(|address| address.trim().to_string())
+        .filter(|address| !address.is_empty())
+        .collect();
     
-    let ethereum_token_address = env::var("ETHEREUM_TOKEN_ADDRESS")
-        .expect("ETHEREUM_TOKEN_ADDRESS must be set in .env file");
+    let start_blocks: HashMap<H160, u64> = env::var("TOKEN_START_BLOCKS")
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| {
+            entry.split_once(':')
+                .and_then(|(address, block)| Some((address.trim().parse::<H160>().ok()?, block.trim().parse::<u64>().ok()?)))
+                .expect("TOKEN_START_BLOCKS must be a comma-separated list of <token address>:<block number>")
+        })
+        .collect();
     
+    let finality = env::var("CONFIRMATIONS")
+        .unwrap_or_else(|_| "0".to_string())
+        .parse::<Finality>()
+        .expect("CONFIRMATIONS must be a number of blocks, \"safe\" or \"finalized\"");
+
+    let index_unconfirmed = env::var("INDEX_UNCONFIRMED")
+        .map(|value| value == "true")
+        .unwrap_or(false);
+
+    let snapshot_interval = env::var("BALANCE_SNAPSHOT_INTERVAL")
+        .unwrap_or_else(|_| "10000".to_string())
+        .parse::<u64>()
+        .ok()
+        .filter(|interval| *interval > 0)
+        .expect("BALANCE_SNAPSHOT_INTERVAL must be a positive number of blocks");
+
+    let backfill_concurrency = env::var("BACKFILL_CONCURRENCY")
+        .unwrap_or_else(|_| "4".to_string())
+        .parse::<usize>()
+        .ok()
+        .filter(|concurrency| *concurrency > 0)
+        .expect("BACKFILL_CONCURRENCY must be a positive number of batches");
+
+    let webhook_max_attempts = env::var("WEBHOOK_MAX_ATTEMPTS")
+        .unwrap_or_else(|_| "8".to_string())
+        .parse::<i32>()
+        .ok()
+        .filter(|attempts| *attempts > 0)
+        .expect("WEBHOOK_MAX_ATTEMPTS must be a positive number of attempts");
+
+    let admin_token = AdminToken(env::var("ADMIN_API_TOKEN").ok().filter(|token| !token.is_empty()));
+
     let api_port = env::var("API_PORT")
         .unwrap_or_else(|_| "8080".to_string())
         .parse::<u16>()
@@ -55,55 +176,108 @@ async fn main() -> std::io::Result<()> {
 
     // Log the configuration details for monitoring.
     info!("Using database URL: {}", database_url);
-    info!("Using Ethereum node URL: {}", ethereum_node_url);
-    info!("Using token address: {}", ethereum_token_address);
+    info!("Using Ethereum node URLs: {}", ethereum_node_urls.join(", "));
+    match max_block_lag {
+        Some(lag) => info!("Avoiding nodes more than {} blocks behind the others", lag),
+        None => info!("Not cross-checking the block number of the nodes"),
+    }
+    match requests_per_second {
+        Some(rate) => info!("Limiting calls to {} requests per second per node", rate),
+        None => info!("Not limiting the rate of calls to the nodes"),
+    }
+    info!("Batching up to {} concurrent calls per request", max_batch_size);
+    info!("Failing over node requests not answered within {:?} or connected within {:?}", request_timeout, connect_timeout);
+    match &ethereum_node_ws_url {
+        Some(url) => info!("Using Ethereum node WebSocket URL: {}", url),
+        None => info!("No Ethereum node WebSocket URL set, polling for new blocks"),
+    }
+    info!("Using token addresses: {}", ethereum_token_addresses.join(", "));
+    for (address, block) in &start_blocks {
+        info!("Starting the backfill of token {:?} from block {}", address, block);
+    }
+    info!("Using finality: {}", finality);
+    info!("Indexing unconfirmed transfers: {}", index_unconfirmed);
+    info!("Using balance snapshot interval: {} blocks", snapshot_interval);
+    info!("Using backfill concurrency: {} batches", backfill_concurrency);
+    info!("Giving up webhook deliveries after {} attempts", webhook_max_attempts);
+    if admin_token.0.is_none() {
+        info!("No ADMIN_API_TOKEN set, admin API disabled");
+    }
     info!("Using API port: {}", api_port);
 
-    // Create database pool, exit on failure.
-    let pool = create_db_pool(&database_url).unwrap_or_else(|e| {
-        eprintln!("Database pool failed: {}", e);
+    // Create the node client shared by the indexer and the RPC metrics, exit on failure.
+    let web3 = connect_to_node(
+        &ethereum_node_urls,
+        max_block_lag,
+        requests_per_second,
+        max_batch_size,
+        request_timeout,
+        connect_timeout,
+    )
+    .await
+    .unwrap_or_else(|e| {
+        eprintln!("Failed to connect to Ethereum node: {}", e);
         std::process::exit(1);
     });
-    info!("Database pool initialized successfully");
-
-    // Initialize TransferRepo with a cloned pool.
-    let _transfer_repo = repositories::transfer_repo::TransferRepo::new(pool.clone());
-    info!("TransferRepo initialized");
-
-    // Attempt to connect to the Ethereum node and log the result.
-    match connect_to_node(&ethereum_node_url).await {
-        Ok(web3) => info!("Connected to Ethereum node: {:?}", web3.eth().chain_id().await),
-        Err(e) => eprintln!("Failed to connect to Ethereum node: {}", e),
-    }
+    info!("Connected to Ethereum node: {:?}", web3.eth().chain_id().await);
+    let node_transport = web3.transport().clone();
 
     // Create a channel for signaling shutdown to the indexer.
     let (shutdown_tx, shutdown_rx) = broadcast::channel(1);
 
+    // Create a channel on which the indexer publishes newly indexed transfers to the live streams.
+    let (transfer_tx, _) = broadcast::channel(TRANSFER_CHANNEL_CAPACITY);
+
     // Clone data for the indexer task to run independently.
     let indexer_shutdown_rx = shutdown_rx;
     let indexer_pool = pool.clone();
-    let indexer_node_url = ethereum_node_url.clone();
-    let indexer_token_address = ethereum_token_address.clone();
+    let indexer_transfer_tx = transfer_tx.clone();
+    let indexer_config = IndexerConfig {
+        node_ws_url: ethereum_node_ws_url,
+        token_addresses: ethereum_token_addresses.clone(),
+        start_blocks,
+        finality,
+        index_unconfirmed,
+        snapshot_interval,
+        backfill_concurrency,
+    };
 
     // Spawn the indexer as a background task.
     tokio::spawn(async move {
         if let Err(e) = start_indexing(
             indexer_pool,
-            indexer_node_url,
-            indexer_token_address,
+            web3,
+            indexer_config,
+            indexer_transfer_tx,
             indexer_shutdown_rx,
         ).await {
             error!("Indexer failed: {}", e);
         }
     });
 
+    // Spawn the webhook delivery as a background task.
+    let webhook_pool = pool.clone();
+    let webhook_shutdown_rx = shutdown_tx.subscribe();
+    let webhook_config = WebhookConfig {
+        max_attempts: webhook_max_attempts,
+    };
+    tokio::spawn(async move {
+        if let Err(e) = run_webhook_deliveries(webhook_pool, webhook_config, webhook_shutdown_rx).await {
+            error!("Webhook delivery failed: {}", e);
+        }
+    });
+
     // Start the API server with the configured port.
     info!("Starting API server on port {}", api_port);
     let server = HttpServer::new(move || {
         let app = App::new()
             .wrap(Cors::permissive())
             .app_data(web::Data::new(pool.clone()))
-            .service(eth_scope());
+            .app_data(web::Data::new(transfer_tx.clone()))
+            .app_data(web::Data::new(admin_token.clone()))
+            .app_data(web::Data::new(node_transport.clone()))
+            .service(eth_scope())
+            .service(admin_scope());
         app.service(Files::new("/", "frontend/dist").index_file("index.html"))
     })
     .bind(("127.0.0.1", api_port))?
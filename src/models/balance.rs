@@ -0,0 +1,60 @@
+use bigdecimal::BigDecimal;
+use chrono::NaiveDateTime;
+use diesel::prelude::*;
+use serde::Serialize;
+use crate::models::transfer::serialize_amount;
+use crate::schema::balances;
+
+/// Address used as the sender of mints and the recipient of burns, which holds no balance.
+pub const ZERO_ADDRESS: &str = "0x0000000000000000000000000000000000000000";
+
+/// Represents the current balance of an address for a token, retrieved from the database.
+#[derive(Queryable, Serialize, Debug)]
+#[diesel(table_name = balances)]
+pub struct Balance {
+    pub token_address: String,
+    pub address: String,
+    #[serde(serialize_with = "serialize_amount")]
+    pub balance: BigDecimal,
+    pub updated_at: NaiveDateTime,
+}
+
+/// Represents a change to the balance of an address for a token, to be added to the database.
+#[derive(Insertable, Debug)]
+#[diesel(table_name = balances)]
+pub struct BalanceChange {
+    pub token_address: String,
+    pub address: String,
+    pub balance: BigDecimal,
+}
+
+/// Represents the balance of an address for a token at a past block.
+#[derive(Serialize, Debug)]
+pub struct HistoricalBalance {
+    pub token_address: String,
+    pub address: String,
+    #[serde(serialize_with = "serialize_amount")]
+    pub balance: BigDecimal,
+    /// Block at the end of which the balance is given, absent when no transfer of the token happened before.
+    pub block_number: Option<i64>,
+    /// Whether every transfer the balance depends on is indexed, which is not the case while a failed
+    /// backfill range of the token starting at or before the block is unresolved.
+    pub complete: bool,
+}
+
+/// Represents an address holding a token with its balance, retrieved from the database.
+#[derive(Queryable, QueryableByName, Debug)]
+#[diesel(table_name = balances)]
+pub struct Holder {
+    pub address: String,
+    pub balance: BigDecimal,
+}
+
+/// Represents the number of addresses holding a token and the sum of their balances.
+#[derive(QueryableByName, Debug)]
+pub struct HolderStats {
+    #[diesel(sql_type = diesel::sql_types::BigInt)]
+    pub holder_count: i64,
+    #[diesel(sql_type = diesel::sql_types::Numeric)]
+    pub total_supply: BigDecimal,
+}
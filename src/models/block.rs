@@ -0,0 +1,36 @@
+use chrono::NaiveDateTime;
+use diesel::prelude::*;
+use serde::Serialize;
+use crate::schema::{indexed_blocks, reorgs};
+
+/// Represents the hash and parent hash of a block indexed by the live indexer.
+#[derive(Insertable, Debug)]
+#[diesel(table_name = indexed_blocks)]
+pub struct NewIndexedBlock {
+    pub chain_id: i64,
+    pub block_number: i64,
+    pub block_hash: String,
+    pub parent_hash: String,
+}
+
+/// Represents a chain reorganization handled by the indexer, retrieved from the database.
+#[derive(Queryable, Serialize, Debug)]
+#[diesel(table_name = reorgs)]
+pub struct Reorg {
+    pub id: i32,
+    pub chain_id: i64,
+    pub fork_block: i64,
+    pub depth: i64,
+    pub removed_transfers: i64,
+    pub detected_at: NaiveDateTime,
+}
+
+/// Represents a chain reorganization to be recorded in the database.
+#[derive(Insertable, Debug)]
+#[diesel(table_name = reorgs)]
+pub struct NewReorg {
+    pub chain_id: i64,
+    pub fork_block: i64,
+    pub depth: i64,
+    pub removed_transfers: i64,
+}
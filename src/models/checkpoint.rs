@@ -0,0 +1,11 @@
+use diesel::prelude::*;
+use crate::schema::indexer_checkpoints;
+
+/// Represents the last fully indexed block for a token on a given chain.
+#[derive(Insertable, Debug)]
+#[diesel(table_name = indexer_checkpoints)]
+pub struct NewIndexerCheckpoint {
+    pub chain_id: i64,
+    pub token_address: String,
+    pub last_block: i64,
+}
@@ -0,0 +1,34 @@
+use chrono::NaiveDateTime;
+use diesel::prelude::*;
+use serde::Serialize;
+use crate::schema::failed_ranges;
+
+/// Represents a block range of a token whose backfill failed, retrieved from the database. It is
+/// retried until its transfers are stored, which marks it as resolved.
+#[derive(Queryable, Serialize, Debug, Clone)]
+#[diesel(table_name = failed_ranges)]
+pub struct FailedRange {
+    pub id: i32,
+    pub chain_id: i64,
+    pub token_address: String,
+    pub from_block: i64,
+    pub to_block: i64,
+    /// Number of failed attempts to fetch the range.
+    pub attempts: i32,
+    pub last_error: String,
+    pub next_retry_at: NaiveDateTime,
+    pub created_at: NaiveDateTime,
+    pub resolved_at: Option<NaiveDateTime>,
+}
+
+/// Represents a block range of a token whose backfill failed, to be recorded in the database.
+#[derive(Insertable, Debug)]
+#[diesel(table_name = failed_ranges)]
+pub struct NewFailedRange {
+    pub chain_id: i64,
+    pub token_address: String,
+    pub from_block: i64,
+    pub to_block: i64,
+    pub attempts: i32,
+    pub last_error: String,
+}
@@ -1 +1,7 @@
-pub mod transfer;
\ No newline at end of file
+pub mod transfer;
+pub mod checkpoint;
+pub mod block;
+pub mod token;
+pub mod balance;
+pub mod webhook;
+pub mod failed_range;
\ No newline at end of file
@@ -0,0 +1,56 @@
+use bigdecimal::BigDecimal;
+use chrono::NaiveDateTime;
+use diesel::prelude::*;
+use serde::Serialize;
+use crate::models::transfer::serialize_amount;
+use crate::schema::tokens;
+
+/// Represents a registered token and its cached ERC-20 metadata, retrieved from the database.
+#[derive(Queryable, Serialize, Debug)]
+#[diesel(table_name = tokens)]
+pub struct Token {
+    pub address: String,
+    pub created_at: NaiveDateTime,
+    pub name: Option<String>,
+    pub symbol: Option<String>,
+    pub decimals: Option<i16>,
+    pub total_supply: Option<String>,
+    pub metadata_updated_at: Option<NaiveDateTime>,
+    /// Block at which `total_supply` was read from the contract.
+    pub total_supply_block: Option<i64>,
+    /// Block at which the contract was deployed, where its backfill starts.
+    pub deployment_block: Option<i64>,
+}
+
+/// Represents a token contract to be registered for indexing.
+#[derive(Insertable, Debug)]
+#[diesel(table_name = tokens)]
+pub struct NewToken {
+    pub address: String,
+}
+
+/// Represents ERC-20 metadata read from a token contract. Fields left as `None` keep their cached value.
+#[derive(AsChangeset, Debug)]
+#[diesel(table_name = tokens)]
+pub struct TokenMetadata {
+    pub name: Option<String>,
+    pub symbol: Option<String>,
+    pub decimals: Option<i16>,
+}
+
+/// Represents the tokens minted and burned during a period and the supply at its end.
+#[derive(QueryableByName, Serialize, Debug)]
+pub struct SupplyPoint {
+    /// Start of the period.
+    #[diesel(sql_type = diesel::sql_types::Timestamp)]
+    pub time: NaiveDateTime,
+    #[diesel(sql_type = diesel::sql_types::Numeric)]
+    #[serde(serialize_with = "serialize_amount")]
+    pub minted: BigDecimal,
+    #[diesel(sql_type = diesel::sql_types::Numeric)]
+    #[serde(serialize_with = "serialize_amount")]
+    pub burned: BigDecimal,
+    #[diesel(sql_type = diesel::sql_types::Numeric)]
+    #[serde(serialize_with = "serialize_amount")]
+    pub supply: BigDecimal,
+}
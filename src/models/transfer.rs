@@ -1,17 +1,52 @@
+use bigdecimal::BigDecimal;
+use chrono::NaiveDateTime;
 use diesel::prelude::*;
-use serde::Serialize;
+use serde::{Serialize, Serializer};
 use crate::schema::transfers;
 
+/// Status of a transfer from a block that reached the configured confirmation depth.
+pub const STATUS_CONFIRMED: &str = "confirmed";
+/// Status of a transfer from a block above the configured confirmation depth, which may still be reorganized.
+pub const STATUS_UNCONFIRMED: &str = "unconfirmed";
+
+/// Kind of a transfer from the zero address, which creates tokens.
+pub const KIND_MINT: &str = "mint";
+/// Kind of a transfer to the zero address, which destroys tokens.
+pub const KIND_BURN: &str = "burn";
+/// Kind of a transfer between two regular addresses.
+pub const KIND_TRANSFER: &str = "transfer";
+
 /// Represents a transfer event retrieved from the database.
-#[derive(Queryable, Serialize, Debug)]
+#[derive(Queryable, Serialize, Debug, Clone)]
 #[diesel(table_name = transfers)]
 pub struct Transfer {
     pub id: i32,
     pub sender: String,
     pub recipient: String,
-    pub amount: String,
+    #[serde(serialize_with = "serialize_amount")]
+    pub amount: BigDecimal,
     pub block_number: i64,
     pub tx_hash: String,
+    pub log_index: i32,
+    pub transaction_index: i32,
+    pub status: String,
+    pub token_address: String,
+    pub block_timestamp: NaiveDateTime,
+    pub kind: String,
+}
+
+/// Change to the confirmed transfers, published by the indexer to the live APIs.
+#[derive(Debug, Clone)]
+pub enum TransferEvent {
+    /// A transfer was confirmed and stored.
+    Indexed(Transfer),
+    /// A stored transfer was removed by a chain reorganization.
+    Removed(Transfer),
+}
+
+/// Serializes an amount as an exact decimal string, so clients never lose precision.
+pub fn serialize_amount<S: Serializer>(amount: &BigDecimal, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&amount.to_plain_string())
 }
 
 /// Represents a new transfer event to be inserted into the database.
@@ -20,7 +55,13 @@ pub struct Transfer {
 pub struct NewTransfer {
     pub sender: String,
     pub recipient: String,
-    pub amount: String,
+    pub amount: BigDecimal,
     pub block_number: i64,
     pub tx_hash: String,
+    pub log_index: i32,
+    pub transaction_index: i32,
+    pub status: String,
+    pub token_address: String,
+    pub block_timestamp: NaiveDateTime,
+    pub kind: String,
 }
\ No newline at end of file
@@ -0,0 +1,94 @@
+use bigdecimal::BigDecimal;
+use chrono::NaiveDateTime;
+use diesel::prelude::*;
+use serde::{Serialize, Serializer};
+use crate::models::transfer::{serialize_amount, Transfer};
+use crate::schema::{webhook_deliveries, webhooks};
+
+/// Status of a delivery waiting for its next attempt.
+pub const DELIVERY_PENDING: &str = "pending";
+/// Status of a delivery acknowledged by the webhook with a 2xx response.
+pub const DELIVERY_DELIVERED: &str = "delivered";
+/// Status of a delivery whose attempts all failed, kept for inspection and manual retry.
+pub const DELIVERY_DEAD: &str = "dead";
+/// Status of an undelivered delivery whose transfer was removed by a chain reorganization.
+pub const DELIVERY_CANCELLED: &str = "cancelled";
+
+/// Represents a registered webhook, retrieved from the database. Its secret is never serialized.
+#[derive(Queryable, Serialize, Debug, Clone)]
+#[diesel(table_name = webhooks)]
+pub struct Webhook {
+    pub id: i32,
+    pub url: String,
+    #[serde(skip_serializing)]
+    pub secret: String,
+    pub sender: Option<String>,
+    pub recipient: Option<String>,
+    #[serde(serialize_with = "serialize_optional_amount")]
+    pub min_amount: Option<BigDecimal>,
+    pub created_at: NaiveDateTime,
+}
+
+impl Webhook {
+    /// Whether a transfer matches every filter of the webhook.
+    pub fn matches(&self, transfer: &Transfer) -> bool {
+        self.sender.as_ref().is_none_or(|sender| *sender == transfer.sender)
+            && self.recipient.as_ref().is_none_or(|recipient| *recipient == transfer.recipient)
+            && self.min_amount.as_ref().is_none_or(|min_amount| transfer.amount >= *min_amount)
+    }
+}
+
+/// Serializes an optional amount as an exact decimal string.
+fn serialize_optional_amount<S: Serializer>(amount: &Option<BigDecimal>, serializer: S) -> Result<S::Ok, S::Error> {
+    match amount {
+        Some(amount) => serialize_amount(amount, serializer),
+        None => serializer.serialize_none(),
+    }
+}
+
+/// Represents a webhook to be registered.
+#[derive(Insertable, Debug)]
+#[diesel(table_name = webhooks)]
+pub struct NewWebhook {
+    pub url: String,
+    pub secret: String,
+    pub sender: Option<String>,
+    pub recipient: Option<String>,
+    pub min_amount: Option<BigDecimal>,
+}
+
+/// Represents the delivery of a transfer to a webhook and its attempts, retrieved from the database.
+#[derive(Queryable, Serialize, Debug, Clone)]
+#[diesel(table_name = webhook_deliveries)]
+pub struct WebhookDelivery {
+    pub id: i32,
+    pub webhook_id: i32,
+    pub transfer_id: i32,
+    /// JSON body posted to the webhook.
+    pub payload: String,
+    pub status: String,
+    pub attempts: i32,
+    pub next_attempt_at: NaiveDateTime,
+    /// HTTP status of the last response, if the webhook responded.
+    pub last_response_status: Option<i32>,
+    pub last_error: Option<String>,
+    pub created_at: NaiveDateTime,
+    pub delivered_at: Option<NaiveDateTime>,
+}
+
+/// Represents a delivery of a transfer to a webhook to be queued.
+#[derive(Insertable, Debug)]
+#[diesel(table_name = webhook_deliveries)]
+pub struct NewWebhookDelivery {
+    pub webhook_id: i32,
+    pub transfer_id: i32,
+    pub payload: String,
+}
+
+/// JSON body posted to a webhook for a transfer.
+#[derive(Serialize, Debug)]
+pub struct WebhookPayload<'a> {
+    /// Always `transfer`.
+    pub event: &'static str,
+    pub transfer: &'a Transfer,
+}
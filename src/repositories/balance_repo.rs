@@ -0,0 +1,427 @@
+use bigdecimal::{BigDecimal, Zero};
+use chrono::NaiveDateTime;
+use diesel::dsl::{count_star, max, min, sum};
+use diesel::prelude::*;
+use diesel::r2d2::ConnectionManager;
+use diesel::sql_types::{Int8, Nullable, Numeric, Text};
+use diesel::upsert::excluded;
+use std::collections::BTreeMap;
+use tokio::task;
+use crate::models::balance::{Balance, BalanceChange, HistoricalBalance, Holder, HolderStats, ZERO_ADDRESS};
+use crate::models::transfer::STATUS_CONFIRMED;
+use crate::schema::{balance_snapshots, balances, failed_ranges, snapshot_checkpoints, tokens, transfers};
+
+/// Recomputes every balance from the confirmed transfers, skipping the zero address.
+const REBUILD_BALANCES_SQL: &str = "
+    INSERT INTO balances (token_address, address, balance)
+    SELECT token_address, address, SUM(delta)
+    FROM (
+        SELECT token_address, recipient AS address, amount AS delta
+        FROM transfers
+        WHERE status = $1 AND recipient <> $2
+        UNION ALL
+        SELECT token_address, sender AS address, -amount AS delta
+        FROM transfers
+        WHERE status = $1 AND sender <> $2
+    ) AS deltas
+    GROUP BY token_address, address";
+
+/// Records the balances at the end of a block of the addresses of a token whose balance changed
+/// since the previous snapshot block, from their latest snapshot and the confirmed transfers since.
+const TAKE_SNAPSHOT_SQL: &str = "
+    INSERT INTO balance_snapshots (token_address, address, block_number, balance)
+    SELECT $1, deltas.address, $3, deltas.delta + COALESCE((
+        SELECT snapshot.balance
+        FROM balance_snapshots AS snapshot
+        WHERE snapshot.token_address = $1 AND snapshot.address = deltas.address AND snapshot.block_number <= $2
+        ORDER BY snapshot.block_number DESC
+        LIMIT 1
+    ), 0)
+    FROM (
+        SELECT address, SUM(delta) AS delta
+        FROM (
+            SELECT recipient AS address, amount AS delta
+            FROM transfers
+            WHERE token_address = $1 AND status = $4 AND block_number > $2 AND block_number <= $3 AND recipient <> $5
+            UNION ALL
+            SELECT sender AS address, -amount AS delta
+            FROM transfers
+            WHERE token_address = $1 AND status = $4 AND block_number > $2 AND block_number <= $3 AND sender <> $5
+        ) AS changes
+        GROUP BY address
+    ) AS deltas";
+
+/// Computes the balances of every address of a token at the end of a block, from the balance
+/// snapshots at or before the snapshot block `$3` and the confirmed transfers after it.
+const HOLDERS_AT_BLOCK_SQL: &str = "
+    WITH snapshots AS (
+        SELECT DISTINCT ON (address) address, balance
+        FROM balance_snapshots
+        WHERE token_address = $1 AND block_number <= $3
+        ORDER BY address, block_number DESC
+    ), changes AS (
+        SELECT recipient AS address, amount AS balance
+        FROM transfers
+        WHERE token_address = $1 AND status = $4 AND block_number > $3 AND block_number <= $2 AND recipient <> $5
+        UNION ALL
+        SELECT sender AS address, -amount AS balance
+        FROM transfers
+        WHERE token_address = $1 AND status = $4 AND block_number > $3 AND block_number <= $2 AND sender <> $5
+    ), holders AS (
+        SELECT address, SUM(balance) AS balance
+        FROM (SELECT address, balance FROM snapshots UNION ALL SELECT address, balance FROM changes) AS balances
+        GROUP BY address
+    )";
+
+/// Point in time at which historical balances are requested.
+#[derive(Debug, Clone, Copy)]
+pub enum BalanceAt {
+    /// At the end of a block.
+    Block(i64),
+    /// After the last transfer of each token at or before a UTC time.
+    Time(NaiveDateTime),
+}
+
+/// Accumulates the balance changes caused by a set of transfers, per token and address.
+#[derive(Debug, Default)]
+pub struct BalanceDeltas {
+    // Ordered so that concurrent transactions lock balance rows in the same order.
+    deltas: BTreeMap<(String, String), BigDecimal>,
+}
+
+impl BalanceDeltas {
+    /// Creates an empty set of balance changes.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records a transfer: debits the sender and credits the recipient, or the opposite when the
+    /// transfer is being reverted. The zero address is skipped.
+    pub fn add_transfer(&mut self, token_address: &str, sender: &str, recipient: &str, amount: &BigDecimal, revert: bool) {
+        let (debited, credited) = if revert { (recipient, sender) } else { (sender, recipient) };
+        if debited != ZERO_ADDRESS {
+            *self.deltas.entry((token_address.to_string(), debited.to_string())).or_default() -= amount;
+        }
+        if credited != ZERO_ADDRESS {
+            *self.deltas.entry((token_address.to_string(), credited.to_string())).or_default() += amount;
+        }
+    }
+
+    /// Adds the accumulated changes to the stored balances, within the caller's transaction.
+    pub fn apply(self, conn: &mut PgConnection) -> QueryResult<usize> {
+        let changes: Vec<BalanceChange> = self.deltas.into_iter()
+            .filter(|(_, delta)| !delta.is_zero())
+            .map(|((token_address, address), balance)| BalanceChange { token_address, address, balance })
+            .collect();
+        if changes.is_empty() {
+            return Ok(0);
+        }
+        diesel::insert_into(balances::table)
+            .values(&changes)
+            .on_conflict((balances::token_address, balances::address))
+            .do_update()
+            .set((
+                balances::balance.eq(balances::balance + excluded(balances::balance)),
+                balances::updated_at.eq(diesel::dsl::now),
+            ))
+            .execute(conn)
+    }
+}
+
+/// Manages database operations for the current token balances of addresses.
+#[derive(Debug)]
+pub struct BalanceRepo {
+    pub pool: diesel::r2d2::Pool<ConnectionManager<PgConnection>>,
+}
+
+impl BalanceRepo {
+    /// Creates a new BalanceRepo with the given database pool.
+    pub fn new(pool: diesel::r2d2::Pool<ConnectionManager<PgConnection>>) -> Self {
+        BalanceRepo { pool }
+    }
+
+    /// Retrieves the balances of an address, optionally for a single token.
+    pub async fn get_balances(
+        &self,
+        address: String,
+        token_address: Option<String>,
+    ) -> Result<Vec<Balance>, Box<dyn std::error::Error + Send + Sync>> {
+        let mut conn = self.pool.get()?;
+        task::spawn_blocking(move || {
+            let mut query = balances::table
+                .filter(balances::address.eq(address.to_lowercase()))
+                .order(balances::token_address.asc())
+                .into_boxed();
+            if let Some(t) = token_address {
+                query = query.filter(balances::token_address.eq(t.to_lowercase()));
+            }
+            query
+                .load::<Balance>(&mut conn)
+                .map_err(|e| Box::new(e) as Box<dyn std::error::Error + Send + Sync>)
+        })
+        .await?
+    }
+
+    /// Retrieves the balances of an address at a past block or time, optionally for a single token.
+    /// Each balance is computed from the address's latest snapshot at or before the block, plus the
+    /// confirmed transfers since, and flagged as incomplete if an unresolved failed range may hold
+    /// some of these transfers.
+    pub async fn get_balances_at(
+        &self,
+        address: String,
+        token_address: Option<String>,
+        at: BalanceAt,
+    ) -> Result<Vec<HistoricalBalance>, Box<dyn std::error::Error + Send + Sync>> {
+        let mut conn = self.pool.get()?;
+        task::spawn_blocking(move || -> Result<Vec<HistoricalBalance>, Box<dyn std::error::Error + Send + Sync>> {
+            let address = address.to_lowercase();
+            let token_addresses = match token_address {
+                Some(t) => vec![t.to_lowercase()],
+                None => tokens::table
+                    .select(tokens::address)
+                    .order((tokens::created_at.asc(), tokens::address.asc()))
+                    .load::<String>(&mut conn)?,
+            };
+            let mut historical_balances = Vec::with_capacity(token_addresses.len());
+            for token_address in token_addresses {
+                let block_number = match at {
+                    BalanceAt::Block(block) => Some(block),
+                    BalanceAt::Time(time) => transfers::table
+                        .filter(transfers::token_address.eq(&token_address))
+                        .filter(transfers::status.eq(STATUS_CONFIRMED))
+                        .filter(transfers::block_timestamp.le(time))
+                        .select(max(transfers::block_number))
+                        .first::<Option<i64>>(&mut conn)?,
+                };
+                let balance = match block_number {
+                    Some(block) if address != ZERO_ADDRESS => {
+                        let (from_block, snapshot_balance) = balance_snapshots::table
+                            .filter(balance_snapshots::token_address.eq(&token_address))
+                            .filter(balance_snapshots::address.eq(&address))
+                            .filter(balance_snapshots::block_number.le(block))
+                            .order(balance_snapshots::block_number.desc())
+                            .select((balance_snapshots::block_number, balance_snapshots::balance))
+                            .first::<(i64, BigDecimal)>(&mut conn)
+                            .optional()?
+                            .unwrap_or((-1, BigDecimal::zero()));
+                        let received = transfers::table
+                            .filter(transfers::recipient.eq(&address))
+                            .filter(transfers::token_address.eq(&token_address))
+                            .filter(transfers::status.eq(STATUS_CONFIRMED))
+                            .filter(transfers::block_number.gt(from_block))
+                            .filter(transfers::block_number.le(block))
+                            .select(sum(transfers::amount))
+                            .first::<Option<BigDecimal>>(&mut conn)?
+                            .unwrap_or_default();
+                        let sent = transfers::table
+                            .filter(transfers::sender.eq(&address))
+                            .filter(transfers::token_address.eq(&token_address))
+                            .filter(transfers::status.eq(STATUS_CONFIRMED))
+                            .filter(transfers::block_number.gt(from_block))
+                            .filter(transfers::block_number.le(block))
+                            .select(sum(transfers::amount))
+                            .first::<Option<BigDecimal>>(&mut conn)?
+                            .unwrap_or_default();
+                        snapshot_balance + received - sent
+                    }
+                    _ => BigDecimal::zero(),
+                };
+                // Last block whose transfers count: blocks are ordered by time, so a range starting after
+                // the first transfer past the time holds none of them.
+                let up_to_block = match at {
+                    BalanceAt::Block(block) => Some(block),
+                    BalanceAt::Time(time) => transfers::table
+                        .filter(transfers::token_address.eq(&token_address))
+                        .filter(transfers::status.eq(STATUS_CONFIRMED))
+                        .filter(transfers::block_timestamp.gt(time))
+                        .select(min(transfers::block_number))
+                        .first::<Option<i64>>(&mut conn)?
+                        .map(|block| block - 1),
+                };
+                let missing_range = failed_ranges::table
+                    .filter(failed_ranges::token_address.eq(&token_address))
+                    .filter(failed_ranges::resolved_at.is_null())
+                    .filter(failed_ranges::from_block.le(up_to_block.unwrap_or(i64::MAX)))
+                    .select(failed_ranges::id)
+                    .first::<i32>(&mut conn)
+                    .optional()?;
+                historical_balances.push(HistoricalBalance {
+                    token_address,
+                    address: address.clone(),
+                    balance,
+                    block_number,
+                    complete: missing_range.is_none(),
+                });
+            }
+            Ok(historical_balances)
+        })
+        .await?
+    }
+
+    /// Retrieves a page of the addresses holding a token, ranked by balance then address, starting
+    /// strictly after the `(balance, address)` position given by `after`, along with the number of
+    /// holders and the sum of their balances. Balances are the current ones, or those at the end of
+    /// the given block.
+    pub async fn get_holders(
+        &self,
+        token_address: String,
+        block: Option<i64>,
+        after: Option<(BigDecimal, String)>,
+        limit: i64,
+    ) -> Result<(Vec<Holder>, HolderStats), Box<dyn std::error::Error + Send + Sync>> {
+        let mut conn = self.pool.get()?;
+        task::spawn_blocking(move || -> Result<(Vec<Holder>, HolderStats), Box<dyn std::error::Error + Send + Sync>> {
+            let token_address = token_address.to_lowercase();
+            let Some(block) = block else {
+                let mut query = balances::table
+                    .filter(balances::token_address.eq(&token_address))
+                    .filter(balances::balance.gt(BigDecimal::zero()))
+                    .order((balances::balance.desc(), balances::address.asc()))
+                    .select((balances::address, balances::balance))
+                    .limit(limit)
+                    .into_boxed();
+                if let Some((balance, address)) = after {
+                    query = query.filter(
+                        balances::balance.lt(balance.clone())
+                            .or(balances::balance.eq(balance).and(balances::address.gt(address))),
+                    );
+                }
+                let holders = query.load::<Holder>(&mut conn)?;
+                let (holder_count, total_supply) = balances::table
+                    .filter(balances::token_address.eq(&token_address))
+                    .filter(balances::balance.gt(BigDecimal::zero()))
+                    .select((count_star(), sum(balances::balance)))
+                    .first::<(i64, Option<BigDecimal>)>(&mut conn)?;
+                let stats = HolderStats { holder_count, total_supply: total_supply.unwrap_or_default() };
+                return Ok((holders, stats));
+            };
+            let snapshot_block = balance_snapshots::table
+                .filter(balance_snapshots::token_address.eq(&token_address))
+                .filter(balance_snapshots::block_number.le(block))
+                .select(max(balance_snapshots::block_number))
+                .first::<Option<i64>>(&mut conn)?
+                .unwrap_or(-1);
+            let (after_balance, after_address) = after.unzip();
+            let holders = diesel::sql_query(format!(
+                "{} SELECT address, balance FROM holders
+                WHERE balance > 0 AND ($6 IS NULL OR balance < $6 OR (balance = $6 AND address > $7))
+                ORDER BY balance DESC, address ASC
+                LIMIT $8",
+                HOLDERS_AT_BLOCK_SQL,
+            ))
+                .bind::<Text, _>(&token_address)
+                .bind::<Int8, _>(block)
+                .bind::<Int8, _>(snapshot_block)
+                .bind::<Text, _>(STATUS_CONFIRMED)
+                .bind::<Text, _>(ZERO_ADDRESS)
+                .bind::<Nullable<Numeric>, _>(after_balance)
+                .bind::<Nullable<Text>, _>(after_address)
+                .bind::<Int8, _>(limit)
+                .load::<Holder>(&mut conn)?;
+            let stats = diesel::sql_query(format!(
+                "{} SELECT COUNT(*) AS holder_count, COALESCE(SUM(balance), 0) AS total_supply FROM holders WHERE balance > 0",
+                HOLDERS_AT_BLOCK_SQL,
+            ))
+                .bind::<Text, _>(&token_address)
+                .bind::<Int8, _>(block)
+                .bind::<Int8, _>(snapshot_block)
+                .bind::<Text, _>(STATUS_CONFIRMED)
+                .bind::<Text, _>(ZERO_ADDRESS)
+                .get_result::<HolderStats>(&mut conn)?;
+            Ok((holders, stats))
+        })
+        .await?
+    }
+
+    /// Records balance snapshots of a token every `interval` blocks up to the given block, skipping
+    /// intervals without transfers. Each snapshot is committed in its own transaction.
+    /// Returns the number of recorded balances.
+    pub async fn take_snapshots(
+        &self,
+        token_address: String,
+        up_to_block: i64,
+        interval: i64,
+    ) -> Result<usize, Box<dyn std::error::Error + Send + Sync>> {
+        let mut conn = self.pool.get()?;
+        task::spawn_blocking(move || -> Result<usize, Box<dyn std::error::Error + Send + Sync>> {
+            let mut recorded = 0;
+            loop {
+                let snapshot = conn.transaction::<Option<usize>, diesel::result::Error, _>(|conn| {
+                    let last_block = snapshot_checkpoints::table
+                        .find(&token_address)
+                        .select(snapshot_checkpoints::last_block)
+                        .first::<i64>(conn)
+                        .optional()?
+                        .unwrap_or(-1);
+                    let next_transfer_block = transfers::table
+                        .filter(transfers::token_address.eq(&token_address))
+                        .filter(transfers::status.eq(STATUS_CONFIRMED))
+                        .filter(transfers::block_number.gt(last_block))
+                        .filter(transfers::block_number.le(up_to_block))
+                        .select(min(transfers::block_number))
+                        .first::<Option<i64>>(conn)?;
+                    let (snapshot_block, inserted) = match next_transfer_block {
+                        // Snapshot at the end of the interval containing the next transfer, once it is fully indexed.
+                        Some(block) => {
+                            let snapshot_block = (block + interval - 1) / interval * interval;
+                            if snapshot_block > up_to_block {
+                                return Ok(None);
+                            }
+                            let inserted = diesel::sql_query(TAKE_SNAPSHOT_SQL)
+                                .bind::<Text, _>(&token_address)
+                                .bind::<Int8, _>(last_block)
+                                .bind::<Int8, _>(snapshot_block)
+                                .bind::<Text, _>(STATUS_CONFIRMED)
+                                .bind::<Text, _>(ZERO_ADDRESS)
+                                .execute(conn)?;
+                            (snapshot_block, Some(inserted))
+                        }
+                        // No transfer left before the block: nothing changed up to it.
+                        None if last_block < up_to_block => (up_to_block, None),
+                        None => return Ok(None),
+                    };
+                    diesel::insert_into(snapshot_checkpoints::table)
+                        .values((
+                            snapshot_checkpoints::token_address.eq(&token_address),
+                            snapshot_checkpoints::last_block.eq(snapshot_block),
+                        ))
+                        .on_conflict(snapshot_checkpoints::token_address)
+                        .do_update()
+                        .set((
+                            snapshot_checkpoints::last_block.eq(excluded(snapshot_checkpoints::last_block)),
+                            snapshot_checkpoints::updated_at.eq(diesel::dsl::now),
+                        ))
+                        .execute(conn)?;
+                    Ok(Some(inserted.unwrap_or(0)))
+                })?;
+                match snapshot {
+                    Some(inserted) => recorded += inserted,
+                    None => return Ok(recorded),
+                }
+            }
+        })
+        .await?
+    }
+
+    /// Recomputes every balance from the indexed transfers in a single transaction, and clears the
+    /// balance snapshots so that the indexer records them again.
+    /// Returns the number of stored balances.
+    pub async fn rebuild_balances(&self) -> Result<usize, Box<dyn std::error::Error + Send + Sync>> {
+        let mut conn = self.pool.get()?;
+        task::spawn_blocking(move || {
+            conn.transaction::<usize, diesel::result::Error, _>(|conn| {
+                // Block concurrent balance updates from the indexer until the rebuild is committed.
+                diesel::sql_query("LOCK TABLE balances IN EXCLUSIVE MODE").execute(conn)?;
+                diesel::delete(balances::table).execute(conn)?;
+                diesel::delete(balance_snapshots::table).execute(conn)?;
+                diesel::delete(snapshot_checkpoints::table).execute(conn)?;
+                diesel::sql_query(REBUILD_BALANCES_SQL)
+                    .bind::<Text, _>(STATUS_CONFIRMED)
+                    .bind::<Text, _>(ZERO_ADDRESS)
+                    .execute(conn)
+            })
+            .map_err(|e| Box::new(e) as Box<dyn std::error::Error + Send + Sync>)
+        })
+        .await?
+    }
+}
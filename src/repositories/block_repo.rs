@@ -0,0 +1,158 @@
+use bigdecimal::BigDecimal;
+use diesel::prelude::*;
+use diesel::r2d2::ConnectionManager;
+use tokio::task;
+use crate::models::block::{NewReorg, Reorg};
+use crate::models::transfer::{Transfer, STATUS_CONFIRMED};
+use crate::repositories::balance_repo::BalanceDeltas;
+use crate::repositories::webhook_repo::cancel_deliveries;
+use crate::schema::{balance_snapshots, indexed_blocks, indexer_checkpoints, reorgs, snapshot_checkpoints, transfers};
+
+/// Manages database operations for indexed block hashes and chain reorganizations.
+#[derive(Debug)]
+pub struct BlockRepo {
+    pub pool: diesel::r2d2::Pool<ConnectionManager<PgConnection>>,
+}
+
+impl BlockRepo {
+    /// Creates a new BlockRepo with the given database pool.
+    pub fn new(pool: diesel::r2d2::Pool<ConnectionManager<PgConnection>>) -> Self {
+        BlockRepo { pool }
+    }
+
+    /// Retrieves the stored hash of an indexed block, if the block is still tracked.
+    pub async fn get_block_hash(
+        &self,
+        chain_id: i64,
+        block_number: i64,
+    ) -> Result<Option<String>, Box<dyn std::error::Error + Send + Sync>> {
+        let mut conn = self.pool.get()?;
+        task::spawn_blocking(move || {
+            indexed_blocks::table
+                .find((chain_id, block_number))
+                .select(indexed_blocks::block_hash)
+                .first::<String>(&mut conn)
+                .optional()
+                .map_err(|e| Box::new(e) as Box<dyn std::error::Error + Send + Sync>)
+        })
+        .await?
+    }
+
+    /// Retrieves the stored hashes of the tracked blocks up to the given one, highest block first.
+    pub async fn get_block_hashes(
+        &self,
+        chain_id: i64,
+        up_to_block: i64,
+    ) -> Result<Vec<(i64, String)>, Box<dyn std::error::Error + Send + Sync>> {
+        let mut conn = self.pool.get()?;
+        task::spawn_blocking(move || {
+            indexed_blocks::table
+                .filter(indexed_blocks::chain_id.eq(chain_id))
+                .filter(indexed_blocks::block_number.le(up_to_block))
+                .order(indexed_blocks::block_number.desc())
+                .select((indexed_blocks::block_number, indexed_blocks::block_hash))
+                .load::<(i64, String)>(&mut conn)
+                .map_err(|e| Box::new(e) as Box<dyn std::error::Error + Send + Sync>)
+        })
+        .await?
+    }
+
+    /// Deletes tracked blocks below the given block number, which are considered final.
+    pub async fn prune_blocks(
+        &self,
+        chain_id: i64,
+        below_block: i64,
+    ) -> Result<usize, Box<dyn std::error::Error + Send + Sync>> {
+        let mut conn = self.pool.get()?;
+        task::spawn_blocking(move || {
+            diesel::delete(
+                indexed_blocks::table
+                    .filter(indexed_blocks::chain_id.eq(chain_id))
+                    .filter(indexed_blocks::block_number.lt(below_block)),
+            )
+            .execute(&mut conn)
+            .map_err(|e| Box::new(e) as Box<dyn std::error::Error + Send + Sync>)
+        })
+        .await?
+    }
+
+    /// Rolls the index back to the given fork block in a single transaction: deletes transfers and
+    /// tracked blocks above it, reverts their balance changes, deletes the balance snapshots above it,
+    /// cancels the undelivered webhook deliveries of the transfers, rewinds checkpoints and records the
+    /// reorganization.
+    /// Returns the removed transfers.
+    pub async fn rollback_to_block(
+        &self,
+        chain_id: i64,
+        fork_block: i64,
+        depth: i64,
+    ) -> Result<Vec<Transfer>, Box<dyn std::error::Error + Send + Sync>> {
+        let mut conn = self.pool.get()?;
+        task::spawn_blocking(move || {
+            conn.transaction::<Vec<Transfer>, diesel::result::Error, _>(|conn| {
+                let orphaned = transfers::table
+                    .filter(transfers::block_number.gt(fork_block))
+                    .filter(transfers::status.eq(STATUS_CONFIRMED))
+                    .select((transfers::token_address, transfers::sender, transfers::recipient, transfers::amount))
+                    .load::<(String, String, String, BigDecimal)>(conn)?;
+                let mut deltas = BalanceDeltas::new();
+                for (token_address, sender, recipient, amount) in &orphaned {
+                    deltas.add_transfer(token_address, sender, recipient, amount, true);
+                }
+                deltas.apply(conn)?;
+                diesel::delete(balance_snapshots::table.filter(balance_snapshots::block_number.gt(fork_block)))
+                    .execute(conn)?;
+                diesel::update(snapshot_checkpoints::table.filter(snapshot_checkpoints::last_block.gt(fork_block)))
+                    .set((
+                        snapshot_checkpoints::last_block.eq(fork_block),
+                        snapshot_checkpoints::updated_at.eq(diesel::dsl::now),
+                    ))
+                    .execute(conn)?;
+                let removed: Vec<Transfer> = diesel::delete(transfers::table.filter(transfers::block_number.gt(fork_block)))
+                    .get_results(conn)?;
+                let removed_ids: Vec<i32> = removed.iter().map(|transfer| transfer.id).collect();
+                cancel_deliveries(conn, &removed_ids)?;
+                diesel::delete(
+                    indexed_blocks::table
+                        .filter(indexed_blocks::chain_id.eq(chain_id))
+                        .filter(indexed_blocks::block_number.gt(fork_block)),
+                )
+                .execute(conn)?;
+                diesel::update(
+                    indexer_checkpoints::table
+                        .filter(indexer_checkpoints::chain_id.eq(chain_id))
+                        .filter(indexer_checkpoints::last_block.gt(fork_block)),
+                )
+                .set((
+                    indexer_checkpoints::last_block.eq(fork_block),
+                    indexer_checkpoints::updated_at.eq(diesel::dsl::now),
+                ))
+                .execute(conn)?;
+                diesel::insert_into(reorgs::table)
+                    .values(&NewReorg {
+                        chain_id,
+                        fork_block,
+                        depth,
+                        removed_transfers: removed.len() as i64,
+                    })
+                    .execute(conn)?;
+                Ok(removed)
+            })
+            .map_err(|e| Box::new(e) as Box<dyn std::error::Error + Send + Sync>)
+        })
+        .await?
+    }
+
+    /// Retrieves the most recent chain reorganizations, newest first.
+    pub async fn get_reorgs(&self, limit: i64) -> Result<Vec<Reorg>, Box<dyn std::error::Error + Send + Sync>> {
+        let mut conn = self.pool.get()?;
+        task::spawn_blocking(move || {
+            reorgs::table
+                .order(reorgs::id.desc())
+                .limit(limit)
+                .load::<Reorg>(&mut conn)
+                .map_err(|e| Box::new(e) as Box<dyn std::error::Error + Send + Sync>)
+        })
+        .await?
+    }
+}
@@ -0,0 +1,50 @@
+use diesel::prelude::*;
+use diesel::r2d2::ConnectionManager;
+use diesel::upsert::excluded;
+use tokio::task;
+use crate::models::checkpoint::NewIndexerCheckpoint;
+use crate::schema::indexer_checkpoints;
+
+/// Moves checkpoints to their new last block, within the caller's transaction.
+pub fn upsert_checkpoints(conn: &mut PgConnection, checkpoints: &[NewIndexerCheckpoint]) -> QueryResult<usize> {
+    diesel::insert_into(indexer_checkpoints::table)
+        .values(checkpoints)
+        .on_conflict((indexer_checkpoints::chain_id, indexer_checkpoints::token_address))
+        .do_update()
+        .set((
+            indexer_checkpoints::last_block.eq(excluded(indexer_checkpoints::last_block)),
+            indexer_checkpoints::updated_at.eq(diesel::dsl::now),
+        ))
+        .execute(conn)
+}
+
+/// Manages database operations for the indexer's persisted progress.
+#[derive(Debug)]
+pub struct CheckpointRepo {
+    pub pool: diesel::r2d2::Pool<ConnectionManager<PgConnection>>,
+}
+
+impl CheckpointRepo {
+    /// Creates a new CheckpointRepo with the given database pool.
+    pub fn new(pool: diesel::r2d2::Pool<ConnectionManager<PgConnection>>) -> Self {
+        CheckpointRepo { pool }
+    }
+
+    /// Retrieves the last fully indexed block for a token on a chain, if indexing has started before.
+    pub async fn get_last_block(
+        &self,
+        chain_id: i64,
+        token_address: String,
+    ) -> Result<Option<i64>, Box<dyn std::error::Error + Send + Sync>> {
+        let mut conn = self.pool.get()?;
+        task::spawn_blocking(move || {
+            indexer_checkpoints::table
+                .find((chain_id, token_address))
+                .select(indexer_checkpoints::last_block)
+                .first::<i64>(&mut conn)
+                .optional()
+                .map_err(|e| Box::new(e) as Box<dyn std::error::Error + Send + Sync>)
+        })
+        .await?
+    }
+}
@@ -0,0 +1,138 @@
+use diesel::dsl::IntervalDsl;
+use diesel::prelude::*;
+use diesel::r2d2::ConnectionManager;
+use tokio::task;
+use crate::models::checkpoint::NewIndexerCheckpoint;
+use crate::models::failed_range::{FailedRange, NewFailedRange};
+use crate::repositories::checkpoint_repo::upsert_checkpoints;
+use crate::schema::{balance_snapshots, failed_ranges, snapshot_checkpoints};
+
+/// Manages database operations for the block ranges whose backfill failed.
+#[derive(Debug)]
+pub struct FailedRangeRepo {
+    pub pool: diesel::r2d2::Pool<ConnectionManager<PgConnection>>,
+}
+
+impl FailedRangeRepo {
+    /// Creates a new FailedRangeRepo with the given database pool.
+    pub fn new(pool: diesel::r2d2::Pool<ConnectionManager<PgConnection>>) -> Self {
+        FailedRangeRepo { pool }
+    }
+
+    /// Records the ranges of a batch that could not be backfilled and moves the checkpoints past
+    /// the batch in a single transaction, so the backfill goes on without losing track of the gap.
+    pub async fn record_failed_ranges(
+        &self,
+        ranges: Vec<NewFailedRange>,
+        checkpoints: Vec<NewIndexerCheckpoint>,
+    ) -> Result<usize, Box<dyn std::error::Error + Send + Sync>> {
+        let mut conn = self.pool.get()?;
+        task::spawn_blocking(move || {
+            conn.transaction::<usize, diesel::result::Error, _>(|conn| {
+                let recorded = diesel::insert_into(failed_ranges::table)
+                    .values(&ranges)
+                    .execute(conn)?;
+                upsert_checkpoints(conn, &checkpoints)?;
+                Ok(recorded)
+            })
+            .map_err(|e| Box::new(e) as Box<dyn std::error::Error + Send + Sync>)
+        })
+        .await?
+    }
+
+    /// Retrieves the unresolved failed ranges of a chain that are due for a retry, oldest first.
+    pub async fn get_due_ranges(
+        &self,
+        chain_id: i64,
+        limit: i64,
+    ) -> Result<Vec<FailedRange>, Box<dyn std::error::Error + Send + Sync>> {
+        let mut conn = self.pool.get()?;
+        task::spawn_blocking(move || {
+            failed_ranges::table
+                .filter(failed_ranges::chain_id.eq(chain_id))
+                .filter(failed_ranges::resolved_at.is_null())
+                .filter(failed_ranges::next_retry_at.le(diesel::dsl::now))
+                .order(failed_ranges::id.asc())
+                .limit(limit)
+                .load::<FailedRange>(&mut conn)
+                .map_err(|e| Box::new(e) as Box<dyn std::error::Error + Send + Sync>)
+        })
+        .await?
+    }
+
+    /// Retrieves the most recent failed ranges, newest first, optionally only the resolved or
+    /// unresolved ones.
+    pub async fn get_failed_ranges(
+        &self,
+        resolved: Option<bool>,
+        limit: i64,
+    ) -> Result<Vec<FailedRange>, Box<dyn std::error::Error + Send + Sync>> {
+        let mut conn = self.pool.get()?;
+        task::spawn_blocking(move || {
+            let mut query = failed_ranges::table.into_boxed();
+            match resolved {
+                Some(true) => query = query.filter(failed_ranges::resolved_at.is_not_null()),
+                Some(false) => query = query.filter(failed_ranges::resolved_at.is_null()),
+                None => {}
+            }
+            query
+                .order(failed_ranges::id.desc())
+                .limit(limit)
+                .load::<FailedRange>(&mut conn)
+                .map_err(|e| Box::new(e) as Box<dyn std::error::Error + Send + Sync>)
+        })
+        .await?
+    }
+
+    /// Records another failed attempt of a range, scheduling the next one after the given delay.
+    pub async fn record_retry_failure(
+        &self,
+        id: i32,
+        error: String,
+        retry_in_secs: i64,
+    ) -> Result<usize, Box<dyn std::error::Error + Send + Sync>> {
+        let mut conn = self.pool.get()?;
+        task::spawn_blocking(move || {
+            diesel::update(failed_ranges::table.find(id))
+                .set((
+                    failed_ranges::attempts.eq(failed_ranges::attempts + 1),
+                    failed_ranges::last_error.eq(error),
+                    failed_ranges::next_retry_at.eq(diesel::dsl::now + retry_in_secs.seconds()),
+                ))
+                .execute(&mut conn)
+                .map_err(|e| Box::new(e) as Box<dyn std::error::Error + Send + Sync>)
+        })
+        .await?
+    }
+
+    /// Marks a range as resolved once its transfers are stored. The token's balance snapshots from
+    /// the start of the range on missed its transfers, so they are deleted to be recorded again.
+    pub async fn mark_resolved(&self, range: FailedRange) -> Result<usize, Box<dyn std::error::Error + Send + Sync>> {
+        let mut conn = self.pool.get()?;
+        task::spawn_blocking(move || {
+            conn.transaction::<usize, diesel::result::Error, _>(|conn| {
+                diesel::delete(
+                    balance_snapshots::table
+                        .filter(balance_snapshots::token_address.eq(&range.token_address))
+                        .filter(balance_snapshots::block_number.ge(range.from_block)),
+                )
+                .execute(conn)?;
+                diesel::update(
+                    snapshot_checkpoints::table
+                        .filter(snapshot_checkpoints::token_address.eq(&range.token_address))
+                        .filter(snapshot_checkpoints::last_block.ge(range.from_block)),
+                )
+                .set((
+                    snapshot_checkpoints::last_block.eq(range.from_block - 1),
+                    snapshot_checkpoints::updated_at.eq(diesel::dsl::now),
+                ))
+                .execute(conn)?;
+                diesel::update(failed_ranges::table.find(range.id))
+                    .set(failed_ranges::resolved_at.eq(diesel::dsl::now))
+                    .execute(conn)
+            })
+            .map_err(|e| Box::new(e) as Box<dyn std::error::Error + Send + Sync>)
+        })
+        .await?
+    }
+}
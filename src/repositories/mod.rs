@@ -1 +1,7 @@
-pub mod transfer_repo;
\ No newline at end of file
+pub mod transfer_repo;
+pub mod checkpoint_repo;
+pub mod block_repo;
+pub mod token_repo;
+pub mod balance_repo;
+pub mod webhook_repo;
+pub mod failed_range_repo;
\ No newline at end of file
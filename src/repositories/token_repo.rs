@@ -0,0 +1,121 @@
+use diesel::prelude::*;
+use diesel::r2d2::ConnectionManager;
+use tokio::task;
+use crate::models::token::{NewToken, Token, TokenMetadata};
+use crate::schema::tokens;
+
+/// Manages database operations for the registry of indexed tokens.
+#[derive(Debug)]
+pub struct TokenRepo {
+    pub pool: diesel::r2d2::Pool<ConnectionManager<PgConnection>>,
+}
+
+impl TokenRepo {
+    /// Creates a new TokenRepo with the given database pool.
+    pub fn new(pool: diesel::r2d2::Pool<ConnectionManager<PgConnection>>) -> Self {
+        TokenRepo { pool }
+    }
+
+    /// Registers token addresses for indexing, ignoring those already registered.
+    pub async fn register_tokens(&self, new_tokens: Vec<NewToken>) -> Result<usize, Box<dyn std::error::Error + Send + Sync>> {
+        let mut conn = self.pool.get()?;
+        task::spawn_blocking(move || {
+            diesel::insert_into(tokens::table)
+                .values(&new_tokens)
+                .on_conflict_do_nothing()
+                .execute(&mut conn)
+                .map_err(|e| Box::new(e) as Box<dyn std::error::Error + Send + Sync>)
+        })
+        .await?
+    }
+
+    /// Retrieves the addresses of all registered tokens.
+    pub async fn get_token_addresses(&self) -> Result<Vec<String>, Box<dyn std::error::Error + Send + Sync>> {
+        let mut conn = self.pool.get()?;
+        task::spawn_blocking(move || {
+            tokens::table
+                .select(tokens::address)
+                .order((tokens::created_at.asc(), tokens::address.asc()))
+                .load::<String>(&mut conn)
+                .map_err(|e| Box::new(e) as Box<dyn std::error::Error + Send + Sync>)
+        })
+        .await?
+    }
+
+    /// Caches the metadata read from a token contract.
+    pub async fn update_metadata(
+        &self,
+        address: String,
+        metadata: TokenMetadata,
+    ) -> Result<usize, Box<dyn std::error::Error + Send + Sync>> {
+        let mut conn = self.pool.get()?;
+        task::spawn_blocking(move || {
+            diesel::update(tokens::table.find(address))
+                .set((&metadata, tokens::metadata_updated_at.eq(diesel::dsl::now)))
+                .execute(&mut conn)
+                .map_err(|e| Box::new(e) as Box<dyn std::error::Error + Send + Sync>)
+        })
+        .await?
+    }
+
+    /// Caches the total supply read from a token contract at a block.
+    pub async fn update_total_supply(
+        &self,
+        address: String,
+        total_supply: String,
+        block_number: i64,
+    ) -> Result<usize, Box<dyn std::error::Error + Send + Sync>> {
+        let mut conn = self.pool.get()?;
+        task::spawn_blocking(move || {
+            diesel::update(tokens::table.find(address))
+                .set((
+                    tokens::total_supply.eq(total_supply),
+                    tokens::total_supply_block.eq(block_number),
+                ))
+                .execute(&mut conn)
+                .map_err(|e| Box::new(e) as Box<dyn std::error::Error + Send + Sync>)
+        })
+        .await?
+    }
+
+    /// Caches the block at which a token contract was deployed.
+    pub async fn update_deployment_block(
+        &self,
+        address: String,
+        block_number: i64,
+    ) -> Result<usize, Box<dyn std::error::Error + Send + Sync>> {
+        let mut conn = self.pool.get()?;
+        task::spawn_blocking(move || {
+            diesel::update(tokens::table.find(address))
+                .set(tokens::deployment_block.eq(block_number))
+                .execute(&mut conn)
+                .map_err(|e| Box::new(e) as Box<dyn std::error::Error + Send + Sync>)
+        })
+        .await?
+    }
+
+    /// Retrieves a registered token with its cached metadata.
+    pub async fn get_token(&self, address: String) -> Result<Option<Token>, Box<dyn std::error::Error + Send + Sync>> {
+        let mut conn = self.pool.get()?;
+        task::spawn_blocking(move || {
+            tokens::table
+                .find(address)
+                .first::<Token>(&mut conn)
+                .optional()
+                .map_err(|e| Box::new(e) as Box<dyn std::error::Error + Send + Sync>)
+        })
+        .await?
+    }
+
+    /// Retrieves all registered tokens with their cached metadata.
+    pub async fn get_tokens(&self) -> Result<Vec<Token>, Box<dyn std::error::Error + Send + Sync>> {
+        let mut conn = self.pool.get()?;
+        task::spawn_blocking(move || {
+            tokens::table
+                .order((tokens::created_at.asc(), tokens::address.asc()))
+                .load::<Token>(&mut conn)
+                .map_err(|e| Box::new(e) as Box<dyn std::error::Error + Send + Sync>)
+        })
+        .await?
+    }
+}
@@ -1,8 +1,69 @@
+use bigdecimal::BigDecimal;
+use chrono::NaiveDateTime;
+use diesel::dsl::sum;
 use diesel::prelude::*;
 use diesel::r2d2::ConnectionManager;
+use diesel::sql_types::{Nullable, Text, Timestamp};
+use diesel::upsert::excluded;
+use std::collections::HashSet;
 use tokio::task;
-use crate::models::transfer::{Transfer, NewTransfer};
-use crate::schema::transfers;
+use crate::repositories::balance_repo::BalanceDeltas;
+use crate::repositories::checkpoint_repo::upsert_checkpoints;
+use crate::repositories::webhook_repo::enqueue_deliveries;
+use crate::models::block::NewIndexedBlock;
+use crate::models::checkpoint::NewIndexerCheckpoint;
+use crate::models::token::SupplyPoint;
+use crate::models::transfer::{Transfer, NewTransfer, KIND_BURN, KIND_MINT, STATUS_CONFIRMED, STATUS_UNCONFIRMED};
+use crate::schema::{indexed_blocks, transfers};
+
+/// Computes the tokens minted and burned per period from the confirmed transfers of a token, and the
+/// supply at the end of each period, keeping the periods within the optional time range.
+const SUPPLY_HISTORY_SQL: &str = "
+    SELECT time, minted, burned, supply
+    FROM (
+        SELECT time, minted, burned, SUM(minted - burned) OVER (ORDER BY time) AS supply
+        FROM (
+            SELECT date_trunc($2, block_timestamp) AS time,
+                COALESCE(SUM(amount) FILTER (WHERE kind = $3), 0) AS minted,
+                COALESCE(SUM(amount) FILTER (WHERE kind = $4), 0) AS burned
+            FROM transfers
+            WHERE token_address = $1 AND status = $5 AND kind IN ($3, $4)
+            GROUP BY 1
+        ) AS periods
+    ) AS history
+    WHERE ($6 IS NULL OR time >= $6) AND ($7 IS NULL OR time <= $7)
+    ORDER BY time";
+
+/// Number of transfers per insert statement, keeping the 11 bound columns of each row within the
+/// 65,535 bind parameters Postgres accepts per statement.
+const INSERT_CHUNK_SIZE: usize = 65_535 / 11;
+
+/// Order in which transfers are retrieved, by block number then log index.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum SortOrder {
+    Asc,
+    #[default]
+    Desc,
+}
+
+/// Optional filters applied when retrieving transfers.
+#[derive(Debug, Default)]
+pub struct TransferFilter {
+    pub sender: Option<String>,
+    pub recipient: Option<String>,
+    /// Matches transfers where the address is either the sender or the recipient.
+    pub address: Option<String>,
+    pub status: Option<String>,
+    pub token_address: Option<String>,
+    /// `mint`, `burn` or `transfer`.
+    pub kind: Option<String>,
+    pub from_block: Option<i64>,
+    pub to_block: Option<i64>,
+    pub from_time: Option<NaiveDateTime>,
+    pub to_time: Option<NaiveDateTime>,
+    pub min_amount: Option<BigDecimal>,
+    pub max_amount: Option<BigDecimal>,
+}
 
 /// Manages database operations for ERC-20 transfer events.
 #[derive(Debug)]
@@ -16,36 +77,183 @@ impl TransferRepo {
         TransferRepo { pool }
     }
 
-    /// Inserts a new transfer into the database, ignoring duplicates.
-    pub async fn insert_transfer(&self, transfer: NewTransfer) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    /// Inserts a batch of confirmed transfers and the hashes of their blocks, updates the balances
+    /// and advances the checkpoints of the covered tokens in a single transaction, queuing the
+    /// webhook deliveries of the new transfers when `deliver` is set.
+    /// Returns the transfers that were not already stored as confirmed.
+    pub async fn insert_transfers_with_checkpoints(
+        &self,
+        new_transfers: Vec<NewTransfer>,
+        blocks: Vec<NewIndexedBlock>,
+        checkpoints: Vec<NewIndexerCheckpoint>,
+        deliver: bool,
+    ) -> Result<Vec<Transfer>, Box<dyn std::error::Error + Send + Sync>> {
         let mut conn = self.pool.get()?;
         task::spawn_blocking(move || {
-            diesel::insert_into(transfers::table)
-                .values(&transfer)
-                .on_conflict_do_nothing() // Skip if transfer already exists (e.g., same tx_hash).
-                .execute(&mut conn)
-                .map_err(|e| Box::new(e) as Box<dyn std::error::Error + Send + Sync>)?;
-            Ok(())
+            conn.transaction::<Vec<Transfer>, diesel::result::Error, _>(|conn| {
+                // Only transfers that were not already stored as confirmed change balances.
+                let tx_hashes: Vec<&str> = new_transfers.iter().map(|transfer| transfer.tx_hash.as_str()).collect();
+                let confirmed: HashSet<(String, i32)> = transfers::table
+                    .filter(transfers::tx_hash.eq_any(&tx_hashes))
+                    .filter(transfers::status.eq(STATUS_CONFIRMED))
+                    .select((transfers::tx_hash, transfers::log_index))
+                    .load(conn)?
+                    .into_iter()
+                    .collect();
+                let mut deltas = BalanceDeltas::new();
+                for transfer in &new_transfers {
+                    if transfer.status == STATUS_CONFIRMED
+                        && !confirmed.contains(&(transfer.tx_hash.clone(), transfer.log_index))
+                    {
+                        deltas.add_transfer(&transfer.token_address, &transfer.sender, &transfer.recipient, &transfer.amount, false);
+                    }
+                }
+                // Promote transfers already stored as unconfirmed (same tx_hash and log_index).
+                let mut inserted: Vec<Transfer> = Vec::with_capacity(new_transfers.len());
+                for chunk in new_transfers.chunks(INSERT_CHUNK_SIZE) {
+                    inserted.extend(
+                        diesel::insert_into(transfers::table)
+                            .values(chunk)
+                            .on_conflict((transfers::tx_hash, transfers::log_index))
+                            .do_update()
+                            .set((
+                                transfers::block_number.eq(excluded(transfers::block_number)),
+                                transfers::transaction_index.eq(excluded(transfers::transaction_index)),
+                                transfers::status.eq(excluded(transfers::status)),
+                                transfers::block_timestamp.eq(excluded(transfers::block_timestamp)),
+                                transfers::kind.eq(excluded(transfers::kind)),
+                            ))
+                            .get_results::<Transfer>(conn)?,
+                    );
+                }
+                deltas.apply(conn)?;
+                // Unconfirmed transfers left in the confirmed range were orphaned before being confirmed.
+                for checkpoint in &checkpoints {
+                    diesel::delete(
+                        transfers::table
+                            .filter(transfers::token_address.eq(&checkpoint.token_address))
+                            .filter(transfers::status.eq(STATUS_UNCONFIRMED))
+                            .filter(transfers::block_number.le(checkpoint.last_block)),
+                    )
+                    .execute(conn)?;
+                }
+                diesel::insert_into(indexed_blocks::table)
+                    .values(&blocks)
+                    .on_conflict((indexed_blocks::chain_id, indexed_blocks::block_number))
+                    .do_update()
+                    .set((
+                        indexed_blocks::block_hash.eq(excluded(indexed_blocks::block_hash)),
+                        indexed_blocks::parent_hash.eq(excluded(indexed_blocks::parent_hash)),
+                    ))
+                    .execute(conn)?;
+                upsert_checkpoints(conn, &checkpoints)?;
+                let newly_confirmed: Vec<Transfer> = inserted.into_iter()
+                    .filter(|transfer| transfer.status == STATUS_CONFIRMED
+                        && !confirmed.contains(&(transfer.tx_hash.clone(), transfer.log_index)))
+                    .collect();
+                if deliver {
+                    enqueue_deliveries(conn, &newly_confirmed)?;
+                }
+                Ok(newly_confirmed)
+            })
+            .map_err(|e| Box::new(e) as Box<dyn std::error::Error + Send + Sync>)
         })
         .await?
     }
 
-    /// Retrieves transfers from the database with optional sender/recipient filters.
+    /// Replaces the unconfirmed transfers above the given confirmed block in a single transaction.
+    pub async fn replace_unconfirmed_transfers(
+        &self,
+        new_transfers: Vec<NewTransfer>,
+        above_block: i64,
+    ) -> Result<usize, Box<dyn std::error::Error + Send + Sync>> {
+        let mut conn = self.pool.get()?;
+        task::spawn_blocking(move || {
+            conn.transaction::<usize, diesel::result::Error, _>(|conn| {
+                diesel::delete(
+                    transfers::table
+                        .filter(transfers::status.eq(STATUS_UNCONFIRMED))
+                        .filter(transfers::block_number.gt(above_block)),
+                )
+                .execute(conn)?;
+                let mut inserted = 0;
+                for chunk in new_transfers.chunks(INSERT_CHUNK_SIZE) {
+                    inserted += diesel::insert_into(transfers::table)
+                        .values(chunk)
+                        .on_conflict_do_nothing()
+                        .execute(conn)?;
+                }
+                Ok(inserted)
+            })
+            .map_err(|e| Box::new(e) as Box<dyn std::error::Error + Send + Sync>)
+        })
+        .await?
+    }
+
+    /// Retrieves a page of transfers matching the filter in the given order, starting strictly after
+    /// the `(block_number, log_index)` position given by `after`.
     pub async fn get_transfers(
         &self,
-        sender: Option<String>,
-        recipient: Option<String>,
+        filter: TransferFilter,
+        order: SortOrder,
+        after: Option<(i64, i32)>,
+        limit: i64,
     ) -> Result<Vec<Transfer>, Box<dyn std::error::Error + Send + Sync>> {
         let mut conn = self.pool.get()?;
-        let sender = sender.clone();
-        let recipient = recipient.clone();
         task::spawn_blocking(move || {
-            let mut query = transfers::table.order(transfers::block_number.desc()).into_boxed();
-            if let Some(s) = sender {
-                query = query.filter(transfers::sender.eq(s));
+            let mut query = transfers::table.limit(limit).into_boxed();
+            query = match order {
+                SortOrder::Asc => query.order((transfers::block_number.asc(), transfers::log_index.asc())),
+                SortOrder::Desc => query.order((transfers::block_number.desc(), transfers::log_index.desc())),
+            };
+            if let Some(s) = filter.sender {
+                query = query.filter(transfers::sender.eq(s.to_lowercase()));
+            }
+            if let Some(r) = filter.recipient {
+                query = query.filter(transfers::recipient.eq(r.to_lowercase()));
+            }
+            if let Some(a) = filter.address {
+                let a = a.to_lowercase();
+                query = query.filter(transfers::sender.eq(a.clone()).or(transfers::recipient.eq(a)));
+            }
+            if let Some(st) = filter.status {
+                query = query.filter(transfers::status.eq(st));
+            }
+            if let Some(t) = filter.token_address {
+                query = query.filter(transfers::token_address.eq(t.to_lowercase()));
+            }
+            if let Some(k) = filter.kind {
+                query = query.filter(transfers::kind.eq(k));
+            }
+            if let Some(from) = filter.from_block {
+                query = query.filter(transfers::block_number.ge(from));
             }
-            if let Some(r) = recipient {
-                query = query.filter(transfers::recipient.eq(r));
+            if let Some(to) = filter.to_block {
+                query = query.filter(transfers::block_number.le(to));
+            }
+            if let Some(from) = filter.from_time {
+                query = query.filter(transfers::block_timestamp.ge(from));
+            }
+            if let Some(to) = filter.to_time {
+                query = query.filter(transfers::block_timestamp.le(to));
+            }
+            if let Some(min) = filter.min_amount {
+                query = query.filter(transfers::amount.ge(min));
+            }
+            if let Some(max) = filter.max_amount {
+                query = query.filter(transfers::amount.le(max));
+            }
+            if let Some((block_number, log_index)) = after {
+                query = match order {
+                    SortOrder::Asc => query.filter(
+                        transfers::block_number.gt(block_number)
+                            .or(transfers::block_number.eq(block_number).and(transfers::log_index.gt(log_index))),
+                    ),
+                    SortOrder::Desc => query.filter(
+                        transfers::block_number.lt(block_number)
+                            .or(transfers::block_number.eq(block_number).and(transfers::log_index.lt(log_index))),
+                    ),
+                };
             }
             query
                 .load::<Transfer>(&mut conn)
@@ -53,4 +261,56 @@ impl TransferRepo {
         })
         .await?
     }
+
+    /// Computes the supply of a token from its confirmed mints minus burns, up to a block if given.
+    pub async fn get_supply(
+        &self,
+        token_address: String,
+        up_to_block: Option<i64>,
+    ) -> Result<BigDecimal, Box<dyn std::error::Error + Send + Sync>> {
+        let mut conn = self.pool.get()?;
+        task::spawn_blocking(move || -> Result<BigDecimal, Box<dyn std::error::Error + Send + Sync>> {
+            let mut total = BigDecimal::default();
+            for (kind, sign) in [(KIND_MINT, 1), (KIND_BURN, -1)] {
+                let mut query = transfers::table
+                    .filter(transfers::token_address.eq(&token_address))
+                    .filter(transfers::status.eq(STATUS_CONFIRMED))
+                    .filter(transfers::kind.eq(kind))
+                    .select(sum(transfers::amount))
+                    .into_boxed();
+                if let Some(block) = up_to_block {
+                    query = query.filter(transfers::block_number.le(block));
+                }
+                let amount = query.first::<Option<BigDecimal>>(&mut conn)?.unwrap_or_default();
+                total += amount * BigDecimal::from(sign);
+            }
+            Ok(total)
+        })
+        .await?
+    }
+
+    /// Retrieves the tokens minted and burned per period (`hour`, `day`, `week` or `month`) and the
+    /// supply at the end of each period, for the periods with mints or burns within the time range.
+    pub async fn get_supply_history(
+        &self,
+        token_address: String,
+        period: &'static str,
+        from_time: Option<NaiveDateTime>,
+        to_time: Option<NaiveDateTime>,
+    ) -> Result<Vec<SupplyPoint>, Box<dyn std::error::Error + Send + Sync>> {
+        let mut conn = self.pool.get()?;
+        task::spawn_blocking(move || {
+            diesel::sql_query(SUPPLY_HISTORY_SQL)
+                .bind::<Text, _>(token_address)
+                .bind::<Text, _>(period)
+                .bind::<Text, _>(KIND_MINT)
+                .bind::<Text, _>(KIND_BURN)
+                .bind::<Text, _>(STATUS_CONFIRMED)
+                .bind::<Nullable<Timestamp>, _>(from_time)
+                .bind::<Nullable<Timestamp>, _>(to_time)
+                .load::<SupplyPoint>(&mut conn)
+                .map_err(|e| Box::new(e) as Box<dyn std::error::Error + Send + Sync>)
+        })
+        .await?
+    }
 }
\ No newline at end of file
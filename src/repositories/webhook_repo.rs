@@ -0,0 +1,234 @@
+use diesel::dsl::IntervalDsl;
+use diesel::prelude::*;
+use diesel::r2d2::ConnectionManager;
+use tokio::task;
+use crate::models::transfer::Transfer;
+use crate::models::webhook::{
+    NewWebhook, NewWebhookDelivery, Webhook, WebhookDelivery, WebhookPayload, DELIVERY_CANCELLED, DELIVERY_DEAD,
+    DELIVERY_DELIVERED, DELIVERY_PENDING,
+};
+use crate::schema::{webhook_deliveries, webhooks};
+
+/// Queues the delivery of newly confirmed transfers to the webhooks they match, within the caller's
+/// transaction so a transfer is never stored without its deliveries.
+pub fn enqueue_deliveries(conn: &mut PgConnection, transfers: &[Transfer]) -> QueryResult<usize> {
+    if transfers.is_empty() {
+        return Ok(0);
+    }
+    let webhooks = webhooks::table.load::<Webhook>(conn)?;
+    let mut deliveries = Vec::new();
+    for transfer in transfers {
+        let matching: Vec<&Webhook> = webhooks.iter().filter(|webhook| webhook.matches(transfer)).collect();
+        if matching.is_empty() {
+            continue;
+        }
+        let payload = serde_json::to_string(&WebhookPayload { event: "transfer", transfer })
+            .map_err(|e| diesel::result::Error::SerializationError(Box::new(e)))?;
+        for webhook in matching {
+            deliveries.push(NewWebhookDelivery {
+                webhook_id: webhook.id,
+                transfer_id: transfer.id,
+                payload: payload.clone(),
+            });
+        }
+    }
+    // Stay within the 65,535 bind parameters Postgres accepts per statement, 3 per delivery.
+    let mut enqueued = 0;
+    for chunk in deliveries.chunks(65_535 / 3) {
+        enqueued += diesel::insert_into(webhook_deliveries::table)
+            .values(chunk)
+            .execute(conn)?;
+    }
+    Ok(enqueued)
+}
+
+/// Cancels the pending and dead deliveries of transfers removed by a chain reorganization, within
+/// the caller's transaction, so they are never sent.
+pub fn cancel_deliveries(conn: &mut PgConnection, transfer_ids: &[i32]) -> QueryResult<usize> {
+    let mut cancelled = 0;
+    for chunk in transfer_ids.chunks(65_535) {
+        cancelled += diesel::update(
+            webhook_deliveries::table
+                .filter(webhook_deliveries::transfer_id.eq_any(chunk))
+                .filter(webhook_deliveries::status.eq_any([DELIVERY_PENDING, DELIVERY_DEAD])),
+        )
+        .set((
+            webhook_deliveries::status.eq(DELIVERY_CANCELLED),
+            webhook_deliveries::last_error.eq("Transfer removed by a chain reorganization"),
+        ))
+        .execute(conn)?;
+    }
+    Ok(cancelled)
+}
+
+/// Manages database operations for webhooks and the delivery of transfers to them.
+#[derive(Debug)]
+pub struct WebhookRepo {
+    pub pool: diesel::r2d2::Pool<ConnectionManager<PgConnection>>,
+}
+
+impl WebhookRepo {
+    /// Creates a new WebhookRepo with the given database pool.
+    pub fn new(pool: diesel::r2d2::Pool<ConnectionManager<PgConnection>>) -> Self {
+        WebhookRepo { pool }
+    }
+
+    /// Registers a webhook.
+    pub async fn create_webhook(&self, new_webhook: NewWebhook) -> Result<Webhook, Box<dyn std::error::Error + Send + Sync>> {
+        let mut conn = self.pool.get()?;
+        task::spawn_blocking(move || {
+            diesel::insert_into(webhooks::table)
+                .values(&new_webhook)
+                .get_result::<Webhook>(&mut conn)
+                .map_err(|e| Box::new(e) as Box<dyn std::error::Error + Send + Sync>)
+        })
+        .await?
+    }
+
+    /// Retrieves all registered webhooks, oldest first.
+    pub async fn get_webhooks(&self) -> Result<Vec<Webhook>, Box<dyn std::error::Error + Send + Sync>> {
+        let mut conn = self.pool.get()?;
+        task::spawn_blocking(move || {
+            webhooks::table
+                .order(webhooks::id.asc())
+                .load::<Webhook>(&mut conn)
+                .map_err(|e| Box::new(e) as Box<dyn std::error::Error + Send + Sync>)
+        })
+        .await?
+    }
+
+    /// Deletes a webhook with its deliveries. Returns whether it existed.
+    pub async fn delete_webhook(&self, id: i32) -> Result<bool, Box<dyn std::error::Error + Send + Sync>> {
+        let mut conn = self.pool.get()?;
+        task::spawn_blocking(move || {
+            diesel::delete(webhooks::table.find(id))
+                .execute(&mut conn)
+                .map(|deleted| deleted > 0)
+                .map_err(|e| Box::new(e) as Box<dyn std::error::Error + Send + Sync>)
+        })
+        .await?
+    }
+
+    /// Retrieves a page of the deliveries of a webhook, newest first, starting strictly before the
+    /// given delivery id and optionally restricted to a status.
+    pub async fn get_deliveries(
+        &self,
+        webhook_id: i32,
+        status: Option<String>,
+        before: Option<i32>,
+        limit: i64,
+    ) -> Result<Vec<WebhookDelivery>, Box<dyn std::error::Error + Send + Sync>> {
+        let mut conn = self.pool.get()?;
+        task::spawn_blocking(move || {
+            let mut query = webhook_deliveries::table
+                .filter(webhook_deliveries::webhook_id.eq(webhook_id))
+                .into_boxed();
+            if let Some(status) = status {
+                query = query.filter(webhook_deliveries::status.eq(status));
+            }
+            if let Some(before) = before {
+                query = query.filter(webhook_deliveries::id.lt(before));
+            }
+            query
+                .order(webhook_deliveries::id.desc())
+                .limit(limit)
+                .load::<WebhookDelivery>(&mut conn)
+                .map_err(|e| Box::new(e) as Box<dyn std::error::Error + Send + Sync>)
+        })
+        .await?
+    }
+
+    /// Retrieves the pending deliveries whose next attempt is due, oldest first, with their webhook.
+    pub async fn get_due_deliveries(
+        &self,
+        limit: i64,
+    ) -> Result<Vec<(WebhookDelivery, Webhook)>, Box<dyn std::error::Error + Send + Sync>> {
+        let mut conn = self.pool.get()?;
+        task::spawn_blocking(move || {
+            webhook_deliveries::table
+                .inner_join(webhooks::table)
+                .filter(webhook_deliveries::status.eq(DELIVERY_PENDING))
+                .filter(webhook_deliveries::next_attempt_at.le(diesel::dsl::now))
+                .order(webhook_deliveries::id.asc())
+                .limit(limit)
+                .load::<(WebhookDelivery, Webhook)>(&mut conn)
+                .map_err(|e| Box::new(e) as Box<dyn std::error::Error + Send + Sync>)
+        })
+        .await?
+    }
+
+    /// Records a successful attempt of a delivery.
+    pub async fn mark_delivered(
+        &self,
+        id: i32,
+        response_status: i32,
+    ) -> Result<usize, Box<dyn std::error::Error + Send + Sync>> {
+        let mut conn = self.pool.get()?;
+        task::spawn_blocking(move || {
+            diesel::update(webhook_deliveries::table.find(id))
+                .set((
+                    webhook_deliveries::status.eq(DELIVERY_DELIVERED),
+                    webhook_deliveries::attempts.eq(webhook_deliveries::attempts + 1),
+                    webhook_deliveries::last_response_status.eq(response_status),
+                    webhook_deliveries::last_error.eq(None::<String>),
+                    webhook_deliveries::delivered_at.eq(diesel::dsl::now),
+                ))
+                .execute(&mut conn)
+                .map_err(|e| Box::new(e) as Box<dyn std::error::Error + Send + Sync>)
+        })
+        .await?
+    }
+
+    /// Records a failed attempt of a delivery, scheduling the next one after the given delay, or
+    /// moving the delivery to the dead-letter state when no attempt is left.
+    pub async fn mark_failed(
+        &self,
+        id: i32,
+        response_status: Option<i32>,
+        error: String,
+        retry_in_secs: Option<i64>,
+    ) -> Result<usize, Box<dyn std::error::Error + Send + Sync>> {
+        let mut conn = self.pool.get()?;
+        task::spawn_blocking(move || {
+            let status = if retry_in_secs.is_some() { DELIVERY_PENDING } else { DELIVERY_DEAD };
+            // A delivery cancelled during the attempt stays cancelled.
+            diesel::update(
+                webhook_deliveries::table
+                    .filter(webhook_deliveries::id.eq(id))
+                    .filter(webhook_deliveries::status.eq(DELIVERY_PENDING)),
+            )
+                .set((
+                    webhook_deliveries::status.eq(status),
+                    webhook_deliveries::attempts.eq(webhook_deliveries::attempts + 1),
+                    webhook_deliveries::last_response_status.eq(response_status),
+                    webhook_deliveries::last_error.eq(error),
+                    webhook_deliveries::next_attempt_at.eq(diesel::dsl::now + retry_in_secs.unwrap_or(0).seconds()),
+                ))
+                .execute(&mut conn)
+                .map_err(|e| Box::new(e) as Box<dyn std::error::Error + Send + Sync>)
+        })
+        .await?
+    }
+
+    /// Moves a dead delivery back to pending for a new series of attempts, starting now.
+    /// Returns the delivery, or `None` if there is no dead delivery with this id.
+    pub async fn retry_delivery(&self, id: i32) -> Result<Option<WebhookDelivery>, Box<dyn std::error::Error + Send + Sync>> {
+        let mut conn = self.pool.get()?;
+        task::spawn_blocking(move || {
+            diesel::update(
+                webhook_deliveries::table
+                    .filter(webhook_deliveries::id.eq(id))
+                    .filter(webhook_deliveries::status.eq(DELIVERY_DEAD)),
+            )
+            .set((
+                webhook_deliveries::status.eq(DELIVERY_PENDING),
+                webhook_deliveries::attempts.eq(0),
+                webhook_deliveries::next_attempt_at.eq(diesel::dsl::now),
+            ))
+            .get_result::<WebhookDelivery>(&mut conn)
+            .optional()
+            .map_err(|e| Box::new(e) as Box<dyn std::error::Error + Send + Sync>)
+        })
+        .await?
+    }
+}
@@ -1,12 +1,146 @@
 // @generated automatically by Diesel CLI.
 
+diesel::table! {
+    balance_snapshots (token_address, address, block_number) {
+        token_address -> Text,
+        address -> Text,
+        block_number -> Int8,
+        balance -> Numeric,
+    }
+}
+
+diesel::table! {
+    balances (token_address, address) {
+        token_address -> Text,
+        address -> Text,
+        balance -> Numeric,
+        updated_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    failed_ranges (id) {
+        id -> Int4,
+        chain_id -> Int8,
+        token_address -> Text,
+        from_block -> Int8,
+        to_block -> Int8,
+        attempts -> Int4,
+        last_error -> Text,
+        next_retry_at -> Timestamp,
+        created_at -> Timestamp,
+        resolved_at -> Nullable<Timestamp>,
+    }
+}
+
+diesel::table! {
+    indexed_blocks (chain_id, block_number) {
+        chain_id -> Int8,
+        block_number -> Int8,
+        block_hash -> Text,
+        parent_hash -> Text,
+    }
+}
+
+diesel::table! {
+    indexer_checkpoints (chain_id, token_address) {
+        chain_id -> Int8,
+        token_address -> Text,
+        last_block -> Int8,
+        updated_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    reorgs (id) {
+        id -> Int4,
+        chain_id -> Int8,
+        fork_block -> Int8,
+        depth -> Int8,
+        removed_transfers -> Int8,
+        detected_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    snapshot_checkpoints (token_address) {
+        token_address -> Text,
+        last_block -> Int8,
+        updated_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    tokens (address) {
+        address -> Text,
+        created_at -> Timestamp,
+        name -> Nullable<Text>,
+        symbol -> Nullable<Text>,
+        decimals -> Nullable<Int2>,
+        total_supply -> Nullable<Text>,
+        metadata_updated_at -> Nullable<Timestamp>,
+        total_supply_block -> Nullable<Int8>,
+        deployment_block -> Nullable<Int8>,
+    }
+}
+
 diesel::table! {
     transfers (id) {
         id -> Int4,
         sender -> Text,
         recipient -> Text,
-        amount -> Text,
+        amount -> Numeric,
         block_number -> Int8,
         tx_hash -> Text,
+        log_index -> Int4,
+        transaction_index -> Int4,
+        status -> Text,
+        token_address -> Text,
+        block_timestamp -> Timestamp,
+        kind -> Text,
     }
 }
+
+diesel::table! {
+    webhook_deliveries (id) {
+        id -> Int4,
+        webhook_id -> Int4,
+        transfer_id -> Int4,
+        payload -> Text,
+        status -> Text,
+        attempts -> Int4,
+        next_attempt_at -> Timestamp,
+        last_response_status -> Nullable<Int4>,
+        last_error -> Nullable<Text>,
+        created_at -> Timestamp,
+        delivered_at -> Nullable<Timestamp>,
+    }
+}
+
+diesel::table! {
+    webhooks (id) {
+        id -> Int4,
+        url -> Text,
+        secret -> Text,
+        sender -> Nullable<Text>,
+        recipient -> Nullable<Text>,
+        min_amount -> Nullable<Numeric>,
+        created_at -> Timestamp,
+    }
+}
+
+diesel::joinable!(webhook_deliveries -> webhooks (webhook_id));
+
+diesel::allow_tables_to_appear_in_same_query!(
+    balance_snapshots,
+    balances,
+    failed_ranges,
+    indexed_blocks,
+    indexer_checkpoints,
+    reorgs,
+    snapshot_checkpoints,
+    tokens,
+    transfers,
+    webhook_deliveries,
+    webhooks,
+);
@@ -0,0 +1,145 @@
+use chrono::{DateTime, NaiveDateTime};
+use futures::stream::{self, StreamExt, TryStreamExt};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::Mutex;
+use web3::types::{BlockId, H256};
+use crate::utils::transport::FailoverTransport;
+
+/// Maximum number of block timestamps kept in memory, the least recently used being evicted first.
+const MAX_CACHED_BLOCKS: usize = 10_000;
+/// Maximum number of block headers requested at once when prefetching timestamps.
+const PREFETCH_CONCURRENCY: usize = 200;
+
+/// Cached timestamps with the order in which they were last used.
+#[derive(Debug, Default)]
+struct Entries {
+    /// Timestamp of each cached block and the tick it was last used at.
+    timestamps: HashMap<H256, (NaiveDateTime, u64)>,
+    /// Cached blocks by the tick they were last used at, oldest first.
+    recency: BTreeMap<u64, H256>,
+    tick: u64,
+}
+
+impl Entries {
+    /// Retrieves a timestamp, marking it as the most recently used.
+    fn get(&mut self, block_hash: &H256) -> Option<NaiveDateTime> {
+        self.tick += 1;
+        let tick = self.tick;
+        let (timestamp, used_at) = self.timestamps.get_mut(block_hash)?;
+        self.recency.remove(used_at);
+        *used_at = tick;
+        self.recency.insert(tick, *block_hash);
+        Some(*timestamp)
+    }
+
+    /// Stores a timestamp as the most recently used, evicting the least recently used one when full.
+    fn insert(&mut self, block_hash: H256, timestamp: NaiveDateTime) {
+        self.tick += 1;
+        if let Some((_, used_at)) = self.timestamps.insert(block_hash, (timestamp, self.tick)) {
+            self.recency.remove(&used_at);
+        }
+        self.recency.insert(self.tick, block_hash);
+        while self.timestamps.len() > MAX_CACHED_BLOCKS {
+            let Some((_, oldest)) = self.recency.pop_first() else { break };
+            self.timestamps.remove(&oldest);
+        }
+    }
+}
+
+/// Caches block timestamps by block hash, so each block is fetched from the node only once and a
+/// reorganized block never reuses the timestamp of the block it replaced.
+#[derive(Debug, Default)]
+pub struct BlockTimestampCache {
+    entries: Mutex<Entries>,
+}
+
+impl BlockTimestampCache {
+    /// Creates an empty cache.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Stores the timestamp of a block, given in unix seconds.
+    pub fn insert(&self, block_hash: H256, unix_seconds: u64) {
+        self.store(block_hash, unix_seconds);
+    }
+
+    /// Stores the timestamp of a block and returns it, if it is a valid time.
+    fn store(&self, block_hash: H256, unix_seconds: u64) -> Option<NaiveDateTime> {
+        let timestamp = DateTime::from_timestamp(unix_seconds as i64, 0)?.naive_utc();
+        self.entries.lock().unwrap().insert(block_hash, timestamp);
+        Some(timestamp)
+    }
+
+    /// Retrieves the timestamp of a block, fetching its header from the node if it is not cached.
+    pub async fn get(
+        &self,
+        web3: &web3::Web3<FailoverTransport>,
+        block_hash: H256,
+    ) -> Result<NaiveDateTime, Box<dyn std::error::Error + Send + Sync + 'static>> {
+        if let Some(timestamp) = self.entries.lock().unwrap().get(&block_hash) {
+            return Ok(timestamp);
+        }
+        let block = web3.eth()
+            .block(BlockId::Hash(block_hash))
+            .await?
+            .ok_or_else(|| format!("Block 0x{:x} not found", block_hash))?;
+        self.store(block_hash, block.timestamp.as_u64())
+            .ok_or_else(|| format!("Block 0x{:x} has an invalid timestamp", block_hash).into())
+    }
+
+    /// Fetches the timestamps of the given blocks that are not cached yet, requesting their headers
+    /// concurrently so the calls are batched.
+    pub async fn prefetch(
+        &self,
+        web3: &web3::Web3<FailoverTransport>,
+        block_hashes: impl IntoIterator<Item = H256>,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
+        let missing: HashSet<H256> = {
+            let mut entries = self.entries.lock().unwrap();
+            block_hashes.into_iter().filter(|hash| entries.get(hash).is_none()).collect()
+        };
+        stream::iter(missing)
+            .map(|block_hash| self.get(web3, block_hash))
+            .buffer_unordered(PREFETCH_CONCURRENCY)
+            .try_for_each(|_| async { Ok(()) })
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn least_recently_used_blocks_are_evicted() {
+        let cache = BlockTimestampCache::new();
+        let hashes: Vec<H256> = (0..=MAX_CACHED_BLOCKS as u64).map(H256::from_low_u64_be).collect();
+        for (i, hash) in hashes[..MAX_CACHED_BLOCKS].iter().enumerate() {
+            cache.insert(*hash, i as u64);
+        }
+        // Using the oldest block keeps it, so the next oldest is evicted instead.
+        assert!(cache.entries.lock().unwrap().get(&hashes[0]).is_some());
+        cache.insert(hashes[MAX_CACHED_BLOCKS], 1);
+
+        let mut entries = cache.entries.lock().unwrap();
+        assert_eq!(entries.timestamps.len(), MAX_CACHED_BLOCKS);
+        assert_eq!(entries.recency.len(), MAX_CACHED_BLOCKS);
+        assert!(entries.get(&hashes[0]).is_some());
+        assert!(entries.get(&hashes[1]).is_none());
+        assert!(entries.get(&hashes[2]).is_some());
+        assert!(entries.get(&hashes[MAX_CACHED_BLOCKS]).is_some());
+    }
+
+    #[test]
+    fn reinserting_a_block_does_not_grow_the_cache() {
+        let cache = BlockTimestampCache::new();
+        let hash = H256::from_low_u64_be(1);
+        cache.insert(hash, 10);
+        cache.insert(hash, 20);
+        let mut entries = cache.entries.lock().unwrap();
+        assert_eq!(entries.timestamps.len(), 1);
+        assert_eq!(entries.recency.len(), 1);
+        assert_eq!(entries.get(&hash), DateTime::from_timestamp(20, 0).map(|time| time.naive_utc()));
+    }
+}
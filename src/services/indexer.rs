@@ -1,167 +1,424 @@
-use web3::types::{H256, FilterBuilder, BlockNumber, H160, U64};
+use web3::types::{H256, FilterBuilder, BlockNumber, BlockId, H160, U64};
+use web3::Transport;
 use diesel::r2d2::ConnectionManager;
 use diesel::pg::PgConnection;
-use tokio::time::{interval, Duration, sleep};
-use crate::repositories::transfer_repo::TransferRepo;
-use crate::utils::eth::{connect_to_node, decode_transfer_log};
-use crate::models::transfer::NewTransfer;
+use tokio::time::{interval, Duration};
+use crate::repositories::checkpoint_repo::CheckpointRepo;
+use crate::repositories::block_repo::BlockRepo;
+use crate::repositories::token_repo::TokenRepo;
+use crate::repositories::balance_repo::BalanceRepo;
+use crate::repositories::failed_range_repo::FailedRangeRepo;
+use crate::services::log_range::AdaptiveRange;
+use crate::services::pipeline::{address_key, publish_transfers, Batch, BatchSource, DecodedBatch, Pipeline, TokenCursor, MAX_BATCH_ATTEMPTS};
+use crate::services::subscription::{run_subscriptions, SubscribedLogs};
+use crate::utils::transport::FailoverTransport;
+use crate::utils::eth::{fetch_token_metadata, find_deployment_block, fetch_total_supply};
+use crate::models::transfer::{TransferEvent, STATUS_CONFIRMED, STATUS_UNCONFIRMED};
+use crate::models::token::{NewToken, TokenMetadata};
+use crate::models::failed_range::NewFailedRange;
+use futures::stream::{self, StreamExt};
 use log::{info, error, warn};
 use hex;
-use tokio::sync::broadcast;
-use tokio::task;
+use tokio::sync::{broadcast, mpsc};
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
 use std::sync::Arc;
 
-/// Finds the block range containing ERC-20 Transfer events for a token.
-async fn find_transfer_blocks(
-    web3: &web3::Web3<web3::transports::Http>,
-    token_address: H160,
-    transfer_topic: H256,
-) -> Result<(u64, u64), Box<dyn std::error::Error + Send + Sync + 'static>> {
-    let eth = web3.eth();
-    let latest_block = eth.block_number().await?.as_u64();
-    let start_block = 0;
+/// Number of most recent blocks whose hashes are tracked to detect chain reorganizations.
+pub const REORG_WINDOW: u64 = 64;
 
-    info!("Searching for transfer blocks of token 0x{}", hex::encode(token_address.as_bytes()));
+/// Number of blocks per backfill batch, committed together with the checkpoints.
+const BACKFILL_BATCH_SIZE: u64 = 100_000;
+/// Maximum number of failed ranges retried per iteration of the live loop.
+const FAILED_RANGES_PER_ITERATION: i64 = 10;
+/// Delay before the first retry of a failed range, doubled after each failed retry.
+const FAILED_RANGE_RETRY_DELAY_SECS: i64 = 60;
+/// Longest delay between two retries of a failed range.
+const MAX_FAILED_RANGE_RETRY_DELAY_SECS: i64 = 3600;
 
-    let filter = FilterBuilder::default()
-        .address(vec![token_address])
-        .topics(Some(vec![transfer_topic]), None, None, None)
-        .from_block(BlockNumber::Number(U64::from(start_block)))
-        .to_block(BlockNumber::Number(U64::from(latest_block)))
-        .build();
+/// Determines up to which block the chain is considered final by the indexer.
+#[derive(Debug, Clone, Copy)]
+pub enum Finality {
+    /// Blocks at least this many blocks below the head (0 means the head itself).
+    Confirmations(u64),
+    /// Blocks up to the node's `safe` block tag.
+    Safe,
+    /// Blocks up to the node's `finalized` block tag.
+    Finalized,
+}
 
-    let logs = eth.logs(filter).await?;
-    info!("Fetched {} logs from block {} to {}", logs.len(), start_block, latest_block);
+impl FromStr for Finality {
+    type Err = String;
 
-    if logs.is_empty() {
-        info!("No transfers found, starting from latest block {}", latest_block);
-        return Ok((latest_block, latest_block));
+    /// Parses a number of confirmations, `safe` or `finalized`.
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.trim().to_lowercase().as_str() {
+            "safe" => Ok(Finality::Safe),
+            "finalized" => Ok(Finality::Finalized),
+            other => other
+                .parse::<u64>()
+                .map(Finality::Confirmations)
+                .map_err(|_| format!("Invalid finality '{}', expected a number of confirmations, 'safe' or 'finalized'", value)),
+        }
     }
+}
 
-    let blocks_with_logs: Vec<u64> = logs.iter()
-        .filter_map(|log| log.block_number.map(|num| num.as_u64()))
-        .collect();
-    let min_block = blocks_with_logs.iter().min().unwrap_or(&latest_block);
-    let max_block = blocks_with_logs.iter().max().unwrap_or(&latest_block);
+impl fmt::Display for Finality {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Finality::Confirmations(n) => write!(f, "{} confirmations", n),
+            Finality::Safe => write!(f, "safe block"),
+            Finality::Finalized => write!(f, "finalized block"),
+        }
+    }
+}
 
-    info!("Detected transfer blocks range: {} to {}", min_block, max_block);
-    Ok((*min_block, *max_block))
+/// Configuration of the indexer.
+#[derive(Debug, Clone)]
+pub struct IndexerConfig {
+    /// WebSocket endpoint of the node; when set, new blocks are indexed as soon as they are announced.
+    pub node_ws_url: Option<String>,
+    /// Token contracts registered for indexing on startup, in addition to those already registered.
+    pub token_addresses: Vec<String>,
+    /// Blocks the backfill of tokens without a checkpoint starts at, instead of their deployment block.
+    pub start_blocks: HashMap<H160, u64>,
+    /// Blocks up to which transfers are stored as confirmed.
+    pub finality: Finality,
+    /// Whether transfers above the final block are also stored, as unconfirmed.
+    pub index_unconfirmed: bool,
+    /// Number of blocks between two balance snapshots.
+    pub snapshot_interval: u64,
+    /// Maximum number of backfill batches fetched at once.
+    pub backfill_concurrency: usize,
 }
 
-/// Processes a batch of logs and stores transfer events in the database.
-async fn process_logs_batch(
-    web3: &web3::Web3<web3::transports::Http>,
-    transfer_repo: Arc<TransferRepo>,
-    token_address: H160,
-    transfer_topic: H256,
-    from_block: u64,
-    to_block: u64,
-) -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
-    let filter = FilterBuilder::default()
-        .address(vec![token_address])
-        .topics(Some(vec![transfer_topic]), None, None, None)
-        .from_block(BlockNumber::Number(U64::from(from_block)))
-        .to_block(BlockNumber::Number(U64::from(to_block)))
-        .build();
-
-    info!("Fetching logs from block {} to {}", from_block, to_block);
-    let logs = web3.eth().logs(filter).await?;
-    info!("Fetched {} logs from block {} to {}", logs.len(), from_block, to_block);
-
-    for log in logs {
-        info!("Processing log: block_number={:?}, tx_hash={:?}", log.block_number, log.transaction_hash);
-        match decode_transfer_log(log.clone()) {
-            Ok((sender, recipient, amount)) => {
-                let transfer = NewTransfer {
-                    sender,
-                    recipient,
-                    amount,
-                    block_number: log.block_number.unwrap_or_default().as_u64() as i64,
-                    tx_hash: format!("0x{:x}", log.transaction_hash.unwrap_or_default()),
-                };
-                match transfer_repo.insert_transfer(transfer).await {
-                    Ok(_) => info!("Inserted historical transfer: tx_hash={}", log.transaction_hash.unwrap_or_default()),
-                    Err(e) => error!("Failed to insert historical transfer: {}", e),
-                }
-            }
-            Err(e) => error!("Failed to decode historical log: {}", e),
+/// Returns the highest block considered final, given the current head block.
+async fn final_block<T: Transport>(
+    web3: &web3::Web3<T>,
+    finality: Finality,
+    head_block: u64,
+) -> Result<u64, Box<dyn std::error::Error + Send + Sync + 'static>> {
+    let tag = match finality {
+        Finality::Confirmations(n) => return Ok(head_block.saturating_sub(n)),
+        Finality::Safe => BlockNumber::Safe,
+        Finality::Finalized => BlockNumber::Finalized,
+    };
+    let block = web3.eth()
+        .block(BlockId::Number(tag))
+        .await?
+        .ok_or_else(|| format!("Node returned no {} block", finality))?;
+    let number = block.number.ok_or_else(|| format!("The {} block has no number", finality))?;
+    Ok(number.as_u64().min(head_block))
+}
+
+/// Finds the block the backfill of a token starts at: the configured start block, or else the block
+/// its contract was deployed at, cached in the token registry after it is first found. A token whose
+/// contract has no code yet starts at the latest block, and one whose deployment block cannot be
+/// searched starts at block 0.
+async fn find_start_block(
+    web3: &web3::Web3<FailoverTransport>,
+    token_repo: &TokenRepo,
+    config: &IndexerConfig,
+    address: H160,
+    key: &str,
+) -> Result<u64, Box<dyn std::error::Error + Send + Sync + 'static>> {
+    if let Some(start_block) = config.start_blocks.get(&address) {
+        info!("Starting {} from configured block {}", key, start_block);
+        return Ok(*start_block);
+    }
+    if let Some(deployment_block) = token_repo.get_token(key.to_string()).await?.and_then(|token| token.deployment_block) {
+        info!("Starting {} from its cached deployment block {}", key, deployment_block);
+        return Ok(deployment_block as u64);
+    }
+    let latest_block = web3.eth().block_number().await?.as_u64();
+    info!("Searching for the deployment block of token {}", key);
+    match find_deployment_block(web3, address, latest_block).await {
+        Ok(Some(deployment_block)) => {
+            info!("Token {} was deployed at block {}", key, deployment_block);
+            token_repo.update_deployment_block(key.to_string(), deployment_block as i64).await?;
+            Ok(deployment_block)
+        }
+        Ok(None) => {
+            warn!("Token {} has no contract code at block {}, starting from it", key, latest_block);
+            Ok(latest_block)
+        }
+        // Nodes without the historical state cannot read the code at past blocks.
+        Err(e) => {
+            warn!(
+                "Could not search for the deployment block of token {}, starting from block 0 (set TOKEN_START_BLOCKS to skip the scan): {}",
+                key, e
+            );
+            Ok(0)
         }
     }
-    Ok(())
 }
 
-/// Backfills historical transfer events into the database within a block range.
+/// Backfills historical transfer events of a set of tokens into the database up to a block.
+///
+/// Each batch fetches the logs of every token whose cursor is within or before the batch with a
+/// single filter. Up to `concurrency` batches go through the pipeline at once but are written in
+/// block order, each together with the checkpoints, so a persisted cursor never skips over a range
+/// that was not stored. The hashes of the last `REORG_WINDOW` blocks are tracked, so the live loop
+/// detects reorganizations right away. A batch failing every attempt is recorded as a failed range,
+/// retried by the live loop. Returns the last block that was fully processed for every token.
 async fn backfill_transfers(
-    pool: diesel::r2d2::Pool<ConnectionManager<PgConnection>>,
-    web3: web3::Web3<web3::transports::Http>,
-    token_address: String,
-    start_block: u64,
+    pipeline: Arc<Pipeline>,
+    failed_range_repo: &FailedRangeRepo,
+    chain_id: i64,
+    cursors: &[TokenCursor],
     end_block: u64,
-) -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
-    let eth = web3.eth();
-    let token_address_h160 = token_address.parse::<H160>()?;
-    let transfer_topic = H256::from_slice(
-        &hex::decode("ddf252ad1be2c89b69c2b068fc378daa952ba7f163c4a11628f55a4df523b3ef")?, // ERC-20 Transfer event topic hash.
+    concurrency: usize,
+) -> Result<u64, Box<dyn std::error::Error + Send + Sync + 'static>> {
+    let start_block = cursors.iter().map(|cursor| cursor.next_block).min().unwrap_or(end_block + 1);
+    info!(
+        "Backfilling transfers of {} tokens from block {} to {}, {} batches at a time",
+        cursors.len(), start_block, end_block, concurrency
     );
-    let transfer_repo = Arc::new(TransferRepo::new(pool));
-    let latest_block = eth.block_number().await?.as_u64();
-    let actual_end_block = end_block.min(latest_block);
-    info!("Backfilling transfers from block {} to {}", start_block, actual_end_block);
-
-    let mut tasks = Vec::new();
-    const BATCH_SIZE: u64 = 100_000; // Process blocks in batches to avoid overloading the node.
 
+    let mut batches = Vec::new();
     let mut from_block = start_block;
-    while from_block <= actual_end_block {
-        let to_block = (from_block + BATCH_SIZE - 1).min(actual_end_block);
-        let web3_clone = web3.clone();
-        let transfer_repo_clone = Arc::clone(&transfer_repo);
-        let token_address_h160_clone = token_address_h160;
-        let transfer_topic_clone = transfer_topic;
-
-        let task = task::spawn(async move {
-            if let Err(e) = process_logs_batch(
-                &web3_clone,
-                transfer_repo_clone,
-                token_address_h160_clone,
-                transfer_topic_clone,
-                from_block,
-                to_block,
-            ).await {
-                error!("Error processing batch {} to {}: {}", from_block, to_block, e);
-            }
-        });
-        tasks.push(task);
-
+    while from_block <= end_block {
+        let to_block = (from_block + BACKFILL_BATCH_SIZE - 1).min(end_block);
+        batches.push(Batch::new(from_block, to_block, cursors));
         from_block = to_block + 1;
     }
 
-    for task in tasks {
-        task.await?;
+    // Shared by the batches, so a range limit of the node is learned once.
+    let range = Arc::new(AdaptiveRange::new(BACKFILL_BATCH_SIZE));
+    let track_from = Some(end_block.saturating_sub(REORG_WINDOW));
+    let mut results = stream::iter(batches)
+        .map(|batch| {
+            let (pipeline, range) = (pipeline.clone(), range.clone());
+            async move {
+                let result = pipeline.process(&range, &batch, None, track_from, STATUS_CONFIRMED).await;
+                (batch, result)
+            }
+        })
+        .buffered(concurrency);
+
+    let mut last_block = start_block.saturating_sub(1);
+    while let Some((batch, result)) = results.next().await {
+        // A batch that cannot be stored is recorded like one that cannot be fetched, so the backfill goes on.
+        let written = match result {
+            Ok(decoded) => pipeline.write(&batch, decoded, BatchSource::Backfill).await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = written {
+            error!("Giving up batch {} to {}, recording it for a later retry: {}", batch.from_block, batch.to_block, e);
+            let ranges = batch.tokens.iter()
+                .map(|cursor| NewFailedRange {
+                    chain_id,
+                    token_address: cursor.key.clone(),
+                    from_block: batch.from_block.max(cursor.next_block) as i64,
+                    to_block: batch.to_block as i64,
+                    attempts: MAX_BATCH_ATTEMPTS as i32,
+                    last_error: e.to_string(),
+                })
+                .collect();
+            failed_range_repo.record_failed_ranges(ranges, pipeline.checkpoints(&batch)).await?;
+        }
+        last_block = batch.to_block;
     }
 
-    info!("Backfill completed up to block {}", latest_block);
+    info!("Backfill completed up to block {}", last_block);
+    Ok(last_block)
+}
+
+/// Retries the failed ranges that are due through the pipeline, storing the transfers of those that
+/// succeed and postponing the others with an exponential backoff.
+async fn retry_failed_ranges(
+    pipeline: &Pipeline,
+    range: &AdaptiveRange,
+    failed_range_repo: &FailedRangeRepo,
+    chain_id: i64,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
+    for failed_range in failed_range_repo.get_due_ranges(chain_id, FAILED_RANGES_PER_ITERATION).await? {
+        let (from_block, to_block) = (failed_range.from_block as u64, failed_range.to_block as u64);
+        info!("Retrying failed range {} to {} of token {}", from_block, to_block, failed_range.token_address);
+        let address = failed_range.token_address.parse::<H160>()?;
+        let batch = Batch {
+            from_block,
+            to_block,
+            tokens: vec![TokenCursor { address, key: failed_range.token_address.clone(), next_block: from_block }],
+        };
+        match pipeline.process(range, &batch, None, None, STATUS_CONFIRMED).await {
+            Ok(decoded) => {
+                pipeline.write(&batch, decoded, BatchSource::FailedRange).await?;
+                failed_range_repo.mark_resolved(failed_range).await?;
+            }
+            Err(e) => {
+                let exponent = (failed_range.attempts - MAX_BATCH_ATTEMPTS as i32).clamp(0, 20) as u32;
+                let retry_in_secs = (FAILED_RANGE_RETRY_DELAY_SECS << exponent).min(MAX_FAILED_RANGE_RETRY_DELAY_SECS);
+                warn!("Failed range {} to {} failed again, retrying in {} seconds: {}", from_block, to_block, retry_in_secs, e);
+                failed_range_repo.record_retry_failure(failed_range.id, e.to_string(), retry_in_secs).await?;
+            }
+        }
+    }
     Ok(())
 }
 
-/// Starts the indexer to monitor and store ERC-20 Transfer events.
+/// Compares the tracked block hashes, highest block first, with the canonical chain, walking back
+/// from the last indexed block. Returns the highest block still on the canonical chain if a
+/// reorganization happened, or an error if none of the tracked blocks is.
+async fn detect_reorg<T: Transport>(
+    web3: &web3::Web3<T>,
+    tracked_hashes: &[(u64, String)],
+    last_block: u64,
+) -> Result<Option<u64>, Box<dyn std::error::Error + Send + Sync + 'static>> {
+    // Nothing tracked at the head: no hashes to compare against.
+    if tracked_hashes.first().map(|(block_number, _)| *block_number) != Some(last_block) {
+        return Ok(None);
+    }
+    for (block_number, stored_hash) in tracked_hashes {
+        let canonical_hash = web3.eth()
+            .block(BlockId::Number(BlockNumber::Number(U64::from(*block_number))))
+            .await?
+            .and_then(|block| block.hash)
+            .map(|hash| format!("0x{:x}", hash));
+        if canonical_hash.as_deref() == Some(stored_hash.as_str()) {
+            return Ok(if *block_number == last_block { None } else { Some(*block_number) });
+        }
+    }
+    // The fork is below every tracked block, so the blocks under them may be orphaned too.
+    Err(format!(
+        "Chain reorganization deeper than the {} tracked blocks: no indexed block from {} to {} is on the canonical chain, reindexing is required",
+        tracked_hashes.len(),
+        tracked_hashes[tracked_hashes.len() - 1].0,
+        last_block,
+    ).into())
+}
+
+/// Rolls the index back to the fork block when the tracked blocks up to `last_block` are no longer on
+/// the canonical chain, publishing the removed confirmed transfers. Returns the fork block, if any.
+async fn roll_back_reorg(
+    web3: &web3::Web3<FailoverTransport>,
+    block_repo: &BlockRepo,
+    transfer_tx: &broadcast::Sender<TransferEvent>,
+    chain_id: i64,
+    last_block: u64,
+) -> Result<Option<u64>, Box<dyn std::error::Error + Send + Sync + 'static>> {
+    let tracked_hashes: Vec<(u64, String)> = block_repo.get_block_hashes(chain_id, last_block as i64).await?
+        .into_iter()
+        .map(|(block_number, hash)| (block_number as u64, hash))
+        .collect();
+    let Some(fork_block) = detect_reorg(web3, &tracked_hashes, last_block).await? else {
+        return Ok(None);
+    };
+    let depth = last_block - fork_block;
+    warn!("Chain reorganization detected: rolling back {} blocks to block {}", depth, fork_block);
+    let removed = block_repo.rollback_to_block(chain_id, fork_block as i64, depth as i64).await?;
+    info!("Removed {} orphaned transfers above block {}", removed.len(), fork_block);
+    let removed = removed.into_iter().filter(|transfer| transfer.status == STATUS_CONFIRMED).collect();
+    publish_transfers(transfer_tx, removed, TransferEvent::Removed);
+    Ok(Some(fork_block))
+}
+
+/// Starts the indexer to monitor and store ERC-20 Transfer events through the given node client,
+/// publishing each newly stored confirmed transfer and each confirmed transfer removed by a
+/// reorganization on `transfer_tx`.
 pub async fn start_indexing(
     pool: diesel::r2d2::Pool<ConnectionManager<PgConnection>>,
-    node_url: String,
-    token_address: String,
+    web3: web3::Web3<FailoverTransport>,
+    config: IndexerConfig,
+    transfer_tx: broadcast::Sender<TransferEvent>,
     mut shutdown_rx: broadcast::Receiver<()>,
 ) -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
-    let web3 = connect_to_node(&node_url).await?;
     let eth = web3.eth();
-    let token_address_h160 = token_address.parse::<H160>()?;
-    info!("Monitoring token address: 0x{}", hex::encode(token_address_h160.as_bytes()));
+    info!("Indexing transfers up to the {}", config.finality);
     let transfer_topic = H256::from_slice(
         &hex::decode("ddf252ad1be2c89b69c2b068fc378daa952ba7f163c4a11628f55a4df523b3ef")?, // ERC-20 Transfer event topic hash.
     );
-    let transfer_repo = Arc::new(TransferRepo::new(pool.clone()));
-    let (min_block, max_block) = find_transfer_blocks(&web3, token_address_h160, transfer_topic).await?;
-    backfill_transfers(pool.clone(), web3.clone(), token_address.clone(), min_block, max_block).await?;
-    let mut last_block = eth.block_number().await?.as_u64();
+    let chain_id = eth.chain_id().await?.as_u64() as i64;
+    let checkpoint_repo = CheckpointRepo::new(pool.clone());
+    let block_repo = BlockRepo::new(pool.clone());
+    let token_repo = TokenRepo::new(pool.clone());
+    let balance_repo = BalanceRepo::new(pool.clone());
+    let failed_range_repo = FailedRangeRepo::new(pool.clone());
+    let pipeline = Pipeline::new(pool.clone(), web3.clone(), transfer_tx.clone(), chain_id, transfer_topic);
+
+    // Register the configured tokens, then index every token of the registry.
+    let mut new_tokens = Vec::new();
+    for token_address in &config.token_addresses {
+        new_tokens.push(NewToken { address: address_key(token_address.parse::<H160>()?) });
+    }
+    token_repo.register_tokens(new_tokens).await?;
+    let metadata_block = eth.block_number().await?.as_u64();
+    let mut cursors = Vec::new();
+    let mut indexed_block = None;
+    for key in token_repo.get_token_addresses().await? {
+        let address = key.parse::<H160>()?;
+        info!("Monitoring token address: {}", key);
+        let (name, symbol, decimals) = fetch_token_metadata(&web3, address, metadata_block).await;
+        info!("Token {} metadata: name={:?}, symbol={:?}, decimals={:?}", key, name, symbol, decimals);
+        if name.is_none() && symbol.is_none() && decimals.is_none() {
+            warn!("Could not read metadata of token {}, keeping cached values", key);
+        } else {
+            let metadata = TokenMetadata {
+                name,
+                symbol,
+                decimals: decimals.map(i16::from),
+            };
+            if let Err(e) = token_repo.update_metadata(key.clone(), metadata).await {
+                error!("Failed to cache metadata of token {}: {}", key, e);
+            }
+        }
+        // Resume right after the persisted checkpoint, or start from the deployment block on a fresh start.
+        let next_block = match checkpoint_repo.get_last_block(chain_id, key.clone()).await? {
+            Some(checkpoint_block) => {
+                info!("Resuming {} from checkpoint at block {}", key, checkpoint_block);
+                indexed_block = indexed_block.max(Some(checkpoint_block as u64));
+                checkpoint_block as u64 + 1
+            }
+            None => find_start_block(&web3, &token_repo, &config, address, &key).await?,
+        };
+        cursors.push(TokenCursor { address, key, next_block });
+    }
+    if cursors.is_empty() {
+        return Err("No token registered for indexing".into());
+    }
+    // Roll back the blocks orphaned while the indexer was stopped, before resuming on the canonical chain.
+    if let Some(indexed_block) = indexed_block {
+        if let Some(fork_block) = roll_back_reorg(&web3, &block_repo, &transfer_tx, chain_id, indexed_block).await? {
+            for cursor in &mut cursors {
+                cursor.next_block = cursor.next_block.min(fork_block + 1);
+            }
+        }
+    }
+    let token_addresses: Vec<H160> = cursors.iter().map(|cursor| cursor.address).collect();
+
+    let start_block = cursors.iter().map(|cursor| cursor.next_block).min().unwrap_or_default();
+    let latest_block = final_block(&web3, config.finality, eth.block_number().await?.as_u64()).await?;
+    let mut last_block = if start_block <= latest_block {
+        backfill_transfers(
+            pipeline.clone(),
+            &failed_range_repo,
+            chain_id,
+            &cursors,
+            latest_block,
+            config.backfill_concurrency,
+        ).await?
+    } else {
+        start_block - 1
+    };
+    let mut supply_block = None;
+
+    // Subscribe to new heads and transfer logs when a WebSocket endpoint is configured.
+    let (events_tx, mut events_rx) = mpsc::channel(1024);
+    if let Some(ws_url) = config.node_ws_url.clone() {
+        let filter = FilterBuilder::default()
+            .address(token_addresses.clone())
+            .topics(Some(vec![transfer_topic]), None, None, None)
+            .build();
+        tokio::spawn(run_subscriptions(ws_url, filter, events_tx));
+    } else {
+        drop(events_tx);
+    }
+    let mut subscribed = SubscribedLogs::new();
+    const BATCH_SIZE: u64 = 100; // Process smaller batches for real-time indexing.
+    let live_range = AdaptiveRange::new(BATCH_SIZE);
+    let retry_range = AdaptiveRange::new(BACKFILL_BATCH_SIZE);
+
     let mut interval = interval(Duration::from_secs(5)); // Check for new blocks every 5 seconds.
     loop {
         tokio::select! {
@@ -170,69 +427,283 @@ pub async fn start_indexing(
                 break;
             }
             _ = interval.tick() => {
-                info!("Starting indexing loop iteration");
-                info!("Checking for new blocks...");
-                let latest_block = match eth.block_number().await {
-                    Ok(block) => block.as_u64(),
-                    Err(e) => {
-                        error!("Failed to get latest block: {}", e);
-                        continue;
-                    }
-                };
-                info!("Current latest block: {}", latest_block);
-                if latest_block <= last_block {
-                    info!("No new blocks to process (latest: {}, last: {})", latest_block, last_block);
+                // Blocks are only polled while the subscription is down.
+                if subscribed.is_live() {
                     continue;
                 }
-                let mut from_block = last_block + 1;
-                let to_block = latest_block;
-                const BATCH_SIZE: u64 = 100; // Process smaller batches for real-time indexing.
-                while from_block <= to_block {
-                    let batch_end = (from_block + BATCH_SIZE - 1).min(to_block);
-                    let filter = FilterBuilder::default()
-                        .address(vec![token_address_h160])
-                        .topics(Some(vec![transfer_topic]), None, None, None)
-                        .from_block(BlockNumber::Number(U64::from(from_block)))
-                        .to_block(BlockNumber::Number(U64::from(batch_end)))
-                        .build();
-                    info!("Fetching logs from block {} to {}", from_block, batch_end);
-                    let logs = match eth.logs(filter).await {
-                        Ok(logs) => logs,
-                        Err(e) => {
-                            error!("Failed to fetch logs from {} to {}: {}", from_block, batch_end, e);
-                            if e.to_string().contains("rate limit") {
-                                warn!("Rate limit hit, waiting 10 seconds...");
-                                sleep(Duration::from_secs(10)).await;
-                            }
-                            break;
-                        }
-                    };
-                    info!("Fetched {} logs from block {} to {}", logs.len(), from_block, batch_end);
-                    for log in logs {
-                        match decode_transfer_log(log.clone()) {
-                            Ok((sender, recipient, amount)) => {
-                                let transfer = NewTransfer {
-                                    sender,
-                                    recipient,
-                                    amount,
-                                    block_number: log.block_number.unwrap_or_default().as_u64() as i64,
-                                    tx_hash: format!("0x{:x}", log.transaction_hash.unwrap_or_default()),
-                                };
-                                if let Err(e) = transfer_repo.insert_transfer(transfer).await {
-                                    error!("Failed to insert transfer: {}", e);
-                                } else {
-                                    info!("Inserted transfer: tx_hash={}", log.transaction_hash.unwrap_or_default());
-                                }
-                            }
-                            Err(e) => error!("Failed to decode log: {}", e),
-                        }
-                    }
-                    from_block = batch_end + 1;
+            }
+            Some(event) = events_rx.recv() => {
+                // Apply every queued event, so a burst of heads is indexed at once.
+                let mut new_head = subscribed.apply(event);
+                while let Ok(event) = events_rx.try_recv() {
+                    new_head |= subscribed.apply(event);
+                }
+                if !new_head {
+                    continue;
                 }
-                last_block = to_block;
             }
         }
+        info!("Starting indexing loop iteration");
+        info!("Checking for new blocks...");
+        let head_block = match eth.block_number().await {
+            Ok(block) => block.as_u64(),
+            Err(e) => {
+                error!("Failed to get latest block: {}", e);
+                continue;
+            }
+        };
+        let latest_block = match final_block(&web3, config.finality, head_block).await {
+            Ok(block) => block,
+            Err(e) => {
+                error!("Failed to get final block: {}", e);
+                continue;
+            }
+        };
+        info!("Current latest block: {} (final up to {})", head_block, latest_block);
+        match roll_back_reorg(&web3, &block_repo, &transfer_tx, chain_id, last_block).await {
+            Ok(Some(fork_block)) => {
+                last_block = fork_block;
+                // Re-fetch the canonical logs rather than relying on removal notices.
+                subscribed.reset();
+            }
+            Ok(None) => {}
+            Err(e) => {
+                error!("Failed to check for chain reorganization: {}", e);
+                continue;
+            }
+        }
+        if latest_block <= last_block {
+            info!("No new final blocks to process (latest: {}, last: {})", latest_block, last_block);
+        }
+        // Track hashes of blocks close to the head, where reorganizations can still happen.
+        let track_from = Some(latest_block.saturating_sub(REORG_WINDOW));
+        let mut from_block = last_block + 1;
+        while from_block <= latest_block {
+            let batch = Batch::new(from_block, (from_block + BATCH_SIZE - 1).min(latest_block), &cursors);
+            let logs = subscribed.logs_between(batch.from_block, batch.to_block);
+            let written = match pipeline.process(&live_range, &batch, logs, track_from, STATUS_CONFIRMED).await {
+                Ok(decoded) => pipeline.write(&batch, decoded, BatchSource::Live).await,
+                Err(e) => Err(e),
+            };
+            if let Err(e) = written {
+                error!("Failed to index blocks {} to {}, retrying on next poll: {}", batch.from_block, batch.to_block, e);
+                subscribed.reset();
+                break;
+            }
+            subscribed.prune(batch.to_block);
+            last_block = batch.to_block;
+            from_block = batch.to_block + 1;
+        }
+        // Refresh the unconfirmed tier once every final block has been stored.
+        if config.index_unconfirmed && last_block == latest_block {
+            let batch = Batch::new(last_block + 1, head_block, &cursors);
+            let unconfirmed = if head_block > last_block {
+                pipeline.process(&live_range, &batch, None, None, STATUS_UNCONFIRMED).await
+            } else {
+                Ok(DecodedBatch { transfers: Vec::new(), blocks: Vec::new() })
+            };
+            let stored = match unconfirmed {
+                Ok(decoded) => pipeline.write_unconfirmed(decoded, last_block).await,
+                Err(e) => Err(e),
+            };
+            if let Err(e) = stored {
+                error!("Failed to store unconfirmed transfers above block {}: {}", last_block, e);
+            }
+        }
+        // Fill the gaps left by backfill batches that failed, before their snapshots are recorded again.
+        if let Err(e) = retry_failed_ranges(&pipeline, &retry_range, &failed_range_repo, chain_id).await {
+            error!("Failed to retry failed ranges: {}", e);
+        }
+        // Read the total supply at the last stored block, to cross-check it with the indexed mints and burns.
+        if supply_block != Some(last_block) {
+            supply_block = Some(last_block);
+            for cursor in &cursors {
+                let Some(total_supply) = fetch_total_supply(&web3, cursor.address, last_block).await else {
+                    warn!("Could not read the total supply of token {} at block {}", cursor.key, last_block);
+                    continue;
+                };
+                if let Err(e) = token_repo.update_total_supply(cursor.key.clone(), total_supply.to_string(), last_block as i64).await {
+                    error!("Failed to cache the total supply of token {}: {}", cursor.key, e);
+                }
+            }
+        }
+        for cursor in &cursors {
+            match balance_repo.take_snapshots(cursor.key.clone(), last_block as i64, config.snapshot_interval as i64).await {
+                Ok(0) => {}
+                Ok(recorded) => info!("Recorded {} balance snapshots of token {} up to block {}", recorded, cursor.key, last_block),
+                Err(e) => error!("Failed to record balance snapshots of token {}: {}", cursor.key, e),
+            }
+        }
+        if let Err(e) = block_repo.prune_blocks(chain_id, last_block.saturating_sub(REORG_WINDOW) as i64).await {
+            error!("Failed to prune tracked blocks: {}", e);
+        }
     }
     info!("Indexer stopped successfully.");
     Ok(())
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::future::{ready, Ready};
+    use jsonrpc_core::{Call, MethodCall, Params};
+    use serde_json::{json, Value};
+    use std::sync::Mutex;
+    use web3::RequestId;
+
+    /// Answer of the stub node to the params of a call.
+    type Respond = dyn Fn(&[Value]) -> Value + Send + Sync;
+
+    /// Node answering every call from its params and recording the params of the calls.
+    #[derive(Clone)]
+    struct StubNode {
+        respond: Arc<Respond>,
+        params: Arc<Mutex<Vec<Vec<Value>>>>,
+    }
+
+    impl StubNode {
+        fn new(result: Value) -> Self {
+            Self::responding(move |_| result.clone())
+        }
+
+        fn responding(respond: impl Fn(&[Value]) -> Value + Send + Sync + 'static) -> Self {
+            StubNode { respond: Arc::new(respond), params: Arc::new(Mutex::new(Vec::new())) }
+        }
+    }
+
+    impl fmt::Debug for StubNode {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.debug_struct("StubNode").field("params", &self.params).finish()
+        }
+    }
+
+    impl Transport for StubNode {
+        type Out = Ready<web3::error::Result<Value>>;
+
+        fn prepare(&self, method: &str, params: Vec<Value>) -> (RequestId, Call) {
+            self.params.lock().unwrap().push(params.clone());
+            (1, web3::helpers::build_request(1, method, params))
+        }
+
+        fn send(&self, _id: RequestId, request: Call) -> Self::Out {
+            let params = match request {
+                Call::MethodCall(MethodCall { params: Params::Array(params), .. }) => params,
+                _ => Vec::new(),
+            };
+            ready(Ok((self.respond)(&params)))
+        }
+    }
+
+    fn block(number: u64) -> Value {
+        let zero = format!("0x{}", "0".repeat(64));
+        json!({
+            "number": format!("0x{:x}", number), "hash": zero, "parentHash": zero, "nonce": "0x0000000000000000",
+            "sha3Uncles": zero, "logsBloom": format!("0x{}", "0".repeat(512)), "transactionsRoot": zero,
+            "stateRoot": zero, "receiptsRoot": zero, "miner": format!("0x{}", "0".repeat(40)),
+            "difficulty": "0x0", "totalDifficulty": "0x0", "extraData": "0x", "size": "0x0", "gasLimit": "0x0",
+            "gasUsed": "0x0", "timestamp": "0x0", "transactions": [], "uncles": [], "baseFeePerGas": "0x0", "mixHash": zero,
+        })
+    }
+
+    #[test]
+    fn finality_is_parsed() {
+        assert!(matches!("12".parse::<Finality>(), Ok(Finality::Confirmations(12))));
+        assert!(matches!(" 0 ".parse::<Finality>(), Ok(Finality::Confirmations(0))));
+        assert!(matches!("safe".parse::<Finality>(), Ok(Finality::Safe)));
+        assert!(matches!("Finalized".parse::<Finality>(), Ok(Finality::Finalized)));
+        assert!("-1".parse::<Finality>().is_err());
+        assert!("latest".parse::<Finality>().is_err());
+        assert!("".parse::<Finality>().is_err());
+    }
+
+    #[tokio::test]
+    async fn confirmations_are_counted_below_the_head() {
+        let node = StubNode::new(Value::Null);
+        let web3 = web3::Web3::new(node.clone());
+        assert_eq!(final_block(&web3, Finality::Confirmations(12), 100).await.unwrap(), 88);
+        assert_eq!(final_block(&web3, Finality::Confirmations(0), 100).await.unwrap(), 100);
+        assert_eq!(final_block(&web3, Finality::Confirmations(12), 12).await.unwrap(), 0);
+        // Near genesis, the head is below the confirmation depth.
+        assert_eq!(final_block(&web3, Finality::Confirmations(12), 5).await.unwrap(), 0);
+        assert!(node.params.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn block_tags_are_read_from_the_node() {
+        let node = StubNode::new(block(90));
+        let web3 = web3::Web3::new(node.clone());
+        assert_eq!(final_block(&web3, Finality::Safe, 100).await.unwrap(), 90);
+        assert_eq!(final_block(&web3, Finality::Finalized, 100).await.unwrap(), 90);
+        // A tag ahead of the polled head, e.g. from another endpoint, is capped to the head.
+        assert_eq!(final_block(&web3, Finality::Safe, 80).await.unwrap(), 80);
+        let params = node.params.lock().unwrap().clone();
+        assert_eq!(params[0][0], json!("safe"));
+        assert_eq!(params[1][0], json!("finalized"));
+
+        let web3 = web3::Web3::new(StubNode::new(Value::Null));
+        assert!(final_block(&web3, Finality::Finalized, 100).await.is_err());
+    }
+
+    /// Node whose canonical block `n` has the hash `canonical_hash(n)`.
+    fn canonical_node() -> StubNode {
+        StubNode::responding(|params| {
+            let number = u64::from_str_radix(params[0].as_str().unwrap().trim_start_matches("0x"), 16).unwrap();
+            let mut block = block(number);
+            block["hash"] = json!(canonical_hash(number));
+            block
+        })
+    }
+
+    fn canonical_hash(block_number: u64) -> String {
+        format!("0x{:x}", H256::from_low_u64_be(block_number))
+    }
+
+    fn orphaned_hash(block_number: u64) -> String {
+        format!("0x{:x}", H256::from_low_u64_be(block_number + 1_000_000))
+    }
+
+    /// Tracked hashes of blocks 90 to 100, highest first, orphaned from `orphaned_from` upwards.
+    fn tracked_hashes(orphaned_from: u64) -> Vec<(u64, String)> {
+        (90..=100).rev()
+            .map(|block_number| {
+                let hash = if block_number >= orphaned_from { orphaned_hash(block_number) } else { canonical_hash(block_number) };
+                (block_number, hash)
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn canonical_head_is_not_a_reorg() {
+        let node = canonical_node();
+        let web3 = web3::Web3::new(node.clone());
+        assert_eq!(detect_reorg(&web3, &tracked_hashes(101), 100).await.unwrap(), None);
+        assert_eq!(node.params.lock().unwrap().len(), 1);
+
+        // Without a tracked hash at the last indexed block, there is nothing to compare.
+        assert_eq!(detect_reorg(&web3, &tracked_hashes(101)[1..], 100).await.unwrap(), None);
+        assert_eq!(detect_reorg(&web3, &[], 100).await.unwrap(), None);
+        assert_eq!(node.params.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn reorg_walks_back_to_the_fork_block() {
+        let node = canonical_node();
+        let web3 = web3::Web3::new(node.clone());
+        assert_eq!(detect_reorg(&web3, &tracked_hashes(98), 100).await.unwrap(), Some(97));
+        let params = node.params.lock().unwrap().clone();
+        let requested: Vec<&Value> = params.iter().map(|params| &params[0]).collect();
+        assert_eq!(requested, [&json!("0x64"), &json!("0x63"), &json!("0x62"), &json!("0x61")]);
+
+        assert_eq!(detect_reorg(&web3, &tracked_hashes(100), 100).await.unwrap(), Some(99));
+    }
+
+    #[tokio::test]
+    async fn reorg_below_the_tracked_blocks_is_an_error() {
+        let web3 = web3::Web3::new(canonical_node());
+        let error = detect_reorg(&web3, &tracked_hashes(90), 100).await.unwrap_err();
+        assert!(error.to_string().contains("no indexed block from 90 to 100"), "{}", error);
+
+        // A block missing on the node does not count as the fork block either.
+        let web3 = web3::Web3::new(StubNode::new(Value::Null));
+        assert!(detect_reorg(&web3, &tracked_hashes(101), 100).await.is_err());
+    }
+}
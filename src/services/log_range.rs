@@ -0,0 +1,240 @@
+use log::{info, warn};
+use std::future::Future;
+use std::sync::Mutex;
+use web3::types::{BlockNumber, FilterBuilder, Log, H160, H256, U64};
+use crate::utils::transport::FailoverTransport;
+
+/// Number of consecutive full-size requests that must succeed before the range is doubled.
+const GROW_AFTER_SUCCESSES: u32 = 4;
+
+/// Fragments of the errors returned by nodes when an `eth_getLogs` request spans too many blocks
+/// or matches too many logs.
+const RANGE_ERROR_PATTERNS: [&str; 11] = [
+    "more than 10000 results",
+    "query returned more than",
+    "response size exceeded",
+    "response size should not",
+    "block range is too wide",
+    "exceed maximum block range",
+    "range too large",
+    "range is too large",
+    "is limited to a",
+    "too many results",
+    "too many logs",
+];
+
+/// Block range of `eth_getLogs` requests, halved when the node rejects a range as too large and
+/// doubled again, up to its maximum, after a series of successful requests.
+#[derive(Debug)]
+pub struct AdaptiveRange {
+    max_size: u64,
+    state: Mutex<RangeState>,
+}
+
+#[derive(Debug)]
+struct RangeState {
+    size: u64,
+    successes: u32,
+}
+
+impl AdaptiveRange {
+    /// Creates a range starting at its maximum number of blocks.
+    pub fn new(max_size: u64) -> Self {
+        AdaptiveRange {
+            max_size,
+            state: Mutex::new(RangeState { size: max_size, successes: 0 }),
+        }
+    }
+
+    /// Current number of blocks per request.
+    pub fn size(&self) -> u64 {
+        self.state.lock().unwrap().size
+    }
+
+    /// Halves the range after a request of the given number of blocks was rejected as too large.
+    /// Returns the new size.
+    fn shrink(&self, rejected_size: u64) -> u64 {
+        let mut state = self.state.lock().unwrap();
+        state.size = state.size.min((rejected_size / 2).max(1));
+        state.successes = 0;
+        state.size
+    }
+
+    /// Records a successful request of the given number of blocks, doubling the range once enough
+    /// requests of the current size succeeded in a row.
+    fn record_success(&self, size: u64) {
+        let mut state = self.state.lock().unwrap();
+        if size < state.size || state.size >= self.max_size {
+            return;
+        }
+        state.successes += 1;
+        if state.successes >= GROW_AFTER_SUCCESSES {
+            state.size = (state.size * 2).min(self.max_size);
+            state.successes = 0;
+            info!("Increased the eth_getLogs block range to {} blocks", state.size);
+        }
+    }
+}
+
+/// Whether an error means that an `eth_getLogs` request covered too many blocks or logs, rather
+/// than a failure of the node or of the connection.
+pub fn is_range_error(error: &web3::Error) -> bool {
+    let message = error.to_string().to_lowercase();
+    RANGE_ERROR_PATTERNS.iter().any(|pattern| message.contains(pattern))
+}
+
+/// Fetches the transfer logs of a set of tokens over a block range, in requests sized by the
+/// adaptive range. A request rejected as too large is split in halves until it succeeds, so no
+/// block of the range is skipped; other errors are returned.
+pub async fn fetch_logs(
+    web3: &web3::Web3<FailoverTransport>,
+    range: &AdaptiveRange,
+    token_addresses: &[H160],
+    transfer_topic: H256,
+    from_block: u64,
+    to_block: u64,
+) -> Result<Vec<Log>, web3::Error> {
+    fetch_in_ranges(range, from_block, to_block, |from, to| {
+        let filter = FilterBuilder::default()
+            .address(token_addresses.to_vec())
+            .topics(Some(vec![transfer_topic]), None, None, None)
+            .from_block(BlockNumber::Number(U64::from(from)))
+            .to_block(BlockNumber::Number(U64::from(to)))
+            .build();
+        web3.eth().logs(filter)
+    })
+    .await
+}
+
+/// Fetches a block range in requests sized by the adaptive range, splitting the requests rejected
+/// as too large.
+async fn fetch_in_ranges<T, F, Fut>(range: &AdaptiveRange, from_block: u64, to_block: u64, fetch: F) -> Result<Vec<T>, web3::Error>
+where
+    F: Fn(u64, u64) -> Fut,
+    Fut: Future<Output = Result<Vec<T>, web3::Error>>,
+{
+    let mut items = Vec::new();
+    let mut from = from_block;
+    let mut size = range.size();
+    while from <= to_block {
+        let to = from.saturating_add(size - 1).min(to_block);
+        match fetch(from, to).await {
+            Ok(fetched) => {
+                range.record_success(to - from + 1);
+                items.extend(fetched);
+                from = to + 1;
+                size = range.size();
+            }
+            Err(e) if to > from && is_range_error(&e) => {
+                size = range.shrink(to - from + 1);
+                warn!("Node rejected eth_getLogs from block {} to {} ({}), retrying with {} blocks", from, to, e, size);
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(items)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+
+    fn rpc_error(message: &str) -> web3::Error {
+        web3::Error::Rpc(jsonrpc_core::Error {
+            code: jsonrpc_core::ErrorCode::ServerError(-32005),
+            message: message.to_string(),
+            data: None,
+        })
+    }
+
+    /// Fetches each block of a request as an item, rejecting requests of more than `limit` blocks.
+    fn limited_fetch(
+        limit: u64,
+        requests: &RefCell<Vec<(u64, u64)>>,
+    ) -> impl Fn(u64, u64) -> std::future::Ready<Result<Vec<u64>, web3::Error>> + '_ {
+        move |from, to| {
+            requests.borrow_mut().push((from, to));
+            std::future::ready(if to - from + 1 > limit {
+                Err(rpc_error("query returned more than 10000 results"))
+            } else {
+                Ok((from..=to).collect())
+            })
+        }
+    }
+
+    #[test]
+    fn range_errors_are_recognised() {
+        assert!(is_range_error(&rpc_error("query returned more than 10000 results")));
+        assert!(is_range_error(&rpc_error("Block range is too wide")));
+        assert!(is_range_error(&rpc_error("exceed maximum block range: 5000")));
+        assert!(is_range_error(&rpc_error("eth_getLogs is limited to a 10,000 range")));
+        assert!(!is_range_error(&rpc_error("invalid block range params")));
+        assert!(!is_range_error(&rpc_error("header not found")));
+    }
+
+    #[test]
+    fn range_shrinks_to_a_floor_and_grows_back() {
+        let range = AdaptiveRange::new(16);
+        assert_eq!(range.shrink(16), 8);
+        // A stale rejection of a larger request does not grow the range.
+        assert_eq!(range.shrink(32), 8);
+        assert_eq!(range.shrink(1), 1);
+        assert_eq!(range.shrink(1), 1);
+
+        // Smaller requests than the current size do not count towards growing it.
+        range.record_success(0);
+        for _ in 0..GROW_AFTER_SUCCESSES - 1 {
+            range.record_success(1);
+        }
+        assert_eq!(range.size(), 1);
+        range.record_success(1);
+        assert_eq!(range.size(), 2);
+
+        for size in [2, 4, 8] {
+            for _ in 0..GROW_AFTER_SUCCESSES {
+                range.record_success(size);
+            }
+        }
+        assert_eq!(range.size(), 16);
+        for _ in 0..GROW_AFTER_SUCCESSES {
+            range.record_success(16);
+        }
+        assert_eq!(range.size(), 16);
+    }
+
+    #[tokio::test]
+    async fn rejected_requests_are_split_without_skipping_blocks() {
+        let range = AdaptiveRange::new(100);
+        let requests = RefCell::new(Vec::new());
+        let blocks = fetch_in_ranges(&range, 10, 109, limited_fetch(30, &requests)).await.unwrap();
+        assert_eq!(blocks, (10..=109).collect::<Vec<u64>>());
+        assert_eq!(
+            requests.into_inner(),
+            vec![(10, 109), (10, 59), (10, 34), (35, 59), (60, 84), (85, 109)]
+        );
+        // Four full-size requests in a row grow the range again.
+        assert_eq!(range.size(), 50);
+    }
+
+    #[tokio::test]
+    async fn single_block_rejections_and_other_errors_are_returned() {
+        let range = AdaptiveRange::new(8);
+        let requests = RefCell::new(Vec::new());
+        let error = fetch_in_ranges(&range, 1, 8, limited_fetch(0, &requests)).await.unwrap_err();
+        assert!(is_range_error(&error));
+        assert_eq!(requests.borrow().last(), Some(&(1, 1)));
+
+        let range = AdaptiveRange::new(8);
+        let calls = RefCell::new(0);
+        let error = fetch_in_ranges(&range, 1, 8, |_, _| {
+            *calls.borrow_mut() += 1;
+            std::future::ready(Err::<Vec<u64>, _>(rpc_error("invalid block range params")))
+        })
+        .await
+        .unwrap_err();
+        assert_eq!(error.to_string(), rpc_error("invalid block range params").to_string());
+        assert_eq!(*calls.borrow(), 1);
+        assert_eq!(range.size(), 8);
+    }
+}
@@ -1 +1,6 @@
-pub mod indexer;
\ No newline at end of file
+pub mod indexer;
+pub mod block_timestamps;
+pub mod subscription;
+pub mod webhooks;
+pub mod log_range;
+pub mod pipeline;
\ No newline at end of file
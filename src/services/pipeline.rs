@@ -0,0 +1,340 @@
+use diesel::pg::PgConnection;
+use diesel::r2d2::ConnectionManager;
+use futures::future::join_all;
+use log::{error, info, warn};
+use rand::Rng;
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::broadcast;
+use tokio::time::{sleep, Duration};
+use web3::types::{BlockId, BlockNumber, Log, H160, H256, U64};
+use crate::models::block::NewIndexedBlock;
+use crate::models::checkpoint::NewIndexerCheckpoint;
+use crate::models::transfer::{NewTransfer, Transfer, TransferEvent};
+use crate::repositories::block_repo::BlockRepo;
+use crate::repositories::transfer_repo::TransferRepo;
+use crate::services::block_timestamps::BlockTimestampCache;
+use crate::services::log_range::{fetch_logs, AdaptiveRange};
+use crate::utils::eth::{decode_transfer_log, transfer_kind};
+use crate::utils::transport::FailoverTransport;
+
+/// Number of attempts to fetch and decode a batch before giving up on it.
+pub const MAX_BATCH_ATTEMPTS: u32 = 5;
+/// Delay before the second attempt of a batch, doubled after each failed attempt.
+const BATCH_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// Indexing progress of a single token.
+#[derive(Debug, Clone)]
+pub struct TokenCursor {
+    pub address: H160,
+    /// Lowercase hex address, as stored in the database.
+    pub key: String,
+    /// First block whose transfers have not been stored yet.
+    pub next_block: u64,
+}
+
+/// Formats an address the way it is stored in the database.
+pub fn address_key(address: H160) -> String {
+    format!("0x{}", hex::encode(address.as_bytes()))
+}
+
+/// Publishes changes to the confirmed transfers to the API's live streams. Having no subscriber is not an error.
+pub fn publish_transfers(transfer_tx: &broadcast::Sender<TransferEvent>, transfers: Vec<Transfer>, event: fn(Transfer) -> TransferEvent) {
+    for transfer in transfers {
+        let _ = transfer_tx.send(event(transfer));
+    }
+}
+
+/// Block range of a set of tokens, fetched, decoded and written as a unit.
+#[derive(Debug, Clone)]
+pub struct Batch {
+    pub from_block: u64,
+    pub to_block: u64,
+    /// Tokens of the batch; transfers of blocks before a token's cursor are already stored and skipped.
+    pub tokens: Vec<TokenCursor>,
+}
+
+impl Batch {
+    /// Creates a batch of the tokens whose cursor is within the range, so its checkpoints never move
+    /// the cursor of a token indexed beyond the range backwards.
+    pub fn new(from_block: u64, to_block: u64, cursors: &[TokenCursor]) -> Self {
+        let tokens = cursors.iter()
+            .filter(|cursor| cursor.next_block <= to_block)
+            .cloned()
+            .collect();
+        Batch { from_block, to_block, tokens }
+    }
+}
+
+/// Origin of a batch of confirmed transfers, deciding what its write does besides storing them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BatchSource {
+    /// Historical blocks of the backfill: checkpointed, but not delivered to webhooks.
+    Backfill,
+    /// A failed backfill range retried out of order: neither checkpointed nor delivered.
+    FailedRange,
+    /// New final blocks of the live loop: checkpointed and delivered to webhooks.
+    Live,
+}
+
+/// Transfers of a batch ready to be written, with the headers of its blocks tracked for reorganizations.
+#[derive(Debug)]
+pub struct DecodedBatch {
+    pub transfers: Vec<NewTransfer>,
+    pub blocks: Vec<NewIndexedBlock>,
+}
+
+/// Ingestion pipeline driven by the backfill, the retries of failed ranges and the live indexer alike.
+/// The logs of a batch are fetched from the node, unless the subscription delivered them, then decoded
+/// into transfers with the timestamps of their blocks and the headers of the blocks near the head, and
+/// finally written together with the checkpoints in a single transaction and published.
+#[derive(Debug)]
+pub struct Pipeline {
+    web3: web3::Web3<FailoverTransport>,
+    timestamps: BlockTimestampCache,
+    transfer_repo: TransferRepo,
+    block_repo: BlockRepo,
+    transfer_tx: broadcast::Sender<TransferEvent>,
+    chain_id: i64,
+    transfer_topic: H256,
+}
+
+impl Pipeline {
+    /// Creates a pipeline storing the transfer events of a chain and publishing them on `transfer_tx`.
+    pub fn new(
+        pool: diesel::r2d2::Pool<ConnectionManager<PgConnection>>,
+        web3: web3::Web3<FailoverTransport>,
+        transfer_tx: broadcast::Sender<TransferEvent>,
+        chain_id: i64,
+        transfer_topic: H256,
+    ) -> Arc<Self> {
+        Arc::new(Pipeline {
+            web3,
+            timestamps: BlockTimestampCache::new(),
+            transfer_repo: TransferRepo::new(pool.clone()),
+            block_repo: BlockRepo::new(pool),
+            transfer_tx,
+            chain_id,
+            transfer_topic,
+        })
+    }
+
+    /// Builds the checkpoints marking the tokens of a batch as indexed up to its last block.
+    pub fn checkpoints(&self, batch: &Batch) -> Vec<NewIndexerCheckpoint> {
+        batch.tokens.iter()
+            .map(|cursor| NewIndexerCheckpoint {
+                chain_id: self.chain_id,
+                token_address: cursor.key.clone(),
+                last_block: batch.to_block as i64,
+            })
+            .collect()
+    }
+
+    /// Fetches the transfer logs of a batch, in requests sized by the adaptive range.
+    async fn fetch(&self, range: &AdaptiveRange, batch: &Batch) -> Result<Vec<Log>, web3::Error> {
+        // An empty address filter would match the logs of every contract.
+        if batch.tokens.is_empty() {
+            return Ok(Vec::new());
+        }
+        info!("Fetching logs from block {} to {}", batch.from_block, batch.to_block);
+        let token_addresses: Vec<H160> = batch.tokens.iter().map(|cursor| cursor.address).collect();
+        let logs = fetch_logs(&self.web3, range, &token_addresses, self.transfer_topic, batch.from_block, batch.to_block).await?;
+        info!("Fetched {} logs from block {} to {}", logs.len(), batch.from_block, batch.to_block);
+        Ok(logs)
+    }
+
+    /// Fetches the headers of a block range and checks that they form a single chain, linked to the
+    /// stored hash of the block preceding the range when it is tracked. Their timestamps are cached.
+    async fn fetch_block_headers(
+        &self,
+        from_block: u64,
+        to_block: u64,
+    ) -> Result<Vec<NewIndexedBlock>, Box<dyn std::error::Error + Send + Sync + 'static>> {
+        let mut expected_parent = match from_block.checked_sub(1) {
+            Some(previous) => self.block_repo.get_block_hash(self.chain_id, previous as i64).await?,
+            None => None,
+        };
+        // Request every header at once, so the calls are batched.
+        let headers = join_all((from_block..=to_block).map(|block_number| {
+            self.web3.eth().block(BlockId::Number(BlockNumber::Number(U64::from(block_number))))
+        }))
+        .await;
+        let mut blocks = Vec::new();
+        for (block_number, header) in (from_block..=to_block).zip(headers) {
+            let block = header?.ok_or_else(|| format!("Block {} not found", block_number))?;
+            let hash = block.hash.ok_or_else(|| format!("Block {} has no hash", block_number))?;
+            self.timestamps.insert(hash, block.timestamp.as_u64());
+            let block_hash = format!("0x{:x}", hash);
+            let parent_hash = format!("0x{:x}", block.parent_hash);
+            if let Some(expected) = &expected_parent {
+                if *expected != parent_hash {
+                    return Err(format!(
+                        "Block {} parent hash {} does not match indexed hash {}", block_number, parent_hash, expected
+                    ).into());
+                }
+            }
+            expected_parent = Some(block_hash.clone());
+            blocks.push(NewIndexedBlock {
+                chain_id: self.chain_id,
+                block_number: block_number as i64,
+                block_hash,
+                parent_hash,
+            });
+        }
+        Ok(blocks)
+    }
+
+    /// Decodes the logs of a batch into transfer events with the timestamps of their blocks, skipping
+    /// the blocks a token's cursor already covered. The headers of the blocks from `track_from` on are
+    /// fetched to be tracked, and a log of a block that is not on the canonical chain fails the batch.
+    async fn decode(
+        &self,
+        batch: &Batch,
+        logs: Vec<Log>,
+        track_from: Option<u64>,
+        status: &str,
+    ) -> Result<DecodedBatch, Box<dyn std::error::Error + Send + Sync + 'static>> {
+        let blocks = match track_from.map(|block| block.max(batch.from_block)) {
+            Some(tracked_from) if tracked_from <= batch.to_block => self.fetch_block_headers(tracked_from, batch.to_block).await?,
+            _ => Vec::new(),
+        };
+        let block_hashes: HashMap<i64, &str> = blocks.iter()
+            .map(|block| (block.block_number, block.block_hash.as_str()))
+            .collect();
+        let orphaned_log = logs.iter().find(|log| {
+            let block_number = log.block_number.unwrap_or_default().as_u64() as i64;
+            let block_hash = format!("0x{:x}", log.block_hash.unwrap_or_default());
+            block_hashes.get(&block_number).is_some_and(|hash| *hash != block_hash)
+        });
+        if let Some(log) = orphaned_log {
+            return Err(format!("Log from block {:?} does not belong to the canonical chain", log.block_number).into());
+        }
+
+        let next_blocks: HashMap<H160, u64> = batch.tokens.iter()
+            .map(|cursor| (cursor.address, cursor.next_block))
+            .collect();
+        let logs: Vec<Log> = logs.into_iter()
+            .filter(|log| next_blocks.get(&log.address)
+                .is_some_and(|next_block| log.block_number.unwrap_or_default().as_u64() >= *next_block))
+            .collect();
+        self.timestamps.prefetch(&self.web3, logs.iter().filter_map(|log| log.block_hash)).await?;
+        let mut transfers = Vec::with_capacity(logs.len());
+        for log in logs {
+            match decode_transfer_log(log.clone()) {
+                Ok((sender, recipient, amount)) => {
+                    let block_hash = log.block_hash.ok_or("Log has no block hash")?;
+                    let kind = transfer_kind(&sender, &recipient).to_string();
+                    transfers.push(NewTransfer {
+                        sender,
+                        recipient,
+                        amount,
+                        block_number: log.block_number.unwrap_or_default().as_u64() as i64,
+                        tx_hash: format!("0x{:x}", log.transaction_hash.unwrap_or_default()),
+                        log_index: log.log_index.unwrap_or_default().as_u32() as i32,
+                        transaction_index: log.transaction_index.unwrap_or_default().as_u32() as i32,
+                        status: status.to_string(),
+                        token_address: address_key(log.address),
+                        block_timestamp: self.timestamps.get(&self.web3, block_hash).await?,
+                        kind,
+                    });
+                }
+                Err(e) => error!("Failed to decode log: {}", e),
+            }
+        }
+        Ok(DecodedBatch { transfers, blocks })
+    }
+
+    /// Fetches and decodes a batch, starting from the logs delivered by the subscription when given.
+    /// Failed attempts are retried with logs fetched from the node, after an exponential backoff with
+    /// random jitter, before giving up.
+    pub async fn process(
+        &self,
+        range: &AdaptiveRange,
+        batch: &Batch,
+        mut subscribed_logs: Option<Vec<Log>>,
+        track_from: Option<u64>,
+        status: &str,
+    ) -> Result<DecodedBatch, Box<dyn std::error::Error + Send + Sync + 'static>> {
+        let mut attempt = 1;
+        loop {
+            let logs = match subscribed_logs.take() {
+                Some(logs) => {
+                    info!("Using {} subscribed logs from block {} to {}", logs.len(), batch.from_block, batch.to_block);
+                    Ok(logs)
+                }
+                None => self.fetch(range, batch).await.map_err(|e| e.into()),
+            };
+            let result = match logs {
+                Ok(logs) => self.decode(batch, logs, track_from, status).await,
+                Err(e) => Err(e),
+            };
+            match result {
+                Ok(decoded) => return Ok(decoded),
+                Err(e) if attempt < MAX_BATCH_ATTEMPTS => {
+                    let delay = BATCH_RETRY_DELAY * 2u32.pow(attempt - 1);
+                    // Spread the retries of concurrent batches so they do not hit the node at once.
+                    let delay = delay.mul_f64(rand::thread_rng().gen_range(0.5..1.0));
+                    warn!(
+                        "Attempt {} of batch {} to {} failed, retrying in {} ms: {}",
+                        attempt, batch.from_block, batch.to_block, delay.as_millis(), e
+                    );
+                    sleep(delay).await;
+                    attempt += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Stores the transfers and tracked blocks of a decoded batch in a single transaction, advancing the
+    /// checkpoints of its tokens and queuing webhook deliveries as its source requires, and publishes
+    /// the newly confirmed transfers. Returns the number of transfers inserted.
+    pub async fn write(
+        &self,
+        batch: &Batch,
+        decoded: DecodedBatch,
+        source: BatchSource,
+    ) -> Result<usize, Box<dyn std::error::Error + Send + Sync + 'static>> {
+        let checkpoints = if source == BatchSource::FailedRange { Vec::new() } else { self.checkpoints(batch) };
+        let deliver = source == BatchSource::Live;
+        let inserted = self.transfer_repo
+            .insert_transfers_with_checkpoints(decoded.transfers, decoded.blocks, checkpoints, deliver)
+            .await?;
+        let count = inserted.len();
+        info!("Inserted {} transfers from block {} to {}", count, batch.from_block, batch.to_block);
+        publish_transfers(&self.transfer_tx, inserted, TransferEvent::Indexed);
+        Ok(count)
+    }
+
+    /// Replaces the unconfirmed transfers above a block with those of a decoded batch.
+    /// Returns the number of transfers stored.
+    pub async fn write_unconfirmed(
+        &self,
+        decoded: DecodedBatch,
+        above_block: u64,
+    ) -> Result<usize, Box<dyn std::error::Error + Send + Sync + 'static>> {
+        let stored = self.transfer_repo.replace_unconfirmed_transfers(decoded.transfers, above_block as i64).await?;
+        info!("Stored {} unconfirmed transfers above block {}", stored, above_block);
+        Ok(stored)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cursor(byte: u8, next_block: u64) -> TokenCursor {
+        let address = H160::repeat_byte(byte);
+        TokenCursor { address, key: address_key(address), next_block }
+    }
+
+    #[test]
+    fn batches_only_checkpoint_tokens_reached_by_their_range() {
+        let cursors = [cursor(1, 100), cursor(2, 150), cursor(3, 201)];
+        let batch = Batch::new(101, 200, &cursors);
+        let tokens: Vec<u64> = batch.tokens.iter().map(|cursor| cursor.next_block).collect();
+        assert_eq!(tokens, [100, 150]);
+        assert_eq!(Batch::new(201, 300, &cursors).tokens.len(), 3);
+        assert!(Batch::new(1, 99, &cursors).tokens.is_empty());
+    }
+}
@@ -0,0 +1,325 @@
+use std::collections::BTreeMap;
+use log::{error, info, warn};
+use tokio::sync::mpsc;
+use tokio::time::{sleep, sleep_until, Duration, Instant};
+use web3::futures::StreamExt;
+use web3::types::{Filter, Log};
+use crate::utils::eth::connect_to_ws_node;
+
+/// Time without a new head after which the subscription is considered stalled and reconnected.
+const HEAD_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Longest delay between two reconnection attempts.
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(60);
+
+/// Maximum number of subscribed logs kept waiting for their block to be indexed.
+const MAX_BUFFERED_LOGS: usize = 100_000;
+
+/// Event received from the node's WebSocket subscriptions.
+#[derive(Debug)]
+pub enum SubscriptionEvent {
+    /// The `logs` and `newHeads` subscriptions were (re)established.
+    Connected,
+    /// A new head was announced.
+    Head(u64),
+    /// A transfer log was emitted, or removed from the chain if its `removed` flag is set.
+    Log(Box<Log>),
+    /// The subscriptions dropped; blocks are polled over HTTP until they are reestablished.
+    Disconnected,
+}
+
+/// Transfer logs delivered by the subscription, kept until their block is indexed.
+#[derive(Debug, Default)]
+pub struct SubscribedLogs {
+    /// Whether the subscriptions are currently established.
+    connected: bool,
+    /// First block whose logs were all delivered since the subscriptions were established.
+    covered_from: Option<u64>,
+    /// Latest head announced since the subscriptions were established.
+    head: Option<u64>,
+    logs: BTreeMap<u64, Vec<Log>>,
+}
+
+impl SubscribedLogs {
+    /// Creates an empty buffer, for a subscription that is not established yet.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Whether new heads are announced by the subscription, so blocks do not need to be polled.
+    pub fn is_live(&self) -> bool {
+        self.connected
+    }
+
+    /// Records an event. Returns `true` when a new head was announced and new blocks should be indexed.
+    pub fn apply(&mut self, event: SubscriptionEvent) -> bool {
+        match event {
+            SubscriptionEvent::Connected => {
+                info!("Subscribed to new heads and transfer logs");
+                self.reset();
+                self.connected = true;
+                false
+            }
+            SubscriptionEvent::Head(block_number) => {
+                if self.covered_from.is_none() {
+                    // Logs of this head may have been emitted before the logs subscription started.
+                    info!("Subscription covers blocks from {}, earlier blocks are fetched with eth_getLogs", block_number + 1);
+                    self.covered_from = Some(block_number + 1);
+                }
+                self.head = Some(self.head.map_or(block_number, |head| head.max(block_number)));
+                true
+            }
+            SubscriptionEvent::Log(log) => {
+                let Some(block_number) = log.block_number.map(|number| number.as_u64()) else {
+                    return false;
+                };
+                if log.removed == Some(true) {
+                    if let Some(logs) = self.logs.get_mut(&block_number) {
+                        logs.retain(|kept| kept.block_hash != log.block_hash || kept.log_index != log.log_index);
+                    }
+                } else if self.covered_from.is_some_and(|covered_from| block_number >= covered_from) {
+                    self.logs.entry(block_number).or_default().push(*log);
+                    if self.logs.values().map(Vec::len).sum::<usize>() > MAX_BUFFERED_LOGS {
+                        warn!("Too many subscribed logs waiting to be indexed, fetching them with eth_getLogs instead");
+                        self.reset();
+                    }
+                }
+                false
+            }
+            SubscriptionEvent::Disconnected => {
+                if self.connected {
+                    warn!("Subscription dropped, polling new blocks over HTTP");
+                }
+                self.reset();
+                self.connected = false;
+                false
+            }
+        }
+    }
+
+    /// Returns the subscribed logs of a block range, or `None` if the subscription may have missed
+    /// some of them: the range must start after the subscription was established and end before
+    /// the latest announced head, whose logs may still be on their way.
+    pub fn logs_between(&self, from_block: u64, to_block: u64) -> Option<Vec<Log>> {
+        let covered_from = self.covered_from?;
+        let head = self.head?;
+        if from_block < covered_from || to_block >= head {
+            return None;
+        }
+        Some(self.logs.range(from_block..=to_block).flat_map(|(_, logs)| logs.iter().cloned()).collect())
+    }
+
+    /// Forgets the logs of blocks up to the given one, once they are indexed.
+    pub fn prune(&mut self, up_to_block: u64) {
+        self.logs = self.logs.split_off(&(up_to_block + 1));
+    }
+
+    /// Forgets every subscribed log, so blocks are fetched with eth_getLogs until the next head.
+    pub fn reset(&mut self) {
+        self.covered_from = None;
+        self.head = None;
+        self.logs.clear();
+    }
+}
+
+/// Subscribes to the transfer logs matching a filter and to new heads over WebSocket, forwarding
+/// them as events. Reconnects with an increasing delay whenever the subscriptions drop, until the
+/// receiver is closed.
+pub async fn run_subscriptions(ws_url: String, filter: Filter, events: mpsc::Sender<SubscriptionEvent>) {
+    let mut delay = Duration::from_secs(1);
+    loop {
+        let started = Instant::now();
+        match forward_events(&ws_url, filter.clone(), &events).await {
+            Ok(()) => return,
+            Err(e) => error!("WebSocket subscription failed: {}", e),
+        }
+        // Start over with a short delay after a subscription that stayed up for a while.
+        if started.elapsed() > MAX_RECONNECT_DELAY {
+            delay = Duration::from_secs(1);
+        }
+        if events.send(SubscriptionEvent::Disconnected).await.is_err() {
+            return;
+        }
+        info!("Reconnecting to the WebSocket endpoint in {} seconds", delay.as_secs());
+        sleep(delay).await;
+        delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+    }
+}
+
+/// Establishes the subscriptions and forwards their events. Returns `Ok` once the receiver is closed.
+async fn forward_events(
+    ws_url: &str,
+    filter: Filter,
+    events: &mpsc::Sender<SubscriptionEvent>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
+    let web3 = connect_to_ws_node(ws_url).await?;
+    // Subscribe to logs first, so every block announced afterwards has its logs delivered.
+    let mut logs = web3.eth_subscribe().subscribe_logs(filter).await?;
+    let mut heads = web3.eth_subscribe().subscribe_new_heads().await?;
+    if events.send(SubscriptionEvent::Connected).await.is_err() {
+        return Ok(());
+    }
+    let mut deadline = Instant::now() + HEAD_TIMEOUT;
+    loop {
+        let event = tokio::select! {
+            head = heads.next() => match head {
+                Some(header) => match header?.number {
+                    Some(number) => {
+                        deadline = Instant::now() + HEAD_TIMEOUT;
+                        SubscriptionEvent::Head(number.as_u64())
+                    }
+                    None => continue,
+                },
+                None => return Err("New heads subscription closed".into()),
+            },
+            log = logs.next() => match log {
+                Some(log) => SubscriptionEvent::Log(Box::new(log?)),
+                None => return Err("Logs subscription closed".into()),
+            },
+            _ = sleep_until(deadline) => {
+                return Err(format!("No new head received for {} seconds", HEAD_TIMEOUT.as_secs()).into());
+            }
+        };
+        if events.send(event).await.is_err() {
+            return Ok(());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use web3::types::{H256, U256, U64};
+
+    fn log(block_number: u64, log_index: u64) -> Box<Log> {
+        Box::new(Log {
+            address: Default::default(),
+            topics: Vec::new(),
+            data: Default::default(),
+            block_hash: Some(H256::from_low_u64_be(block_number)),
+            block_number: Some(U64::from(block_number)),
+            transaction_hash: Some(H256::from_low_u64_be(block_number * 1000 + log_index)),
+            transaction_index: Some(0.into()),
+            log_index: Some(U256::from(log_index)),
+            transaction_log_index: None,
+            log_type: None,
+            removed: None,
+        })
+    }
+
+    fn removed(block_number: u64, log_index: u64) -> Box<Log> {
+        let mut log = log(block_number, log_index);
+        log.removed = Some(true);
+        log
+    }
+
+    fn log_indexes(logs: Option<Vec<Log>>) -> Option<Vec<(u64, u64)>> {
+        logs.map(|logs| {
+            logs.iter().map(|log| (log.block_number.unwrap().as_u64(), log.log_index.unwrap().as_u64())).collect()
+        })
+    }
+
+    /// A buffer subscribed at head 100, so it covers blocks from 101.
+    fn subscribed() -> SubscribedLogs {
+        let mut subscribed = SubscribedLogs::new();
+        assert!(!subscribed.apply(SubscriptionEvent::Connected));
+        assert!(subscribed.apply(SubscriptionEvent::Head(100)));
+        subscribed
+    }
+
+    #[test]
+    fn logs_are_only_served_for_covered_ranges_below_the_head() {
+        let unsubscribed = SubscribedLogs::new();
+        assert!(!unsubscribed.is_live());
+        assert_eq!(unsubscribed.logs_between(1, 2), None);
+
+        let mut subscribed = subscribed();
+        assert!(subscribed.is_live());
+        // Logs of the announced head itself may predate the logs subscription.
+        subscribed.apply(SubscriptionEvent::Log(log(100, 0)));
+        subscribed.apply(SubscriptionEvent::Log(log(101, 0)));
+        subscribed.apply(SubscriptionEvent::Log(log(101, 1)));
+        subscribed.apply(SubscriptionEvent::Log(log(103, 0)));
+        assert!(subscribed.apply(SubscriptionEvent::Head(103)));
+        // An older head announced late does not move the head back.
+        assert!(subscribed.apply(SubscriptionEvent::Head(102)));
+
+        assert_eq!(log_indexes(subscribed.logs_between(100, 102)), None);
+        assert_eq!(log_indexes(subscribed.logs_between(101, 103)), None);
+        assert_eq!(log_indexes(subscribed.logs_between(101, 102)), Some(vec![(101, 0), (101, 1)]));
+        assert_eq!(log_indexes(subscribed.logs_between(102, 102)), Some(vec![]));
+    }
+
+    #[test]
+    fn removed_logs_are_dropped() {
+        let mut subscribed = subscribed();
+        subscribed.apply(SubscriptionEvent::Log(log(101, 0)));
+        subscribed.apply(SubscriptionEvent::Log(log(101, 1)));
+        subscribed.apply(SubscriptionEvent::Head(102));
+
+        assert!(!subscribed.apply(SubscriptionEvent::Log(removed(101, 0))));
+        // A removal from another fork of the block leaves the delivered log in place.
+        let mut other_fork = removed(101, 1);
+        other_fork.block_hash = Some(H256::repeat_byte(0xff));
+        subscribed.apply(SubscriptionEvent::Log(other_fork));
+        // Removals are never buffered as logs.
+        subscribed.apply(SubscriptionEvent::Log(removed(101, 2)));
+
+        assert_eq!(log_indexes(subscribed.logs_between(101, 101)), Some(vec![(101, 1)]));
+    }
+
+    #[test]
+    fn prune_forgets_indexed_blocks() {
+        let mut subscribed = subscribed();
+        for block_number in 101..=104 {
+            subscribed.apply(SubscriptionEvent::Log(log(block_number, 0)));
+        }
+        subscribed.apply(SubscriptionEvent::Head(105));
+
+        subscribed.prune(102);
+        assert_eq!(log_indexes(subscribed.logs_between(101, 104)), Some(vec![(103, 0), (104, 0)]));
+    }
+
+    #[test]
+    fn disconnecting_resets_the_coverage() {
+        let mut subscribed = subscribed();
+        subscribed.apply(SubscriptionEvent::Log(log(101, 0)));
+        subscribed.apply(SubscriptionEvent::Head(102));
+
+        assert!(!subscribed.apply(SubscriptionEvent::Disconnected));
+        assert!(!subscribed.is_live());
+        assert_eq!(subscribed.logs_between(101, 101), None);
+
+        // Once reconnected, only blocks after the first new head are covered again.
+        subscribed.apply(SubscriptionEvent::Connected);
+        assert!(subscribed.is_live());
+        assert_eq!(subscribed.logs_between(101, 101), None);
+        subscribed.apply(SubscriptionEvent::Head(102));
+        subscribed.apply(SubscriptionEvent::Log(log(103, 0)));
+        subscribed.apply(SubscriptionEvent::Head(104));
+        assert_eq!(log_indexes(subscribed.logs_between(101, 103)), None);
+        assert_eq!(log_indexes(subscribed.logs_between(103, 103)), Some(vec![(103, 0)]));
+    }
+
+    #[test]
+    fn overflowing_the_buffer_gives_up_the_coverage() {
+        let mut subscribed = subscribed();
+        for log_index in 0..MAX_BUFFERED_LOGS as u64 {
+            subscribed.apply(SubscriptionEvent::Log(log(101, log_index)));
+        }
+        subscribed.apply(SubscriptionEvent::Head(102));
+        assert_eq!(subscribed.logs_between(101, 101).map(|logs| logs.len()), Some(MAX_BUFFERED_LOGS));
+
+        // One more log drops them all rather than keeping a truncated block.
+        subscribed.apply(SubscriptionEvent::Log(log(102, 0)));
+        assert!(subscribed.is_live());
+        assert_eq!(subscribed.logs_between(101, 101), None);
+
+        // Coverage starts over after the next head, without any of the dropped logs.
+        subscribed.apply(SubscriptionEvent::Head(103));
+        subscribed.apply(SubscriptionEvent::Log(log(104, 0)));
+        subscribed.apply(SubscriptionEvent::Head(105));
+        assert_eq!(subscribed.logs_between(102, 104), None);
+        assert_eq!(log_indexes(subscribed.logs_between(104, 104)), Some(vec![(104, 0)]));
+    }
+}
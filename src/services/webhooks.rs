@@ -0,0 +1,341 @@
+use diesel::pg::PgConnection;
+use diesel::r2d2::ConnectionManager;
+use futures::future::join_all;
+use hmac::{Hmac, Mac};
+use log::{error, info, warn};
+use sha2::Sha256;
+use tokio::sync::broadcast;
+use tokio::time::{interval, Duration, MissedTickBehavior};
+use crate::models::webhook::{Webhook, WebhookDelivery};
+use crate::repositories::webhook_repo::WebhookRepo;
+
+/// Interval between two polls of the deliveries that are due.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+/// Maximum number of deliveries attempted concurrently.
+const DELIVERY_BATCH_SIZE: i64 = 50;
+/// Time a webhook has to respond before the attempt fails.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+/// Delay before the second attempt of a delivery, doubled after each failed attempt.
+const BASE_RETRY_DELAY_SECS: i64 = 5;
+/// Longest delay between two attempts of a delivery.
+const MAX_RETRY_DELAY_SECS: i64 = 3600;
+/// Maximum length of a response body or error kept in the delivery log.
+const MAX_ERROR_LENGTH: usize = 500;
+
+/// Configuration of the webhook delivery worker.
+#[derive(Debug, Clone)]
+pub struct WebhookConfig {
+    /// Number of failed attempts after which a delivery is moved to the dead-letter state.
+    pub max_attempts: i32,
+}
+
+/// Outcome of an attempt to deliver a transfer to a webhook.
+enum Attempt {
+    Delivered(i32),
+    Failed(Option<i32>, String),
+}
+
+/// Computes the `X-Webhook-Signature` header of a body: the hex HMAC-SHA256 of the body keyed with
+/// the webhook's secret.
+fn sign_payload(secret: &str, payload: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any length");
+    mac.update(payload.as_bytes());
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+/// Delay before the next attempt of a delivery that failed its given number of attempts.
+fn retry_delay_secs(attempts: i32) -> i64 {
+    let exponent = (attempts - 1).clamp(0, 20) as u32;
+    (BASE_RETRY_DELAY_SECS << exponent).min(MAX_RETRY_DELAY_SECS)
+}
+
+/// Shortens an error to the length kept in the delivery log.
+fn truncate(message: String) -> String {
+    message.chars().take(MAX_ERROR_LENGTH).collect()
+}
+
+/// Posts the payload of a delivery to its webhook with the signature headers.
+async fn attempt_delivery(client: &reqwest::Client, delivery: &WebhookDelivery, webhook: &Webhook) -> Attempt {
+    let response = client.post(&webhook.url)
+        .header(reqwest::header::CONTENT_TYPE, "application/json")
+        .header("X-Webhook-Signature", sign_payload(&webhook.secret, &delivery.payload))
+        .header("X-Webhook-Id", webhook.id.to_string())
+        .header("X-Webhook-Delivery", delivery.id.to_string())
+        .body(delivery.payload.clone())
+        .send()
+        .await;
+    match response {
+        Ok(response) if response.status().is_success() => Attempt::Delivered(response.status().as_u16() as i32),
+        Ok(response) => {
+            let status = response.status();
+            let body = response.text().await.unwrap_or_default();
+            Attempt::Failed(Some(status.as_u16() as i32), truncate(format!("Webhook responded with {}: {}", status, body)))
+        }
+        Err(e) => Attempt::Failed(None, truncate(format!("Request failed: {}", e))),
+    }
+}
+
+/// State of a delivery after an attempt.
+#[derive(Debug, PartialEq)]
+enum Transition {
+    Delivered(i32),
+    /// The attempt failed and is retried after the given delay.
+    Retry { status: Option<i32>, message: String, retry_in_secs: i64 },
+    /// The last attempt failed and the delivery is moved to the dead-letter state.
+    Dead { status: Option<i32>, message: String },
+}
+
+/// Computes the state of a delivery that had the given number of failed attempts before this one.
+fn transition(attempt: Attempt, previous_attempts: i32, max_attempts: i32) -> Transition {
+    match attempt {
+        Attempt::Delivered(status) => Transition::Delivered(status),
+        Attempt::Failed(status, message) => {
+            let attempts = previous_attempts + 1;
+            if attempts < max_attempts {
+                Transition::Retry { status, message, retry_in_secs: retry_delay_secs(attempts) }
+            } else {
+                Transition::Dead { status, message }
+            }
+        }
+    }
+}
+
+/// Attempts a delivery and records its outcome, scheduling a retry or giving up after the last attempt.
+async fn deliver(repo: &WebhookRepo, client: &reqwest::Client, config: &WebhookConfig, delivery: WebhookDelivery, webhook: Webhook) {
+    let attempt = attempt_delivery(client, &delivery, &webhook).await;
+    let attempts = delivery.attempts + 1;
+    let result = match transition(attempt, delivery.attempts, config.max_attempts) {
+        Transition::Delivered(status) => repo.mark_delivered(delivery.id, status).await,
+        Transition::Retry { status, message, retry_in_secs } => {
+            warn!(
+                "Delivery {} to webhook {} failed (attempt {}), retrying in {} seconds: {}",
+                delivery.id, webhook.id, attempts, retry_in_secs, message
+            );
+            repo.mark_failed(delivery.id, status, message, Some(retry_in_secs)).await
+        }
+        Transition::Dead { status, message } => {
+            error!(
+                "Delivery {} to webhook {} failed after {} attempts, giving up: {}",
+                delivery.id, webhook.id, attempts, message
+            );
+            repo.mark_failed(delivery.id, status, message, None).await
+        }
+    };
+    if let Err(e) = result {
+        error!("Failed to record the attempt of delivery {}: {}", delivery.id, e);
+    }
+}
+
+/// Delivers the transfers queued by the indexer to their webhooks until shutdown is signaled.
+pub async fn run_webhook_deliveries(
+    pool: diesel::r2d2::Pool<ConnectionManager<PgConnection>>,
+    config: WebhookConfig,
+    mut shutdown_rx: broadcast::Receiver<()>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
+    let repo = WebhookRepo::new(pool);
+    let client = reqwest::Client::builder().timeout(REQUEST_TIMEOUT).build()?;
+    let mut poll = interval(POLL_INTERVAL);
+    poll.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    info!("Webhook delivery started, giving up after {} attempts", config.max_attempts);
+
+    loop {
+        tokio::select! {
+            _ = shutdown_rx.recv() => {
+                info!("Shutting down webhook delivery");
+                return Ok(());
+            }
+            _ = poll.tick() => {}
+        }
+        let due = match repo.get_due_deliveries(DELIVERY_BATCH_SIZE).await {
+            Ok(due) => due,
+            Err(e) => {
+                error!("Failed to fetch due webhook deliveries: {}", e);
+                continue;
+            }
+        };
+        join_all(due.into_iter().map(|(delivery, webhook)| deliver(&repo, &client, &config, delivery, webhook))).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bigdecimal::BigDecimal;
+    use chrono::NaiveDateTime;
+    use std::str::FromStr;
+    use std::sync::{Arc, Mutex};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+    use crate::models::transfer::Transfer;
+
+    fn webhook(url: &str) -> Webhook {
+        Webhook {
+            id: 1,
+            url: url.to_string(),
+            secret: "secret".to_string(),
+            sender: None,
+            recipient: None,
+            min_amount: None,
+            created_at: NaiveDateTime::default(),
+        }
+    }
+
+    fn delivery(attempts: i32) -> WebhookDelivery {
+        WebhookDelivery {
+            id: 7,
+            webhook_id: 1,
+            transfer_id: 3,
+            payload: r#"{"event":"transfer"}"#.to_string(),
+            status: "pending".to_string(),
+            attempts,
+            next_attempt_at: NaiveDateTime::default(),
+            last_response_status: None,
+            last_error: None,
+            created_at: NaiveDateTime::default(),
+            delivered_at: None,
+        }
+    }
+
+    fn transfer(sender: &str, recipient: &str, amount: &str) -> Transfer {
+        Transfer {
+            id: 3,
+            sender: sender.to_string(),
+            recipient: recipient.to_string(),
+            amount: BigDecimal::from_str(amount).unwrap(),
+            block_number: 1,
+            tx_hash: "0x01".to_string(),
+            log_index: 0,
+            transaction_index: 0,
+            status: "confirmed".to_string(),
+            token_address: "0xaa".to_string(),
+            block_timestamp: NaiveDateTime::default(),
+            kind: "transfer".to_string(),
+        }
+    }
+
+    /// Serves each connection with the next of the given HTTP statuses, recording the requests received.
+    async fn serve(statuses: Vec<u16>) -> (String, Arc<Mutex<Vec<String>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let received = requests.clone();
+        tokio::spawn(async move {
+            for status in statuses {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let mut request = Vec::new();
+                let mut buf = [0u8; 4096];
+                // Read the headers, then the body announced by Content-Length.
+                loop {
+                    let n = socket.read(&mut buf).await.unwrap();
+                    request.extend_from_slice(&buf[..n]);
+                    let text = String::from_utf8_lossy(&request).to_lowercase();
+                    if let Some(end) = text.find("\r\n\r\n") {
+                        let length = text.lines()
+                            .find_map(|line| line.strip_prefix("content-length:"))
+                            .map_or(0, |value| value.trim().parse::<usize>().unwrap());
+                        if request.len() >= end + 4 + length {
+                            break;
+                        }
+                    }
+                    if n == 0 {
+                        break;
+                    }
+                }
+                received.lock().unwrap().push(String::from_utf8_lossy(&request).to_string());
+                let response = format!("HTTP/1.1 {} Status\r\ncontent-length: 4\r\nconnection: close\r\n\r\nbody", status);
+                socket.write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+        (url, requests)
+    }
+
+    #[test]
+    fn sign_payload_matches_known_hmac() {
+        // HMAC-SHA256 test vector from RFC 4231, test case 2.
+        assert_eq!(
+            sign_payload("Jefe", "what do ya want for nothing?"),
+            "sha256=5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    #[test]
+    fn retry_delay_doubles_up_to_the_cap() {
+        assert_eq!(retry_delay_secs(0), 5);
+        assert_eq!(retry_delay_secs(1), 5);
+        assert_eq!(retry_delay_secs(2), 10);
+        assert_eq!(retry_delay_secs(3), 20);
+        assert_eq!(retry_delay_secs(10), 2560);
+        assert_eq!(retry_delay_secs(11), MAX_RETRY_DELAY_SECS);
+        assert_eq!(retry_delay_secs(1000), MAX_RETRY_DELAY_SECS);
+    }
+
+    #[test]
+    fn matches_applies_every_filter() {
+        let transfer = transfer("0xa1", "0xb2", "100");
+        let mut filtered = webhook("http://localhost");
+        assert!(filtered.matches(&transfer));
+
+        filtered.sender = Some("0xa1".to_string());
+        assert!(filtered.matches(&transfer));
+        filtered.recipient = Some("0xc3".to_string());
+        assert!(!filtered.matches(&transfer));
+        filtered.recipient = Some("0xb2".to_string());
+        assert!(filtered.matches(&transfer));
+
+        filtered.min_amount = Some(BigDecimal::from(100));
+        assert!(filtered.matches(&transfer));
+        filtered.min_amount = Some(BigDecimal::from(101));
+        assert!(!filtered.matches(&transfer));
+
+        let mut by_sender = webhook("http://localhost");
+        by_sender.sender = Some("0xb2".to_string());
+        assert!(!by_sender.matches(&transfer));
+    }
+
+    #[tokio::test]
+    async fn delivery_transitions_follow_the_responses() {
+        let (url, requests) = serve(vec![200, 500, 503]).await;
+        let client = reqwest::Client::new();
+        let webhook = webhook(&url);
+
+        let attempt = attempt_delivery(&client, &delivery(0), &webhook).await;
+        assert_eq!(transition(attempt, 0, 3), Transition::Delivered(200));
+
+        let attempt = attempt_delivery(&client, &delivery(0), &webhook).await;
+        match transition(attempt, 0, 3) {
+            Transition::Retry { status, message, retry_in_secs } => {
+                assert_eq!(status, Some(500));
+                assert!(message.contains("body"));
+                assert_eq!(retry_in_secs, 5);
+            }
+            other => panic!("expected a retry, got {:?}", other),
+        }
+
+        let attempt = attempt_delivery(&client, &delivery(2), &webhook).await;
+        match transition(attempt, 2, 3) {
+            Transition::Dead { status, .. } => assert_eq!(status, Some(503)),
+            other => panic!("expected a dead letter, got {:?}", other),
+        }
+
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), 3);
+        let signature = sign_payload("secret", r#"{"event":"transfer"}"#);
+        assert!(requests[0].to_lowercase().contains(&format!("x-webhook-signature: {}", signature)));
+        assert!(requests[0].ends_with(r#"{"event":"transfer"}"#));
+    }
+
+    #[tokio::test]
+    async fn unreachable_webhook_is_retried_without_status() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        drop(listener);
+        let attempt = attempt_delivery(&reqwest::Client::new(), &delivery(0), &webhook(&url)).await;
+        match transition(attempt, 0, 3) {
+            Transition::Retry { status, message, .. } => {
+                assert_eq!(status, None);
+                assert!(message.starts_with("Request failed"));
+            }
+            other => panic!("expected a retry, got {:?}", other),
+        }
+    }
+}
@@ -1,17 +1,46 @@
-use web3::types::{Log, H256, U256};
+use std::future::Future;
+use std::str::FromStr;
+use std::time::Duration;
+use bigdecimal::BigDecimal;
+use web3::types::{BlockId, BlockNumber, Bytes, CallRequest, Log, H160, H256, U256, U64};
 use web3::Web3;
-use web3::transports::Http;
+use web3::transports::WebSocket;
 use web3::Error;
 use hex;
+use crate::models::balance::ZERO_ADDRESS;
+use crate::models::transfer::{KIND_BURN, KIND_MINT, KIND_TRANSFER};
+use crate::utils::transport::FailoverTransport;
 
-/// Connects to an Ethereum node using the given URL.
-pub async fn connect_to_node(node_url: &str) -> Result<Web3<Http>, Error> {
-    let transport = Http::new(node_url)?;
+/// Connects to an Ethereum node through the given endpoint URLs, failing over between them,
+/// cross-checking their heads when a maximum block lag is given, keeping the calls to each of
+/// them within the given number of requests per second and batching concurrent calls.
+pub async fn connect_to_node(
+    node_urls: &[String],
+    max_block_lag: Option<u64>,
+    requests_per_second: Option<f64>,
+    max_batch_size: usize,
+    request_timeout: Duration,
+    connect_timeout: Duration,
+) -> Result<Web3<FailoverTransport>, Error> {
+    let transport = FailoverTransport::new(
+        node_urls,
+        max_block_lag,
+        requests_per_second,
+        max_batch_size,
+        request_timeout,
+        connect_timeout,
+    )?;
+    Ok(Web3::new(transport))
+}
+
+/// Connects to an Ethereum node's WebSocket endpoint, for subscriptions.
+pub async fn connect_to_ws_node(ws_url: &str) -> Result<Web3<WebSocket>, Error> {
+    let transport = WebSocket::new(ws_url).await?;
     Ok(Web3::new(transport))
 }
 
 /// Decodes an Ethereum log into an ERC-20 Transfer event (sender, recipient, amount).
-pub fn decode_transfer_log(log: Log) -> Result<(String, String, String), String> {
+pub fn decode_transfer_log(log: Log) -> Result<(String, String, BigDecimal), String> {
     // ERC-20 Transfer event topic hash.
     let transfer_topic = H256::from_slice(
         &hex::decode("ddf252ad1be2c89b69c2b068fc378daa952ba7f163c4a11628f55a4df523b3ef")
@@ -33,7 +62,238 @@ pub fn decode_transfer_log(log: Log) -> Result<(String, String, String), String>
     let recipient_bytes = log.topics[2].as_bytes();
     let sender = format!("0x{}", hex::encode(&sender_bytes[12..]));
     let recipient = format!("0x{}", hex::encode(&recipient_bytes[12..]));
-    let amount = U256::from_big_endian(&log.data.0).to_string();
+    let amount = BigDecimal::from_str(&U256::from_big_endian(&log.data.0).to_string())
+        .map_err(|e| format!("Failed to convert amount: {}", e))?;
 
     Ok((sender, recipient, amount))
-}
\ No newline at end of file
+}
+
+/// Classifies a decoded transfer as a mint (from the zero address), a burn (to the zero address)
+/// or a regular transfer.
+pub fn transfer_kind(sender: &str, recipient: &str) -> &'static str {
+    if sender == ZERO_ADDRESS {
+        KIND_MINT
+    } else if recipient == ZERO_ADDRESS {
+        KIND_BURN
+    } else {
+        KIND_TRANSFER
+    }
+}
+
+/// ERC-20 `name()`, `symbol()`, `decimals()` and `totalSupply()` function selectors.
+const NAME_SELECTOR: [u8; 4] = [0x06, 0xfd, 0xde, 0x03];
+const SYMBOL_SELECTOR: [u8; 4] = [0x95, 0xd8, 0x9b, 0x41];
+const DECIMALS_SELECTOR: [u8; 4] = [0x31, 0x3c, 0xe5, 0x67];
+const TOTAL_SUPPLY_SELECTOR: [u8; 4] = [0x18, 0x16, 0x0d, 0xdd];
+
+/// Calls a parameterless view function of a contract at a block, or at the latest block, and
+/// returns the raw result.
+async fn call_contract(web3: &Web3<FailoverTransport>, contract: H160, selector: [u8; 4], block: Option<u64>) -> Result<Vec<u8>, Error> {
+    let request = CallRequest {
+        to: Some(contract),
+        data: Some(Bytes(selector.to_vec())),
+        ..Default::default()
+    };
+    let block = block.map(|number| BlockId::Number(BlockNumber::Number(U64::from(number))));
+    Ok(web3.eth().call(request, block).await?.0)
+}
+
+/// Decodes a string returned by `name()` or `symbol()`, either ABI-encoded or as a `bytes32`
+/// as returned by legacy tokens (e.g. MKR).
+pub fn decode_string_result(data: &[u8]) -> Option<String> {
+    let bytes = if data.len() == 32 {
+        data
+    } else {
+        let offset = U256::from_big_endian(data.get(..32)?);
+        let offset = usize::try_from(offset).ok()?;
+        let length = U256::from_big_endian(data.get(offset..offset.checked_add(32)?)?);
+        let length = usize::try_from(length).ok()?;
+        let start = offset + 32;
+        data.get(start..start.checked_add(length)?)?
+    };
+    let value = String::from_utf8(bytes.to_vec()).ok()?;
+    let value = value.trim_end_matches('\0').to_string();
+    if value.is_empty() { None } else { Some(value) }
+}
+
+/// Decodes a `uint256` returned by a contract call.
+fn decode_uint_result(data: &[u8]) -> Option<U256> {
+    data.get(..32).map(U256::from_big_endian)
+}
+
+/// Reads the name, symbol and decimals of a token contract at a block. Values the contract does not
+/// provide are `None`. The total supply is read separately, at the blocks the token is indexed up to.
+pub async fn fetch_token_metadata(
+    web3: &Web3<FailoverTransport>,
+    token_address: H160,
+    block: u64,
+) -> (Option<String>, Option<String>, Option<u8>) {
+    // Issue the calls at once, so they are batched.
+    let (name, symbol, decimals) = futures::join!(
+        call_contract(web3, token_address, NAME_SELECTOR, Some(block)),
+        call_contract(web3, token_address, SYMBOL_SELECTOR, Some(block)),
+        call_contract(web3, token_address, DECIMALS_SELECTOR, Some(block)),
+    );
+    let name = name.ok().and_then(|data| decode_string_result(&data));
+    let symbol = symbol.ok().and_then(|data| decode_string_result(&data));
+    let decimals = decimals.ok()
+        .and_then(|data| decode_uint_result(&data))
+        .and_then(|value| u8::try_from(value).ok());
+    (name, symbol, decimals)
+}
+
+/// Whether a contract has code at a block.
+async fn has_code(web3: &Web3<FailoverTransport>, contract: H160, block: u64) -> Result<bool, Error> {
+    let code = web3.eth().code(contract, Some(BlockNumber::Number(U64::from(block)))).await?;
+    Ok(!code.0.is_empty())
+}
+
+/// Finds the block at which a contract was deployed, by bisecting on whether it has code at blocks up
+/// to the given latest one. Returns `None` if it has no code at the latest block. Reading the code at
+/// old blocks requires a node keeping the historical state.
+pub async fn find_deployment_block(
+    web3: &Web3<FailoverTransport>,
+    contract: H160,
+    latest_block: u64,
+) -> Result<Option<u64>, Error> {
+    bisect_deployment_block(latest_block, |block| has_code(web3, contract, block)).await
+}
+
+/// Bisects the first block at which `code_at` reports code, up to the latest block.
+async fn bisect_deployment_block<F, Fut>(latest_block: u64, code_at: F) -> Result<Option<u64>, Error>
+where
+    F: Fn(u64) -> Fut,
+    Fut: Future<Output = Result<bool, Error>>,
+{
+    if !code_at(latest_block).await? {
+        return Ok(None);
+    }
+    // The deployment block lies between `low` and `high`, where the contract has code.
+    let (mut low, mut high) = (0, latest_block);
+    while low < high {
+        let middle = low + (high - low) / 2;
+        if code_at(middle).await? {
+            high = middle;
+        } else {
+            low = middle + 1;
+        }
+    }
+    Ok(Some(high))
+}
+
+/// Reads the `totalSupply()` of a token contract at a block, if the contract provides it.
+pub async fn fetch_total_supply(web3: &Web3<FailoverTransport>, token_address: H160, block: u64) -> Option<U256> {
+    call_contract(web3, token_address, TOTAL_SUPPLY_SELECTOR, Some(block)).await.ok()
+        .and_then(|data| decode_uint_result(&data))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+
+    /// ABI-encodes a string return value: offset, length, then the padded bytes.
+    fn abi_string(value: &str) -> Vec<u8> {
+        let mut data = vec![0u8; 64];
+        data[31] = 0x20;
+        data[56..64].copy_from_slice(&(value.len() as u64).to_be_bytes());
+        data.extend_from_slice(value.as_bytes());
+        data.resize(64 + value.len().div_ceil(32) * 32, 0);
+        data
+    }
+
+    #[test]
+    fn abi_encoded_strings_are_decoded() {
+        assert_eq!(decode_string_result(&abi_string("Lobster Token")), Some("Lobster Token".to_string()));
+        let long = "A token name that is longer than a single thirty-two byte word";
+        assert_eq!(decode_string_result(&abi_string(long)), Some(long.to_string()));
+        assert_eq!(decode_string_result(&abi_string("")), None);
+    }
+
+    #[test]
+    fn bytes32_strings_are_decoded() {
+        // `symbol()` of MKR, a bytes32 padded with zeros.
+        let mut data = [0u8; 32];
+        data[..3].copy_from_slice(b"MKR");
+        assert_eq!(decode_string_result(&data), Some("MKR".to_string()));
+        assert_eq!(decode_string_result(&[0u8; 32]), None);
+    }
+
+    #[test]
+    fn empty_and_garbage_data_is_rejected() {
+        assert_eq!(decode_string_result(&[]), None);
+        assert_eq!(decode_string_result(&[0x01, 0x02]), None);
+        // Offset pointing past the end of the data.
+        let mut data = abi_string("LOB");
+        data[31] = 0xff;
+        assert_eq!(decode_string_result(&data), None);
+        // Length running past the end of the data.
+        let mut data = abi_string("LOB");
+        data[63] = 0xff;
+        assert_eq!(decode_string_result(&data), None);
+        // Offset too large for any buffer.
+        let mut data = abi_string("LOB");
+        data[..32].fill(0xff);
+        assert_eq!(decode_string_result(&data), None);
+        // Invalid UTF-8.
+        let mut data = abi_string("LOB");
+        data[64] = 0xff;
+        assert_eq!(decode_string_result(&data), None);
+    }
+
+    /// Bisects a contract deployed at the given block, returning the result and the blocks queried.
+    async fn bisect(deployed_at: Option<u64>, latest_block: u64) -> (Option<u64>, Vec<u64>) {
+        let queried = RefCell::new(Vec::new());
+        let result = bisect_deployment_block(latest_block, |block| {
+            queried.borrow_mut().push(block);
+            std::future::ready(Ok(deployed_at.is_some_and(|deployed_at| block >= deployed_at)))
+        })
+        .await
+        .unwrap();
+        (result, queried.into_inner())
+    }
+
+    #[tokio::test]
+    async fn deployment_block_is_found_at_the_boundaries() {
+        assert_eq!(bisect(Some(0), 1000).await.0, Some(0));
+        assert_eq!(bisect(Some(1000), 1000).await.0, Some(1000));
+        assert_eq!(bisect(Some(999), 1000).await.0, Some(999));
+        assert_eq!(bisect(Some(1), 1000).await.0, Some(1));
+        assert_eq!(bisect(Some(0), 0).await.0, Some(0));
+        for deployed_at in 0..=64 {
+            assert_eq!(bisect(Some(deployed_at), 64).await.0, Some(deployed_at));
+        }
+    }
+
+    #[tokio::test]
+    async fn deployment_search_takes_logarithmic_calls() {
+        let (result, queried) = bisect(Some(123_456), 20_000_000).await;
+        assert_eq!(result, Some(123_456));
+        assert!(queried.len() <= 26, "{} calls", queried.len());
+    }
+
+    #[tokio::test]
+    async fn undeployed_contract_is_not_searched() {
+        assert_eq!(bisect(None, 1000).await, (None, vec![1000]));
+    }
+
+    #[tokio::test]
+    async fn code_errors_are_returned() {
+        let result = bisect_deployment_block(1000, |block| {
+            std::future::ready(if block == 1000 { Ok(true) } else { Err(Error::Unreachable) })
+        })
+        .await;
+        assert!(matches!(result, Err(Error::Unreachable)));
+    }
+
+    #[test]
+    fn transfers_from_or_to_the_zero_address_are_mints_or_burns() {
+        let holder = "0x1234567890123456789012345678901234567890";
+        assert_eq!(transfer_kind(ZERO_ADDRESS, holder), KIND_MINT);
+        assert_eq!(transfer_kind(holder, ZERO_ADDRESS), KIND_BURN);
+        assert_eq!(transfer_kind(holder, holder), KIND_TRANSFER);
+        assert_eq!(transfer_kind(holder, "0xabcdefabcdefabcdefabcdefabcdefabcdefabcd"), KIND_TRANSFER);
+        // A transfer from and to the zero address counts as a mint, as in the migration tagging stored transfers.
+        assert_eq!(transfer_kind(ZERO_ADDRESS, ZERO_ADDRESS), KIND_MINT);
+    }
+}
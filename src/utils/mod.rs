@@ -1 +1,3 @@
-pub mod eth;
\ No newline at end of file
+pub mod eth;
+pub mod rate_limit;
+pub mod transport;
\ No newline at end of file
@@ -0,0 +1,298 @@
+use chrono::{DateTime, Utc};
+use reqwest::header::{HeaderMap, RETRY_AFTER};
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::time::Instant;
+use web3::error::TransportError;
+use web3::Error;
+
+/// JSON-RPC error codes used by node providers to reject a request over their rate limit.
+const RATE_LIMIT_CODES: [i64; 2] = [429, -32007];
+/// Fragments of the JSON-RPC errors returned by node providers to reject a request over their rate limit,
+/// for providers reusing generic error codes such as -32005.
+const RATE_LIMIT_PATTERNS: [&str; 5] = [
+    "rate limit",
+    "too many requests",
+    "compute units per second",
+    "request limit reached",
+    "exceeded its throughput",
+];
+/// Pause after a rate-limited response that does not say how long to wait, doubled after each further one.
+const BASE_RATE_LIMIT_PAUSE: Duration = Duration::from_secs(1);
+/// Longest pause after a rate-limited response, whatever the node asks.
+const MAX_RATE_LIMIT_PAUSE: Duration = Duration::from_secs(60);
+
+/// Whether an error means that the node rejected the request over its rate limit, either with HTTP 429
+/// or with a JSON-RPC rate-limit error.
+pub fn is_rate_limit_error(error: &Error) -> bool {
+    match error {
+        Error::Transport(TransportError::Code(status)) => *status == 429,
+        Error::Rpc(rpc_error) => {
+            let message = rpc_error.message.to_lowercase();
+            RATE_LIMIT_CODES.contains(&rpc_error.code.code())
+                || RATE_LIMIT_PATTERNS.iter().any(|pattern| message.contains(pattern))
+        }
+        _ => false,
+    }
+}
+
+/// Parses the `Retry-After` header of a response, given either as a number of seconds or as an HTTP date.
+pub fn parse_retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let value = headers.get(RETRY_AFTER)?.to_str().ok()?.trim();
+    if let Ok(secs) = value.parse::<u64>() {
+        return Some(Duration::from_secs(secs));
+    }
+    let date = DateTime::parse_from_rfc2822(value).ok()?.with_timezone(&Utc);
+    Some((date - Utc::now()).to_std().unwrap_or_default())
+}
+
+/// Delay asked in the data of a JSON-RPC rate-limit error, as sent by Infura.
+pub fn parse_backoff(error: &Error) -> Option<Duration> {
+    let Error::Rpc(rpc_error) = error else { return None };
+    let secs = rpc_error.data.as_ref()?.get("backoff_seconds")?.as_f64()?;
+    Duration::try_from_secs_f64(secs).ok()
+}
+
+/// Counters of the calls delayed by the rate limiter or rejected by the node over its rate limit.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ThrottleStats {
+    /// Calls that waited for the request budget or for a pause asked by the node.
+    pub throttled_calls: u64,
+    /// Total time spent waiting by the throttled calls, in milliseconds.
+    pub throttled_ms: u64,
+    /// Responses rejecting a call over the node's rate limit.
+    pub rate_limited_responses: u64,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    refilled_at: Instant,
+    /// Time until which no call is sent, after the node rejected one over its rate limit.
+    paused_until: Option<Instant>,
+    /// Number of rate-limited responses in a row.
+    rate_limited_streak: u32,
+    stats: ThrottleStats,
+}
+
+/// Token bucket spacing the calls to an endpoint to a number of calls per second, with bursts of up to
+/// one second of budget, and pausing them when the node asks to slow down.
+#[derive(Debug)]
+pub struct RateLimiter {
+    /// Calls allowed per second, or `None` to only honour the pauses asked by the node.
+    requests_per_second: Option<f64>,
+    bucket: Mutex<Bucket>,
+}
+
+impl RateLimiter {
+    /// Creates a rate limiter allowing the given number of calls per second, or any number when `None`.
+    pub fn new(requests_per_second: Option<f64>) -> Self {
+        RateLimiter {
+            requests_per_second,
+            bucket: Mutex::new(Bucket {
+                tokens: requests_per_second.unwrap_or(0.0).max(1.0),
+                refilled_at: Instant::now(),
+                paused_until: None,
+                rate_limited_streak: 0,
+                stats: ThrottleStats::default(),
+            }),
+        }
+    }
+
+    /// Time until which calls are paused after a rate-limited response, if any.
+    pub fn paused_until(&self) -> Option<Instant> {
+        let bucket = self.bucket.lock().unwrap();
+        bucket.paused_until.filter(|until| *until > Instant::now())
+    }
+
+    /// Waits until a request of the given number of calls may be sent, taking them from the budget. A
+    /// batch larger than the budget is sent as soon as a call is available, the next calls waiting
+    /// for the budget it overdrew.
+    pub async fn acquire(&self, calls: usize) {
+        let started = Instant::now();
+        loop {
+            let wait = {
+                let mut bucket = self.bucket.lock().unwrap();
+                let now = Instant::now();
+                match (bucket.paused_until, self.requests_per_second) {
+                    (Some(until), _) if until > now => until - now,
+                    (_, None) => Duration::ZERO,
+                    (_, Some(rate)) => {
+                        let refilled = bucket.tokens + (now - bucket.refilled_at).as_secs_f64() * rate;
+                        bucket.tokens = refilled.min(rate.max(1.0));
+                        bucket.refilled_at = now;
+                        if bucket.tokens >= 1.0 {
+                            bucket.tokens -= calls as f64;
+                            Duration::ZERO
+                        } else {
+                            Duration::from_secs_f64((1.0 - bucket.tokens) / rate)
+                        }
+                    }
+                }
+            };
+            if wait.is_zero() {
+                break;
+            }
+            tokio::time::sleep(wait).await;
+        }
+        let waited = started.elapsed();
+        if waited >= Duration::from_millis(1) {
+            let mut bucket = self.bucket.lock().unwrap();
+            bucket.stats.throttled_calls += 1;
+            bucket.stats.throttled_ms += waited.as_millis() as u64;
+        }
+    }
+
+    /// Records a call rejected over the node's rate limit, pausing the calls for the delay asked by the
+    /// node, or for an exponential backoff when it did not ask. Returns the pause.
+    pub fn record_rate_limited(&self, retry_after: Option<Duration>) -> Duration {
+        let mut bucket = self.bucket.lock().unwrap();
+        bucket.stats.rate_limited_responses += 1;
+        bucket.rate_limited_streak += 1;
+        let backoff = BASE_RATE_LIMIT_PAUSE * 2u32.pow((bucket.rate_limited_streak - 1).min(6));
+        let pause = retry_after.unwrap_or(backoff).min(MAX_RATE_LIMIT_PAUSE);
+        let until = Instant::now() + pause;
+        let until = bucket.paused_until.map_or(until, |paused| paused.max(until));
+        bucket.paused_until = Some(until);
+        // Resume with an empty budget rather than a burst of the calls held back by the pause.
+        bucket.tokens = 0.0;
+        bucket.refilled_at = until;
+        pause
+    }
+
+    /// Records a call accepted by the node, resetting the backoff of rate-limited responses.
+    pub fn record_accepted(&self) {
+        self.bucket.lock().unwrap().rate_limited_streak = 0;
+    }
+
+    /// Counters of the throttled calls so far.
+    pub fn stats(&self) -> ThrottleStats {
+        self.bucket.lock().unwrap().stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration as ChronoDuration;
+    use reqwest::header::HeaderValue;
+    use web3::error::TransportError;
+
+    fn rpc_error(code: i64, message: &str, data: Option<serde_json::Value>) -> Error {
+        Error::Rpc(jsonrpc_core::Error { code: jsonrpc_core::ErrorCode::from(code), message: message.to_string(), data })
+    }
+
+    fn retry_after(value: &str) -> Option<Duration> {
+        let mut headers = HeaderMap::new();
+        headers.insert(RETRY_AFTER, HeaderValue::from_str(value).unwrap());
+        parse_retry_after(&headers)
+    }
+
+    #[test]
+    fn rate_limit_errors_are_recognised() {
+        assert!(is_rate_limit_error(&Error::Transport(TransportError::Code(429))));
+        assert!(!is_rate_limit_error(&Error::Transport(TransportError::Code(503))));
+        assert!(is_rate_limit_error(&rpc_error(429, "Too Many Requests", None)));
+        assert!(is_rate_limit_error(&rpc_error(-32007, "request limit", None)));
+        assert!(is_rate_limit_error(&rpc_error(-32005, "Your app has exceeded its compute units per second capacity", None)));
+        assert!(is_rate_limit_error(&rpc_error(-32005, "daily request count exceeded, request rate limited", None)));
+        assert!(!is_rate_limit_error(&rpc_error(-32005, "query returned more than 10000 results", None)));
+        assert!(!is_rate_limit_error(&rpc_error(-32000, "header not found", None)));
+        assert!(!is_rate_limit_error(&Error::Unreachable));
+    }
+
+    #[test]
+    fn retry_after_accepts_seconds_and_http_dates() {
+        assert_eq!(retry_after("3"), Some(Duration::from_secs(3)));
+        assert_eq!(retry_after(" 120 "), Some(Duration::from_secs(120)));
+
+        let date = (Utc::now() + ChronoDuration::seconds(30)).format("%a, %d %b %Y %H:%M:%S GMT").to_string();
+        let delay = retry_after(&date).unwrap();
+        assert!(delay > Duration::from_secs(27) && delay <= Duration::from_secs(30), "{:?}", delay);
+        assert_eq!(retry_after("Sun, 06 Nov 1994 08:49:37 GMT"), Some(Duration::ZERO));
+
+        assert_eq!(retry_after("soon"), None);
+        assert_eq!(parse_retry_after(&HeaderMap::new()), None);
+    }
+
+    #[test]
+    fn backoff_is_read_from_the_error_data() {
+        let error = rpc_error(-32005, "project ID request rate exceeded", Some(serde_json::json!({ "backoff_seconds": 2.5 })));
+        assert_eq!(parse_backoff(&error), Some(Duration::from_millis(2500)));
+        assert_eq!(parse_backoff(&rpc_error(-32005, "rate limited", None)), None);
+        assert_eq!(parse_backoff(&Error::Transport(TransportError::Code(429))), None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn bucket_spaces_calls_after_a_burst() {
+        let limiter = RateLimiter::new(Some(2.0));
+        let started = Instant::now();
+        limiter.acquire(1).await;
+        limiter.acquire(1).await;
+        assert_eq!(started.elapsed(), Duration::ZERO);
+
+        limiter.acquire(1).await;
+        assert_eq!(started.elapsed(), Duration::from_millis(500));
+        limiter.acquire(1).await;
+        assert_eq!(started.elapsed(), Duration::from_millis(1000));
+
+        // A batch overdraws the budget, delaying the next calls.
+        limiter.acquire(3).await;
+        assert_eq!(started.elapsed(), Duration::from_millis(1500));
+        limiter.acquire(1).await;
+        assert_eq!(started.elapsed(), Duration::from_millis(3000));
+
+        let stats = limiter.stats();
+        assert_eq!(stats.throttled_calls, 4);
+        assert_eq!(stats.throttled_ms, 3000);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn bucket_refills_up_to_one_second_of_budget() {
+        let limiter = RateLimiter::new(Some(2.0));
+        tokio::time::sleep(Duration::from_secs(10)).await;
+        let started = Instant::now();
+        limiter.acquire(1).await;
+        limiter.acquire(1).await;
+        limiter.acquire(1).await;
+        assert_eq!(started.elapsed(), Duration::from_millis(500));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn unlimited_bucket_never_waits() {
+        let limiter = RateLimiter::new(None);
+        let started = Instant::now();
+        for _ in 0..100 {
+            limiter.acquire(10).await;
+        }
+        assert_eq!(started.elapsed(), Duration::ZERO);
+        assert_eq!(limiter.stats().throttled_calls, 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn rate_limited_responses_pause_the_calls() {
+        let limiter = RateLimiter::new(None);
+        assert_eq!(limiter.record_rate_limited(Some(Duration::from_secs(3))), Duration::from_secs(3));
+        assert!(limiter.paused_until().is_some());
+        let started = Instant::now();
+        limiter.acquire(1).await;
+        assert_eq!(started.elapsed(), Duration::from_secs(3));
+        assert!(limiter.paused_until().is_none());
+        assert_eq!(limiter.stats().rate_limited_responses, 1);
+    }
+
+    #[test]
+    fn rate_limit_backoff_doubles_up_to_the_cap() {
+        let limiter = RateLimiter::new(Some(10.0));
+        assert_eq!(limiter.record_rate_limited(None), Duration::from_secs(1));
+        assert_eq!(limiter.record_rate_limited(None), Duration::from_secs(2));
+        assert_eq!(limiter.record_rate_limited(None), Duration::from_secs(4));
+        limiter.record_accepted();
+        assert_eq!(limiter.record_rate_limited(None), Duration::from_secs(1));
+        for _ in 0..10 {
+            limiter.record_rate_limited(None);
+        }
+        assert_eq!(limiter.record_rate_limited(None), MAX_RATE_LIMIT_PAUSE);
+        assert_eq!(limiter.record_rate_limited(Some(Duration::from_secs(600))), MAX_RATE_LIMIT_PAUSE);
+    }
+}
@@ -0,0 +1,685 @@
+use futures::future::{join_all, BoxFuture};
+use jsonrpc_core::{Call, Id, Output, Request};
+use log::{info, warn};
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::oneshot;
+use tokio::time::sleep;
+use web3::error::TransportError;
+use web3::helpers::{arbitrary_precision_deserialize_workaround, build_request, to_result_from_output};
+use web3::types::U64;
+use web3::{Error, RequestId, Transport};
+use crate::utils::rate_limit::{is_rate_limit_error, parse_backoff, parse_retry_after, RateLimiter};
+
+/// Weight of the latest call in the moving averages of latency and error rate.
+const HEALTH_SMOOTHING: f64 = 0.2;
+/// Factor by which a fully failing endpoint's latency is multiplied when ranking endpoints.
+const ERROR_RATE_WEIGHT: f64 = 10.0;
+/// Time an endpoint is avoided after its first consecutive failure, doubled after each further one.
+const BASE_COOLDOWN: Duration = Duration::from_secs(1);
+/// Longest time an endpoint is avoided after failing.
+const MAX_COOLDOWN: Duration = Duration::from_secs(60);
+/// Time a batch waits for the calls issued concurrently with its first call before it is sent.
+const BATCH_WINDOW: Duration = Duration::from_millis(2);
+/// Number of times a call rejected over the rate limit of every endpoint is sent again.
+const MAX_RATE_LIMIT_RETRIES: u32 = 5;
+
+/// Health of an endpoint, measured from the calls routed to it.
+#[derive(Debug, Default)]
+struct Health {
+    /// Moving average of the latency of successful calls, in milliseconds.
+    latency_ms: f64,
+    /// Moving average of the share of failed calls.
+    error_rate: f64,
+    consecutive_failures: u32,
+    /// Time until which the endpoint is avoided after failing.
+    down_until: Option<Instant>,
+    /// Whether the endpoint's head was found behind the other endpoints' by the block number cross-check.
+    lagging: bool,
+    requests: u64,
+    calls: u64,
+    failures: u64,
+}
+
+/// Health and throttling counters of an endpoint, as served by the RPC metrics endpoint.
+#[derive(Debug, Serialize)]
+pub struct EndpointMetrics {
+    pub name: String,
+    pub available: bool,
+    pub lagging: bool,
+    pub latency_ms: f64,
+    pub error_rate: f64,
+    /// HTTP requests sent, each carrying a single call or a batch of calls.
+    pub requests: u64,
+    /// JSON-RPC calls sent, individually or in batches.
+    pub calls: u64,
+    pub failures: u64,
+    pub throttled_calls: u64,
+    pub throttled_ms: u64,
+    pub rate_limited_responses: u64,
+}
+
+#[derive(Debug)]
+struct Endpoint {
+    /// Host of the endpoint, logged instead of its URL that may hold an API key.
+    name: String,
+    url: reqwest::Url,
+    client: reqwest::Client,
+    limiter: RateLimiter,
+    health: Mutex<Health>,
+}
+
+/// Id of a call, matching it to its output in a batch response.
+fn call_id(call: &Call) -> Option<Id> {
+    match call {
+        Call::MethodCall(method_call) => Some(method_call.id.clone()),
+        Call::Invalid { id } => Some(id.clone()),
+        Call::Notification(_) => None,
+    }
+}
+
+/// Decodes the response to a request, with the result of each of its calls in order. A request of a
+/// single call is answered with a single output, and a batch with an array of outputs in any order,
+/// or with a single error when the node rejects the whole batch.
+fn decode_outputs(ids: &[Option<Id>], value: Value) -> Result<Vec<Result<Value, Error>>, Error> {
+    if ids.len() == 1 {
+        let output: Output = serde_json::from_value(value)?;
+        return Ok(vec![to_result_from_output(output)]);
+    }
+    if value.is_object() {
+        return match serde_json::from_value::<Output>(value)? {
+            Output::Failure(failure) => Err(Error::Rpc(failure.error)),
+            Output::Success(_) => Err(Error::InvalidResponse("Batch answered with a single result".to_string())),
+        };
+    }
+    let mut outputs: HashMap<Id, Output> = serde_json::from_value::<Vec<Output>>(value)?
+        .into_iter()
+        .map(|output| (output.id().clone(), output))
+        .collect();
+    Ok(ids.iter()
+        .map(|id| {
+            id.as_ref()
+                .and_then(|id| outputs.remove(id))
+                .ok_or_else(|| Error::InvalidResponse(format!("Batch response is missing call {:?}", id)))
+                .and_then(to_result_from_output)
+        })
+        .collect())
+}
+
+impl Endpoint {
+    /// Ranks the endpoint for routing, lower is better: its latency penalized by its error rate,
+    /// after every endpoint that is not failing or lagging.
+    fn score(&self, now: Instant) -> f64 {
+        let paused = self.limiter.paused_until().is_some();
+        let health = self.health.lock().unwrap();
+        let available = health.down_until.is_none_or(|until| until <= now) && !health.lagging && !paused;
+        let score = health.latency_ms * (1.0 + ERROR_RATE_WEIGHT * health.error_rate);
+        if available { score } else { f64::MAX / 2.0 + score }
+    }
+
+    fn record_success(&self, latency: Duration) {
+        let mut health = self.health.lock().unwrap();
+        let latency_ms = latency.as_secs_f64() * 1000.0;
+        health.latency_ms = if health.latency_ms == 0.0 {
+            latency_ms
+        } else {
+            health.latency_ms + HEALTH_SMOOTHING * (latency_ms - health.latency_ms)
+        };
+        health.error_rate -= HEALTH_SMOOTHING * health.error_rate;
+        if health.consecutive_failures > 0 {
+            info!("Node {} recovered", self.name);
+        }
+        health.consecutive_failures = 0;
+        health.down_until = None;
+    }
+
+    fn record_failure(&self) {
+        let mut health = self.health.lock().unwrap();
+        health.error_rate += HEALTH_SMOOTHING * (1.0 - health.error_rate);
+        health.failures += 1;
+        health.consecutive_failures += 1;
+        let cooldown = BASE_COOLDOWN * 2u32.pow((health.consecutive_failures - 1).min(6));
+        health.down_until = Some(Instant::now() + cooldown.min(MAX_COOLDOWN));
+    }
+
+    /// Posts a JSON-RPC request to the endpoint, returning the response body along with the delay asked
+    /// by its `Retry-After` header, if any.
+    async fn post(&self, request: &Request) -> (Result<Value, Error>, Option<Duration>) {
+        let response = match self.client.post(self.url.clone()).json(request).send().await {
+            Ok(response) => response,
+            Err(e) => return (Err(Error::Transport(TransportError::Message(format!("failed to send request: {}", e)))), None),
+        };
+        let status = response.status();
+        let retry_after = parse_retry_after(response.headers());
+        let body = match response.bytes().await {
+            Ok(body) => body,
+            Err(e) => return (Err(Error::Transport(TransportError::Message(format!("failed to read response: {}", e)))), retry_after),
+        };
+        if !status.is_success() {
+            return (Err(Error::Transport(TransportError::Code(status.as_u16()))), retry_after);
+        }
+        let result = arbitrary_precision_deserialize_workaround(&body).map_err(|e| {
+            Error::Transport(TransportError::Message(format!(
+                "failed to deserialize response: {}: {}",
+                e,
+                String::from_utf8_lossy(&body)
+            )))
+        });
+        (result, retry_after)
+    }
+
+    /// Sends calls to the endpoint within its request budget, as a single request or a batch, recording
+    /// its latency, failure or rejection over the node's rate limit. Returns the result of each call, or
+    /// the error of the whole request.
+    async fn send(&self, calls: Vec<Call>) -> Result<Vec<Result<Value, Error>>, Error> {
+        self.limiter.acquire(calls.len()).await;
+        {
+            let mut health = self.health.lock().unwrap();
+            health.requests += 1;
+            health.calls += calls.len() as u64;
+        }
+        let ids: Vec<Option<Id>> = calls.iter().map(call_id).collect();
+        let request = match <[Call; 1]>::try_from(calls) {
+            Ok([call]) => Request::Single(call),
+            Err(calls) => Request::Batch(calls),
+        };
+        let started = Instant::now();
+        let (response, retry_after) = self.post(&request).await;
+        let results = response.and_then(|value| decode_outputs(&ids, value));
+        let rate_limit_error = match &results {
+            Ok(results) => results.iter().filter_map(|result| result.as_ref().err()).find(|e| is_rate_limit_error(e)),
+            Err(e) => Some(e).filter(|e| is_rate_limit_error(e)),
+        };
+        match (&results, rate_limit_error) {
+            (_, Some(e)) => {
+                let pause = self.limiter.record_rate_limited(retry_after.or_else(|| parse_backoff(e)));
+                warn!("Node {} is rate limiting calls, pausing it for {:?}: {}", self.name, pause, e);
+            }
+            // The node answered: an RPC error is a property of the request, not of the endpoint.
+            (Ok(_) | Err(Error::Rpc(_)), None) => {
+                self.limiter.record_accepted();
+                self.record_success(started.elapsed());
+            }
+            (Err(_), None) => self.record_failure(),
+        }
+        results
+    }
+
+    fn metrics(&self, now: Instant) -> EndpointMetrics {
+        let stats = self.limiter.stats();
+        let paused = self.limiter.paused_until().is_some();
+        let health = self.health.lock().unwrap();
+        EndpointMetrics {
+            name: self.name.clone(),
+            available: health.down_until.is_none_or(|until| until <= now) && !health.lagging && !paused,
+            lagging: health.lagging,
+            latency_ms: health.latency_ms,
+            error_rate: health.error_rate,
+            requests: health.requests,
+            calls: health.calls,
+            failures: health.failures,
+            throttled_calls: stats.throttled_calls,
+            throttled_ms: stats.throttled_ms,
+            rate_limited_responses: stats.rate_limited_responses,
+        }
+    }
+}
+
+/// Call waiting for the next batch, with the channel its result is sent on.
+type QueuedCall = (Call, oneshot::Sender<Result<Value, Error>>);
+
+#[derive(Debug)]
+struct Inner {
+    endpoints: Vec<Endpoint>,
+    /// Number of blocks an endpoint's head may be behind the highest head before it is avoided,
+    /// or `None` to trust each endpoint's `eth_blockNumber`.
+    max_block_lag: Option<u64>,
+    /// Maximum number of calls sent in one batch request; calls are sent one by one when 1.
+    max_batch_size: usize,
+    /// Calls waiting for the next batch.
+    queue: Mutex<Vec<QueuedCall>>,
+    next_id: AtomicUsize,
+}
+
+/// Transport spreading calls over several HTTP endpoints of the same chain. Each call is routed to
+/// the healthiest endpoint, ranked by latency and error rate, and fails over to the next ones when
+/// the endpoint cannot be reached. With a maximum block lag, `eth_blockNumber` is asked to every
+/// endpoint and those whose head is too far behind are avoided. Calls to each endpoint are kept within
+/// its request budget, and paused for the delay the node asks when it rejects them over its rate limit.
+/// Calls issued concurrently are coalesced into JSON-RPC batch requests.
+#[derive(Debug, Clone)]
+pub struct FailoverTransport {
+    inner: Arc<Inner>,
+}
+
+impl FailoverTransport {
+    /// Creates a transport over the given endpoint URLs, preferring them in order until their health is
+    /// measured, allowing each of them the given number of calls per second, or any number when `None`,
+    /// and sending concurrent calls in batches of up to `max_batch_size` calls. A request that is not
+    /// answered within `request_timeout`, or not connected within `connect_timeout`, fails over.
+    pub fn new(
+        urls: &[String],
+        max_block_lag: Option<u64>,
+        requests_per_second: Option<f64>,
+        max_batch_size: usize,
+        request_timeout: Duration,
+        connect_timeout: Duration,
+    ) -> Result<Self, Error> {
+        if urls.is_empty() {
+            return Err(Error::Transport(TransportError::Message("No node URL given".to_string())));
+        }
+        let client = reqwest::Client::builder()
+            .timeout(request_timeout)
+            .connect_timeout(connect_timeout)
+            .build()
+            .map_err(|e| Error::Transport(TransportError::Message(format!("failed to build client: {}", e))))?;
+        let endpoints = urls.iter()
+            .enumerate()
+            .map(|(index, url)| {
+                let url = reqwest::Url::parse(url)
+                    .map_err(|e| Error::Transport(TransportError::Message(format!("Invalid node URL #{}: {}", index + 1, e))))?;
+                let name = url.host_str()
+                    .map(|host| match url.port() {
+                        Some(port) => format!("{}:{}", host, port),
+                        None => host.to_string(),
+                    })
+                    .unwrap_or_else(|| format!("#{}", index + 1));
+                Ok(Endpoint {
+                    name,
+                    url,
+                    client: client.clone(),
+                    limiter: RateLimiter::new(requests_per_second),
+                    health: Mutex::new(Health::default()),
+                })
+            })
+            .collect::<Result<Vec<_>, Error>>()?;
+        Ok(FailoverTransport {
+            inner: Arc::new(Inner {
+                endpoints,
+                max_block_lag,
+                max_batch_size: max_batch_size.max(1),
+                queue: Mutex::new(Vec::new()),
+                next_id: AtomicUsize::new(0),
+            }),
+        })
+    }
+
+    /// Health and throttling counters of each endpoint.
+    pub fn metrics(&self) -> Vec<EndpointMetrics> {
+        let now = Instant::now();
+        self.inner.endpoints.iter().map(|endpoint| endpoint.metrics(now)).collect()
+    }
+}
+
+impl Inner {
+    /// Indices of the endpoints from the healthiest to the least healthy.
+    fn ranked_endpoints(&self) -> Vec<usize> {
+        let now = Instant::now();
+        let mut ranked: Vec<(usize, f64)> = self.endpoints.iter()
+            .enumerate()
+            .map(|(index, endpoint)| (index, endpoint.score(now)))
+            .collect();
+        ranked.sort_by(|a, b| a.1.total_cmp(&b.1));
+        ranked.into_iter().map(|(index, _)| index).collect()
+    }
+
+    /// Sends calls to the healthiest endpoint in one request, failing over to the next ones with the
+    /// calls left unanswered when it cannot be reached or is rate limiting calls. Calls rejected over
+    /// the rate limit of every endpoint are sent again once the pause asked by the node is over, a few
+    /// times before giving up. Returns the result of each call in order.
+    async fn send(&self, calls: Vec<Call>) -> Vec<Result<Value, Error>> {
+        let unanswered = |result: &Option<Result<Value, Error>>| match result {
+            None => true,
+            Some(Err(e)) => is_rate_limit_error(e),
+            Some(Ok(_)) => false,
+        };
+        let mut results: Vec<Option<Result<Value, Error>>> = calls.iter().map(|_| None).collect();
+        let mut rate_limit_retries = 0;
+        loop {
+            let mut last_error = None;
+            for index in self.ranked_endpoints() {
+                let pending: Vec<usize> = (0..calls.len()).filter(|&i| unanswered(&results[i])).collect();
+                if pending.is_empty() {
+                    break;
+                }
+                let endpoint = &self.endpoints[index];
+                if let Some(e) = &last_error {
+                    warn!("Failing over to node {} after error: {}", endpoint.name, e);
+                }
+                match endpoint.send(pending.iter().map(|&i| calls[i].clone()).collect()).await {
+                    Ok(outputs) => {
+                        for (i, output) in pending.into_iter().zip(outputs) {
+                            if let Err(e) = &output {
+                                if is_rate_limit_error(e) {
+                                    last_error = Some(e.clone());
+                                }
+                            }
+                            results[i] = Some(output);
+                        }
+                    }
+                    Err(e) => last_error = Some(e),
+                }
+            }
+            let retry = results.iter().any(unanswered)
+                && last_error.as_ref().is_some_and(is_rate_limit_error)
+                && rate_limit_retries < MAX_RATE_LIMIT_RETRIES;
+            if !retry {
+                let error = last_error.unwrap_or(Error::Unreachable);
+                return results.into_iter().map(|result| result.unwrap_or_else(|| Err(error.clone()))).collect();
+            }
+            rate_limit_retries += 1;
+        }
+    }
+
+    /// Sends a single call, see [`Inner::send`].
+    async fn send_one(&self, call: Call) -> Result<Value, Error> {
+        self.send(vec![call]).await.pop().unwrap_or(Err(Error::Internal))
+    }
+
+    /// Queues a call into the next batch, sent once the calls issued meanwhile have been queued too.
+    async fn send_batched(self: Arc<Self>, call: Call) -> Result<Value, Error> {
+        let (result_tx, result_rx) = oneshot::channel();
+        let first = {
+            let mut queue = self.queue.lock().unwrap();
+            queue.push((call, result_tx));
+            queue.len() == 1
+        };
+        if first {
+            tokio::spawn(self.clone().flush_queue());
+        }
+        result_rx.await.unwrap_or(Err(Error::Internal))
+    }
+
+    /// Sends the queued calls in batches of at most the maximum batch size, after waiting for the
+    /// calls issued concurrently with the first one.
+    async fn flush_queue(self: Arc<Self>) {
+        sleep(BATCH_WINDOW).await;
+        let mut queued = std::mem::take(&mut *self.queue.lock().unwrap());
+        let mut batches = Vec::new();
+        while !queued.is_empty() {
+            let rest = queued.split_off(queued.len().min(self.max_batch_size));
+            batches.push(std::mem::replace(&mut queued, rest));
+        }
+        join_all(batches.into_iter().map(|batch| async {
+            let (calls, result_txs): (Vec<Call>, Vec<_>) = batch.into_iter().unzip();
+            for (result_tx, result) in result_txs.into_iter().zip(self.send(calls).await) {
+                // The caller may have stopped waiting for the result.
+                let _ = result_tx.send(result);
+            }
+        }))
+        .await;
+    }
+
+    /// Asks every endpoint for its head, flags those too far behind the highest one as lagging, and
+    /// returns the head of the healthiest endpoint that is not lagging.
+    async fn cross_check_block_number(&self, call: Call, max_block_lag: u64) -> Result<Value, Error> {
+        let results = join_all(self.endpoints.iter().map(|endpoint| async {
+            endpoint.send(vec![call.clone()]).await.and_then(|mut results| results.pop().unwrap_or(Err(Error::Internal)))
+        }))
+        .await;
+        let heads: Vec<Option<u64>> = results.iter()
+            .map(|result| result.as_ref().ok().and_then(|value| serde_json::from_value::<U64>(value.clone()).ok()).map(|head| head.as_u64()))
+            .collect();
+        let Some(highest) = heads.iter().flatten().max().copied() else {
+            return results.into_iter().find_map(Result::err).map_or(Err(Error::Unreachable), Err);
+        };
+        for (endpoint, head) in self.endpoints.iter().zip(&heads) {
+            let Some(head) = head else { continue };
+            let lagging = head + max_block_lag < highest;
+            let mut health = endpoint.health.lock().unwrap();
+            if lagging && !health.lagging {
+                warn!("Node {} is {} blocks behind the highest head {}, avoiding it", endpoint.name, highest - head, highest);
+            } else if !lagging && health.lagging {
+                info!("Node {} caught up with the highest head {}", endpoint.name, highest);
+            }
+            health.lagging = lagging;
+        }
+        let mut results: Vec<Option<Result<Value, Error>>> = results.into_iter().map(Some).collect();
+        for index in self.ranked_endpoints() {
+            if heads[index].is_some_and(|head| head + max_block_lag >= highest) {
+                if let Some(result) = results[index].take() {
+                    return result;
+                }
+            }
+        }
+        Err(Error::Unreachable)
+    }
+}
+
+impl Transport for FailoverTransport {
+    type Out = BoxFuture<'static, Result<Value, Error>>;
+
+    fn prepare(&self, method: &str, params: Vec<Value>) -> (RequestId, Call) {
+        let id = self.inner.next_id.fetch_add(1, Ordering::AcqRel);
+        (id, build_request(id, method, params))
+    }
+
+    fn send(&self, _id: RequestId, call: Call) -> Self::Out {
+        let inner = self.inner.clone();
+        Box::pin(async move {
+            let is_block_number = matches!(&call, Call::MethodCall(method_call) if method_call.method == "eth_blockNumber");
+            match inner.max_block_lag {
+                Some(max_block_lag) if is_block_number && inner.endpoints.len() > 1 => {
+                    inner.cross_check_block_number(call, max_block_lag).await
+                }
+                _ if inner.max_batch_size > 1 => inner.send_batched(call).await,
+                _ => inner.send_one(call).await,
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    /// Serves JSON-RPC calls with the result given by `result` for their method, or never answers
+    /// when it returns `None`. Returns the URL of the node.
+    async fn serve_node(result: fn(&str) -> Option<Value>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            loop {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                tokio::spawn(async move {
+                    let mut request = Vec::new();
+                    let mut buf = [0u8; 4096];
+                    let body = loop {
+                        let n = socket.read(&mut buf).await.unwrap_or(0);
+                        if n == 0 {
+                            return;
+                        }
+                        request.extend_from_slice(&buf[..n]);
+                        let text = String::from_utf8_lossy(&request).to_string();
+                        let Some(end) = text.find("\r\n\r\n") else { continue };
+                        let length = text.to_lowercase().lines()
+                            .find_map(|line| line.strip_prefix("content-length:").map(|value| value.trim().parse::<usize>().unwrap()))
+                            .unwrap_or(0);
+                        if request.len() >= end + 4 + length {
+                            break request[end + 4..end + 4 + length].to_vec();
+                        }
+                    };
+                    let call: Value = serde_json::from_slice(&body).unwrap();
+                    let Some(value) = result(call["method"].as_str().unwrap_or_default()) else {
+                        tokio::time::sleep(Duration::from_secs(3600)).await;
+                        return;
+                    };
+                    let response = json!({ "jsonrpc": "2.0", "id": call["id"], "result": value }).to_string();
+                    let response = format!(
+                        "HTTP/1.1 200 OK\r\ncontent-type: application/json\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{}",
+                        response.len(), response
+                    );
+                    let _ = socket.write_all(response.as_bytes()).await;
+                });
+            }
+        });
+        url
+    }
+
+    /// URL on which nothing listens, so connections are refused.
+    async fn closed_url() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        format!("http://{}", listener.local_addr().unwrap())
+    }
+
+    fn transport(urls: &[String], max_block_lag: Option<u64>) -> FailoverTransport {
+        FailoverTransport::new(urls, max_block_lag, None, 1, Duration::from_millis(500), Duration::from_millis(500)).unwrap()
+    }
+
+    fn block_number_call(id: u64) -> Call {
+        build_request(id as usize, "eth_blockNumber", vec![])
+    }
+
+    #[test]
+    fn endpoints_are_ranked_by_health() {
+        let urls: Vec<String> = (1..=5).map(|port| format!("http://127.0.0.1:{}", port)).collect();
+        let transport = transport(&urls, None);
+        let inner = &transport.inner;
+        // Unmeasured endpoints keep their configured order.
+        assert_eq!(inner.ranked_endpoints(), vec![0, 1, 2, 3, 4]);
+
+        let set = |index: usize, latency_ms: f64, error_rate: f64| {
+            let mut health = inner.endpoints[index].health.lock().unwrap();
+            health.latency_ms = latency_ms;
+            health.error_rate = error_rate;
+        };
+        set(0, 100.0, 0.0);
+        set(1, 50.0, 0.0);
+        // Fast but failing: 20 ms * (1 + 10 * 0.5) = 120.
+        set(2, 20.0, 0.5);
+        set(3, 10.0, 0.0);
+        inner.endpoints[3].health.lock().unwrap().down_until = Some(Instant::now() + Duration::from_secs(60));
+        set(4, 5.0, 0.0);
+        inner.endpoints[4].health.lock().unwrap().lagging = true;
+        let ranked = inner.ranked_endpoints();
+        assert_eq!(&ranked[..3], &[1, 0, 2]);
+        // Unavailable endpoints come last.
+        assert_eq!(&ranked[3..], &[3, 4]);
+
+        // An endpoint whose cooldown is over is available again.
+        inner.endpoints[3].health.lock().unwrap().down_until = Some(Instant::now());
+        assert_eq!(inner.ranked_endpoints()[0], 3);
+    }
+
+    #[tokio::test]
+    async fn calls_fail_over_to_the_next_endpoint() {
+        let urls = vec![closed_url().await, serve_node(|_| Some(json!("0x10"))).await];
+        let transport = transport(&urls, None);
+        let results = transport.inner.send(vec![block_number_call(1)]).await;
+        assert_eq!(results[0].as_ref().unwrap(), &json!("0x10"));
+
+        let metrics = transport.metrics();
+        assert_eq!(metrics[0].failures, 1);
+        assert!(!metrics[0].available);
+        assert_eq!(metrics[1].failures, 0);
+        // The failed endpoint is now avoided.
+        assert_eq!(transport.inner.ranked_endpoints(), vec![1, 0]);
+    }
+
+    #[tokio::test]
+    async fn hanging_endpoints_time_out_and_fail_over() {
+        let urls = vec![serve_node(|_| None).await, serve_node(|_| Some(json!("0x20"))).await];
+        let transport = transport(&urls, None);
+        let started = Instant::now();
+        let results = transport.inner.send(vec![block_number_call(1)]).await;
+        assert_eq!(results[0].as_ref().unwrap(), &json!("0x20"));
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert_eq!(transport.metrics()[0].failures, 1);
+    }
+
+    #[tokio::test]
+    async fn unreachable_endpoints_return_the_last_error() {
+        let urls = vec![closed_url().await, closed_url().await];
+        let results = transport(&urls, None).inner.send(vec![block_number_call(1)]).await;
+        assert!(matches!(&results[0], Err(Error::Transport(_))));
+    }
+
+    #[tokio::test]
+    async fn lagging_endpoints_are_flagged_by_the_block_number_cross_check() {
+        let urls = vec![
+            serve_node(|_| Some(json!("0x50"))).await,
+            serve_node(|_| Some(json!("0x64"))).await,
+            serve_node(|_| Some(json!("0x62"))).await,
+        ];
+        let transport = transport(&urls, Some(5));
+        let head = transport.inner.cross_check_block_number(block_number_call(1), 5).await.unwrap();
+        assert!(head == json!("0x64") || head == json!("0x62"));
+
+        let metrics = transport.metrics();
+        assert!(metrics[0].lagging);
+        assert!(!metrics[0].available);
+        assert!(!metrics[1].lagging);
+        assert!(!metrics[2].lagging);
+        assert_eq!(*transport.inner.ranked_endpoints().last().unwrap(), 0);
+
+        // Through the transport, `eth_blockNumber` never returns the lagging head.
+        let web3 = web3::Web3::new(transport);
+        assert!(web3.eth().block_number().await.unwrap().as_u64() >= 0x62);
+    }
+
+    fn ids(ids: &[u64]) -> Vec<Option<Id>> {
+        ids.iter().map(|id| Some(Id::Num(*id))).collect()
+    }
+
+    fn success(id: u64, result: Value) -> Value {
+        json!({ "jsonrpc": "2.0", "id": id, "result": result })
+    }
+
+    fn failure(id: Value, code: i64, message: &str) -> Value {
+        json!({ "jsonrpc": "2.0", "id": id, "error": { "code": code, "message": message } })
+    }
+
+    #[test]
+    fn single_call_is_answered_with_a_single_output() {
+        let results = decode_outputs(&ids(&[1]), success(1, json!("0x10"))).unwrap();
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].as_ref().unwrap(), &json!("0x10"));
+
+        let results = decode_outputs(&ids(&[1]), failure(json!(1), -32000, "header not found")).unwrap();
+        assert!(matches!(&results[0], Err(Error::Rpc(error)) if error.message == "header not found"));
+    }
+
+    #[test]
+    fn batch_outputs_are_matched_to_calls_by_id() {
+        let response = json!([
+            success(3, json!("c")),
+            failure(json!(1), -32000, "execution reverted"),
+            success(2, json!("b")),
+        ]);
+        let results = decode_outputs(&ids(&[1, 2, 3]), response).unwrap();
+        assert!(matches!(&results[0], Err(Error::Rpc(error)) if error.message == "execution reverted"));
+        assert_eq!(results[1].as_ref().unwrap(), &json!("b"));
+        assert_eq!(results[2].as_ref().unwrap(), &json!("c"));
+    }
+
+    #[test]
+    fn missing_outputs_fail_only_their_calls() {
+        let response = json!([success(1, json!("a")), success(9, json!("unknown"))]);
+        let results = decode_outputs(&ids(&[1, 2]), response).unwrap();
+        assert_eq!(results[0].as_ref().unwrap(), &json!("a"));
+        assert!(matches!(&results[1], Err(Error::InvalidResponse(message)) if message.contains("missing call")));
+
+        // A call without an id can never be matched.
+        let results = decode_outputs(&[None, Some(Id::Num(1))], json!([success(1, json!("a"))])).unwrap();
+        assert!(results[0].is_err());
+        assert_eq!(results[1].as_ref().unwrap(), &json!("a"));
+    }
+
+    #[test]
+    fn batch_rejected_as_a_whole_fails_every_call() {
+        let error = decode_outputs(&ids(&[1, 2]), failure(Value::Null, -32600, "batch too large")).unwrap_err();
+        assert!(matches!(error, Error::Rpc(error) if error.message == "batch too large"));
+
+        let error = decode_outputs(&ids(&[1, 2]), success(1, json!("a"))).unwrap_err();
+        assert!(matches!(error, Error::InvalidResponse(_)));
+
+        assert!(decode_outputs(&ids(&[1, 2]), json!("garbage")).is_err());
+    }
+}